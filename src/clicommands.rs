@@ -4,18 +4,66 @@
  * Contains various functions that create JSON requests out of CLI arguments,
  * parse the JSON response and provide a human-readable(-ish) console output.
  **/
-use std::io::Result;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
-use serde_json;
+use serde_json::{self, json};
 
 use job_queue::*;
-use protocol::{Request, Response};
+use notifier::format_time;
+use output::{self, JobRecord, OutputFormat};
+use protocol::{ExportRecord, Request, Response, EXPORT_FORMAT, EXPORT_VERSION};
+use queues::QueueInfo;
+use resources::{format_mem, ResourceUsage};
+use table::{self, TableStyle};
 
-/// Dumps a job vector to the console
-fn print_jobs(header: &str, jobs: Vec<Job>) {
-    println!("{}", header);
-    for j in jobs {
-        println!("{:?}", j);
+/// Sends a single request to the server and returns its decoded response
+pub fn send_request(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    request: &Request,
+    dump_protocol: bool,
+) -> Result<Response> {
+    let request_s = serde_json::to_string_pretty(request)?;
+    let mut response_req = client
+        .post(url)
+        .body(request_s.clone())
+        .send()
+        .map_err(Error::other)?;
+    if dump_protocol {
+        println!("Sent: {} ", request_s);
+    }
+
+    let response_s = response_req.text().map_err(Error::other)?;
+    if dump_protocol {
+        println!("Received: {} ", response_s);
+    }
+    Ok(serde_json::from_str(&response_s)?)
+}
+
+/// Prints a page of a job listing to the console, noting if there are more.
+/// Run times of queued jobs are estimated from `history`.
+fn print_page(header: &str, page: &JobPage, style: &TableStyle, history: &[Job]) {
+    if style.format.is_none() {
+        println!("{}", header);
+    }
+    table::print_jobs(&page.jobs, style, &table::estimate_runtimes(history));
+
+    let (shown, offset, total) = (page.jobs.len(), page.offset, page.total);
+    if style.format.is_some() {
+        return;
+    }
+    if shown == 0 && total > 0 {
+        println!("(offset {} is past all {} jobs)", offset, total);
+    } else if shown < total {
+        println!("({} to {} of {} jobs)", offset + 1, offset + shown, total);
     }
 }
 
@@ -26,121 +74,429 @@ fn print_jobs(header: &str, jobs: Vec<Job>) {
 /// * `client` - a HTTP(S) client object to be used for the connection
 /// * `url` - the absolute URL that the client should use for posting the request
 /// * `cmdline`- command line to be submitted for execution
+/// * `quiet` - print only the job ID
+/// * `output` - the output format
 /// * `dump_protocol` - a flag indicating that the JSON requests and responses are to be dumped
 pub fn handle_submit(
     client: &reqwest::Client,
     url: reqwest::Url,
     cmdline: &str,
+    options: SubmitOptions,
+    quiet: bool,
+    output: OutputFormat,
     dump_protocol: bool,
 ) -> Result<()> {
-    // serialize the request into a JSON object
-    let request_s = serde_json::to_string_pretty(&Request::SubmitJob(cmdline.to_string()))?;
+    let request = Request::SubmitJobWith {
+        cmdline: cmdline.to_string(),
+        options,
+    };
 
-    // write it to the server
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+    match send_request(client, url, &request, dump_protocol)? {
+        Response::SubmitJob(id) => match output {
+            OutputFormat::Table if quiet => println!("{}", id),
+            OutputFormat::Table => println!("Submitted as job #{}", id),
+            _ => output::print_value(output, &json!({ "id": id })),
+        },
+        Response::Error(s) => {
+            eprintln!("Could not submit job: {}", s);
+            return Err(Error::from(ErrorKind::Other));
+        }
+        response => panic!("Unexpected response: {:?}", response),
     }
 
-    // block for the server's response...
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+    Ok(())
+}
+
+/// Creates a new file that only the user can read, with an unpredictable
+/// name in the temporary directory
+fn create_temp_file(prefix: &str) -> Result<(PathBuf, File)> {
+    let mut seed = RandomState::new().build_hasher();
+    seed.write_u32(std::process::id());
+    loop {
+        seed.write_u64(seed.finish());
+        let path = std::env::temp_dir().join(format!("{}-{:016x}", prefix, seed.finish()));
+        match OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+        {
+            Ok(file) => return Ok((path, file)),
+            Err(ref e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
     }
+}
 
-    // ...and deserialize the response object from JSON
-    let response = serde_json::from_str(&response_s)?;
+/// Lets the user edit a command line in `$EDITOR`. Lines starting with `#`
+/// are dropped and the others are joined.
+fn edit_cmdline(jobid: u64, cmdline: &str) -> Result<String> {
+    let (path, mut file) = create_temp_file("qmanager-resubmit")?;
+    let written = write!(
+        file,
+        "{}\n# Edit the command line of job #{} and save to resubmit it.\n\
+         # Lines starting with '#' are ignored, an empty command line aborts.\n",
+        cmdline, jobid
+    );
+    drop(file);
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&path);
+        return Err(e);
+    }
 
-    match response {
-        Response::SubmitJob(id) => println!("Submitted as job #{}", id),
-        Response::Error(s) => eprintln!("Could not submit job: {}", s),
-        _ => panic!("Unexpected response: {:?}", response),
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .status();
+    let edited = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+
+    if !status?.success() {
+        return Err(Error::other(format!("{} failed", editor)));
+    }
+    Ok(edited?
+        .lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" "))
+}
+
+/// Submits a copy of a job, optionally with an edited command line
+#[allow(clippy::too_many_arguments)]
+pub fn handle_resubmit(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    jobid: u64,
+    edit: bool,
+    queue: Option<String>,
+    quiet: bool,
+    output: OutputFormat,
+    dump_protocol: bool,
+) -> Result<()> {
+    let cmdline = if edit {
+        let job = match send_request(client, url.clone(), &Request::GetJob(jobid), dump_protocol)? {
+            Response::GetJob(job) => job,
+            Response::Error(s) => {
+                eprintln!("Could not resubmit job: {}", s);
+                return Err(Error::from(ErrorKind::Other));
+            }
+            response => panic!("Unexpected response: {:?}", response),
+        };
+        let cmdline = edit_cmdline(jobid, &job.cmdline)?;
+        if cmdline.is_empty() {
+            eprintln!("Empty command line, job #{} is not resubmitted", jobid);
+            return Err(Error::from(ErrorKind::Other));
+        }
+        Some(cmdline)
+    } else {
+        None
+    };
+
+    let request = Request::ResubmitJob {
+        id: jobid,
+        cmdline,
+        queue,
+    };
+    match send_request(client, url, &request, dump_protocol)? {
+        Response::SubmitJob(id) => match output {
+            OutputFormat::Table if quiet => println!("{}", id),
+            OutputFormat::Table => println!("Resubmitted job #{} as job #{}", jobid, id),
+            _ => output::print_value(output, &json!({ "id": id, "resubmitted_from": jobid })),
+        },
+        Response::Error(s) => {
+            eprintln!("Could not resubmit job: {}", s);
+            return Err(Error::from(ErrorKind::Other));
+        }
+        response => panic!("Unexpected response: {:?}", response),
     }
 
     Ok(())
 }
 
-/// Requests a job to be removed from the queue
+/// Requests a job to be removed from the queue and prints it
 pub fn handle_remove(
     client: &reqwest::Client,
     url: reqwest::Url,
     jobid: u64,
+    output: OutputFormat,
     dump_protocol: bool,
-) -> Result<Job> {
-    let request_s = serde_json::to_string_pretty(&Request::RemoveJob(jobid))?;
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+) -> Result<()> {
+    match send_request(client, url, &Request::RemoveJob(jobid), dump_protocol)? {
+        Response::GetJob(job) => {
+            if output == OutputFormat::Table {
+                println!("Removed job #{}", job.id);
+                table::print_jobs(&[job], &TableStyle::default(), &HashMap::new());
+            } else {
+                output::print_job(output, JobRecord::new(&job));
+            }
+            Ok(())
+        }
+        Response::Error(s) => {
+            eprintln!("Could not remove job: {}", s);
+            Err(Error::from(ErrorKind::Other))
+        }
+        response => panic!("Unexpected response: {:?}", response),
     }
+}
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+/// Holds or releases the job with the given ID
+pub fn handle_hold(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    jobid: u64,
+    hold: bool,
+    output: OutputFormat,
+    dump_protocol: bool,
+) -> Result<()> {
+    let (request, verb) = if hold {
+        (Request::HoldJob(jobid), "hold")
+    } else {
+        (Request::ReleaseJob(jobid), "release")
+    };
+    match send_request(client, url, &request, dump_protocol)? {
+        Response::GetJob(job) => {
+            if output == OutputFormat::Table {
+                println!("{} job #{}", if hold { "Held" } else { "Released" }, job.id);
+                table::print_jobs(&[job], &TableStyle::default(), &HashMap::new());
+            } else {
+                output::print_job(output, JobRecord::new(&job));
+            }
+            Ok(())
+        }
+        Response::Error(s) => {
+            eprintln!("Could not {} job: {}", verb, s);
+            Err(Error::from(ErrorKind::Other))
+        }
+        response => panic!("Unexpected response: {:?}", response),
     }
-    let response = serde_json::from_str(&response_s)?;
+}
 
-    match response {
-        Response::GetJob(job) => Ok(job),
+/// Changes a queued job and prints it
+pub fn handle_update(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    jobid: u64,
+    changes: JobChanges,
+    output: OutputFormat,
+    dump_protocol: bool,
+) -> Result<()> {
+    let request = Request::UpdateJob { id: jobid, changes };
+    match send_request(client, url, &request, dump_protocol)? {
+        Response::GetJob(job) => {
+            if output == OutputFormat::Table {
+                println!("Updated job #{}", job.id);
+                table::print_jobs(&[job], &TableStyle::default(), &HashMap::new());
+            } else {
+                output::print_job(output, JobRecord::new(&job));
+            }
+            Ok(())
+        }
         Response::Error(s) => {
-            eprintln!("Could not remove job: {}", s);
-            Err(::std::io::Error::from(::std::io::ErrorKind::Other))
+            eprintln!("Could not update job: {}", s);
+            Err(Error::from(ErrorKind::Other))
         }
-        _ => panic!("Unexpected response: {:?}", response),
+        response => panic!("Unexpected response: {:?}", response),
     }
 }
 
-/// Requests a running job to be terminated
-pub fn handle_kill(
+/// Moves the job with the given ID within the queue and prints the queue
+pub fn handle_move(
     client: &reqwest::Client,
     url: reqwest::Url,
     jobid: u64,
+    to: QueuePosition,
+    output: OutputFormat,
     dump_protocol: bool,
 ) -> Result<()> {
-    let request_s = serde_json::to_string_pretty(&Request::KillJob(jobid))?;
+    let request = Request::MoveJob { id: jobid, to };
+    match send_request(client, url, &request, dump_protocol)? {
+        Response::GetJobs(jobs) => {
+            if output == OutputFormat::Table {
+                println!("Moved job #{}", jobid);
+                table::print_jobs(&jobs, &TableStyle::default(), &HashMap::new());
+            } else {
+                output::print_jobs(output, &jobs);
+            }
+            Ok(())
+        }
+        Response::Error(s) => {
+            eprintln!("Could not move job: {}", s);
+            Err(Error::from(ErrorKind::Other))
+        }
+        response => panic!("Unexpected response: {:?}", response),
+    }
+}
 
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+/// Number of stdout and stderr lines shown by `qmanager show`
+const SHOW_EXCERPT_LINES: usize = 10;
+
+/// Formats the time between two points in time, up to now if the second one
+/// has not been reached yet
+fn format_span(from: Option<SystemTime>, to: Option<SystemTime>) -> String {
+    match from {
+        Some(from) => to
+            .unwrap_or_else(SystemTime::now)
+            .duration_since(from)
+            .map(|d| humantime::format_duration(Duration::from_secs(d.as_secs())).to_string())
+            .unwrap_or_default(),
+        None => "-".to_string(),
     }
+}
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+/// Prints the last lines of a job's output
+fn print_excerpt(name: &str, output: &str) {
+    let lines: Vec<&str> = output.lines().collect();
+    if lines.len() > SHOW_EXCERPT_LINES {
+        println!(
+            "--- {} (last {} of {} lines) ---",
+            name,
+            SHOW_EXCERPT_LINES,
+            lines.len()
+        );
+    } else {
+        println!("--- {} ---", name);
     }
-    let response = serde_json::from_str(&response_s)?;
+    for line in &lines[lines.len().saturating_sub(SHOW_EXCERPT_LINES)..] {
+        println!("{}", line);
+    }
+}
 
-    match response {
-        Response::Ok => Ok(()),
+/// Prints a single job in a readable layout, or in the given output format
+pub fn handle_show(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    jobid: u64,
+    output: OutputFormat,
+    dump_protocol: bool,
+) -> Result<()> {
+    let job = match send_request(client, url.clone(), &Request::GetJob(jobid), dump_protocol)? {
+        Response::GetJob(job) => job,
+        Response::Error(s) => {
+            eprintln!("Could not get job {}: {}", jobid, s);
+            return Err(Error::from(ErrorKind::Other));
+        }
+        response => panic!("Unexpected response: {:?}", response),
+    };
+
+    if output != OutputFormat::Table {
+        output::print_job(output, JobRecord::with_output(&job));
+        return Ok(());
+    }
+
+    let time = |t: Option<SystemTime>| t.map(format_time).unwrap_or_else(|| "-".to_string());
+    let exit_status = match job.state {
+        JobState::Queued | JobState::Held | JobState::Running => "-".to_string(),
+        JobState::Terminated(code) => format!("exit code {}", code),
+        JobState::Killed(signum) => format!("killed by signal {}", signum),
+        JobState::Failed(ref reason) => format!("failed to launch: {}", reason),
+    };
+
+    println!("Job #{}", job.id);
+    println!("Command line: {}", job.cmdline);
+    println!("Appkey:       {}", job.appkey());
+    println!("Owner:        {}", job.owner.as_deref().unwrap_or("-"));
+    println!("Queue:        {}", job.queue);
+    if job.cpus.is_some() || job.mem.is_some() {
+        let needs = job.needs();
+        println!(
+            "Resources:    {} CPUs, {} memory",
+            needs.cpus,
+            format_mem(needs.mem)
+        );
+    }
+    println!("State:        {}", job.state.name());
+    println!("Submitted:    {}", format_time(job.scheduled));
+    println!("Started:      {}", time(job.started));
+    println!("Finished:     {}", time(job.finished));
+    println!(
+        "Wait time:    {}",
+        format_span(Some(job.scheduled), job.started)
+    );
+    println!("Run time:     {}", format_span(job.started, job.finished));
+    println!("Exit status:  {}", exit_status);
+    if let Some(pid) = job.pid {
+        println!("PID:          {}", pid);
+    }
+    if let Some(from) = job.resubmitted_from {
+        println!("Copy of:      job #{}", from);
+    }
+    if !job.changes.is_empty() {
+        println!("Changes:");
+        for change in &job.changes {
+            println!(
+                "  {}  {}: {} -> {}",
+                format_time(change.time),
+                change.field,
+                change.old,
+                change.new
+            );
+        }
+    }
+
+    // later attempts of the same job
+    let copies = JobQuery {
+        filter: JobFilter {
+            resubmitted_from: Some(job.id),
+            ..JobFilter::default()
+        },
+        sort: Some(SortKey::Id),
+        ..JobQuery::default()
+    };
+    let copies = list_jobs(client, url, JobList::All, &copies, dump_protocol)?;
+    if !copies.jobs.is_empty() {
+        let ids: Vec<String> = copies.jobs.iter().map(|j| format!("#{}", j.id)).collect();
+        println!("Resubmitted:  as job {}", ids.join(", "));
+    }
+    print_excerpt("stdout", &job.stdout);
+    print_excerpt("stderr", &job.stderr);
+
+    Ok(())
+}
+
+/// Requests a running job to be terminated
+pub fn handle_kill(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    jobid: u64,
+    output: OutputFormat,
+    dump_protocol: bool,
+) -> Result<()> {
+    match send_request(client, url, &Request::KillJob(jobid), dump_protocol)? {
+        Response::Ok => {
+            match output {
+                OutputFormat::Table => println!("Sent SIGTERM to job #{}", jobid),
+                _ => output::print_value(output, &json!({ "id": jobid, "signal": 15 })),
+            }
+            Ok(())
+        }
         Response::Error(s) => {
             eprintln!("Could not kill job: {}", s);
-            Err(::std::io::Error::from(::std::io::ErrorKind::Other))
+            Err(Error::from(ErrorKind::Other))
         }
-        _ => panic!("Unexpected response: {:?}", response),
+        response => panic!("Unexpected response: {:?}", response),
     }
 }
 
-/// Sets the current state of the queue.
+/// Sets the current state of the given queue, or of the default queue.
 /// Note that 'Stopped' cannot be set manually and will yield errors. You will have
 /// to set 'Stopping' and let the queue itself to decide to go into 'Stopped' mode.
 pub fn handle_set_queue_status(
     client: &reqwest::Client,
     url: reqwest::Url,
+    queue: Option<String>,
     new_state: QueueState,
     dump_protocol: bool,
 ) -> Result<()> {
-    let request_s = serde_json::to_string_pretty(&Request::SetQueueState(new_state)).unwrap();
+    let request = Request::SetQueueStateIn {
+        queue,
+        state: new_state,
+    };
+    let request_s = serde_json::to_string_pretty(&request).unwrap();
     let mut response_req = client
         .post(url.clone())
         .body(request_s.clone())
@@ -156,37 +512,71 @@ pub fn handle_set_queue_status(
     let response = serde_json::from_str(&response_s)?;
     match response {
         Response::QueueState(s) => println!("Current queue status: {:?}", s),
-        Response::Error(s) => eprintln!("Could not get queue status: {}", s),
+        Response::Error(s) => {
+            eprintln!("Could not set queue status: {}", s);
+            return Err(Error::from(ErrorKind::Other));
+        }
         _ => panic!("Unexpected response: {:?}", response),
     };
     Ok(())
 }
 
-/// Removes jobs from the finished queue based on their age.
-/// There is no direct JSON command to do this, so it requests
-/// the job lists and removes them manually.
-pub fn handle_cleanup(
+/// Requests the queues with their state and number of jobs
+fn get_queues(
     client: &reqwest::Client,
     url: reqwest::Url,
-    max_age: humantime::Duration,
     dump_protocol: bool,
-) -> Result<usize> {
-    // Request list of finished jobs
-    let request_s = serde_json::to_string_pretty(&Request::GetFinishedJobs).unwrap();
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+) -> Result<Vec<QueueInfo>> {
+    match send_request(client, url, &Request::GetQueues, dump_protocol)? {
+        Response::Queues(queues) => Ok(queues),
+        Response::Error(s) => {
+            eprintln!("Could not get queues: {}", s);
+            Err(Error::from(ErrorKind::Other))
+        }
+        response => panic!("Unexpected response: {:?}", response),
     }
+}
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+/// Requests the host's resources and the ones allocated to running jobs
+fn get_resources(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    dump_protocol: bool,
+) -> Result<Option<ResourceUsage>> {
+    match send_request(client, url, &Request::GetResources, dump_protocol)? {
+        Response::Resources(usage) => Ok(usage),
+        Response::Error(s) => {
+            eprintln!("Could not get resources: {}", s);
+            Err(Error::from(ErrorKind::Other))
+        }
+        response => panic!("Unexpected response: {:?}", response),
     }
+}
+
+/// Lists the queues with their state and number of jobs
+pub fn handle_queues(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    output: OutputFormat,
+    dump_protocol: bool,
+) -> Result<()> {
+    let queues = get_queues(client, url, dump_protocol)?;
+    match output {
+        OutputFormat::Table => table::print_queues(&queues),
+        _ => output::print_queues(output, &queues),
+    }
+    Ok(())
+}
 
+/// Removes jobs from the finished queue based on their age.
+/// The daemon removes all matching jobs at once and returns them.
+pub fn handle_cleanup(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    max_age: humantime::Duration,
+    output: OutputFormat,
+    dump_protocol: bool,
+) -> Result<()> {
     // Get time stamp of oldest acceptable finished job
     let oldest_time = std::time::SystemTime::now() - *max_age;
     debug!(
@@ -196,103 +586,304 @@ pub fn handle_cleanup(
         oldest_time
     );
 
-    // Counter for removed jobs
-    let mut jobs_removed = 0;
-
-    // Find and remove expired jobs
-    if let Response::GetJobs(jobs) = serde_json::from_str(&response_s)? {
-        for job in &jobs {
-            if let Some(t) = job.finished {
-                if t < oldest_time {
-                    match handle_remove(client, url.clone(), job.id, dump_protocol) {
-                        Ok(_) => jobs_removed += 1,
-                        Err(e) => println!("Could not remove job {}: {}", job.id, e),
-                    }
-                }
+    let filter = JobFilter {
+        finished_before: Some(oldest_time),
+        ..JobFilter::default()
+    };
+    let response = send_request(client, url, &Request::Cleanup { filter }, dump_protocol)?;
+
+    match response {
+        Response::GetJobs(jobs) => {
+            match output {
+                OutputFormat::Table => println!("{} jobs removed.", jobs.len()),
+                _ => output::print_jobs(output, &jobs),
             }
+            Ok(())
         }
+        Response::Error(s) => {
+            eprintln!("Could not remove jobs: {}", s);
+            Err(::std::io::Error::from(::std::io::ErrorKind::Other))
+        }
+        _ => panic!("Unexpected response: {:?}", response),
     }
-
-    Ok(jobs_removed)
 }
 
-/// Requests the job queue state, the list of queued, running and finished jobs respectively
-pub fn handle_queue_status(
+/// Requests a page of all jobs matching the given query and prints it
+pub fn handle_history(
     client: &reqwest::Client,
     url: reqwest::Url,
+    query: JobQuery,
+    style: &TableStyle,
+    output: OutputFormat,
     dump_protocol: bool,
 ) -> Result<()> {
-    // Request general queue state
-    let mut request_s = serde_json::to_string_pretty(&Request::GetQueueState).unwrap();
-    let mut response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
-    }
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+    let page = list_jobs(client, url, JobList::All, &query, dump_protocol)?;
+    match output {
+        OutputFormat::Table => print_page("MATCHING JOBS", &page, style, &page.jobs),
+        _ => output::print_page(output, &page),
     }
-    let response = serde_json::from_str(&response_s)?;
+    Ok(())
+}
+
+/// Asks the daemon to reload its config file and reports settings that
+/// could not be applied without a restart
+pub fn handle_reload_config(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    dump_protocol: bool,
+) -> Result<()> {
+    let response = send_request(client, url, &Request::ReloadConfig, dump_protocol)?;
+
     match response {
-        Response::QueueState(s) => println!("Current queue status: {:?}", s),
-        Response::Error(s) => eprintln!("Could not get queue status: {}", s),
+        Response::ConfigReloaded(restart_required) => {
+            println!("Configuration reloaded.");
+            if !restart_required.is_empty() {
+                println!(
+                    "Changes to these settings require a restart: {}",
+                    restart_required.join(", ")
+                );
+            }
+        }
+        Response::Error(s) => {
+            eprintln!("Could not reload configuration: {}", s);
+            return Err(::std::io::Error::from(::std::io::ErrorKind::Other));
+        }
         _ => panic!("Unexpected response: {:?}", response),
-    };
+    }
 
-    // Request list of queued jobs (including running)
-    request_s = serde_json::to_string_pretty(&Request::GetQueuedJobs).unwrap();
-    response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+    Ok(())
+}
+
+/// Subscribes to the daemon's event stream and prints each event as
+/// `<event> <data>` until the connection is closed
+pub fn handle_events(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    job: Option<u64>,
+    owner: Option<String>,
+) -> Result<()> {
+    let mut url = url.join("events").unwrap();
+    {
+        let mut query = url.query_pairs_mut();
+        if let Some(job) = job {
+            query.append_pair("job", &job.to_string());
+        }
+        if let Some(ref owner) = owner {
+            query.append_pair("owner", owner);
+        }
+    }
+    // avoid a dangling '?' without filters
+    if url.query() == Some("") {
+        url.set_query(None);
     }
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+    let response = client.get(url).send().map_err(Error::other)?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let mut response = response;
+        let message = response.text().unwrap_or_default();
+        eprintln!("Could not subscribe to events: {} {}", status, message);
+        return Err(Error::from(ErrorKind::Other));
     }
-    let mut response = serde_json::from_str(&response_s)?;
 
-    match response {
-        Response::GetJobs(jobs) => print_jobs("QUEUED JOBS", jobs),
-        Response::Error(s) => {
-            eprintln!("Could not get queued jobs: {}", s);
+    let mut event = String::new();
+    let mut data = String::new();
+    let stdout = ::std::io::stdout();
+    for line in BufReader::new(response).lines() {
+        let line = line?;
+        if line.is_empty() {
+            if !event.is_empty() || !data.is_empty() {
+                let mut out = stdout.lock();
+                writeln!(out, "{} {}", event, data)?;
+                out.flush()?;
+            }
+            event.clear();
+            data.clear();
+        } else if let Some(value) = line.strip_prefix("event: ") {
+            event = value.to_owned();
+        } else if let Some(value) = line.strip_prefix("data: ") {
+            data = value.to_owned();
         }
-        _ => {
-            panic!("Unexpected response: {:?}", response);
+    }
+
+    Ok(())
+}
+
+/// Writes all queued and finished jobs to the given writer in the export
+/// format: a header line followed by one JSON line per job
+pub fn handle_export<W: Write>(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    mut out: W,
+    dump_protocol: bool,
+) -> Result<()> {
+    let header = ExportRecord::Header {
+        format: EXPORT_FORMAT.to_owned(),
+        version: EXPORT_VERSION,
+        exported: SystemTime::now(),
+    };
+    serde_json::to_writer(&mut out, &header)?;
+    out.write_all(b"\n")?;
+
+    let mut n = 0;
+    match send_request(client, url, &Request::ExportJobs, dump_protocol)? {
+        Response::GetJobs(jobs) => {
+            for job in jobs {
+                serde_json::to_writer(&mut out, &ExportRecord::Job { job })?;
+                out.write_all(b"\n")?;
+                n += 1;
+            }
+        }
+        Response::Error(s) => {
+            eprintln!("Could not get jobs: {}", s);
+            return Err(Error::from(ErrorKind::Other));
         }
+        response => panic!("Unexpected response: {:?}", response),
     }
 
-    // Request list of finished jobs
-    request_s = serde_json::to_string_pretty(&Request::GetFinishedJobs).unwrap();
-    response_req = client
-        .post(url.clone())
-        .body(request_s.clone())
-        .send()
-        .unwrap();
-    if dump_protocol {
-        println!("Sent: {} ", request_s);
+    out.flush()?;
+    info!("Exported {} jobs", n);
+    Ok(())
+}
+
+/// Reads jobs from an export file and submits them to the server for import
+pub fn handle_import<R: Read>(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    input: R,
+    preserve_ids: bool,
+    dry_run: bool,
+    dump_protocol: bool,
+) -> Result<()> {
+    let mut jobs = Vec::new();
+    let mut seen_header = false;
+    for line in BufReader::new(input).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line)? {
+            ExportRecord::Header {
+                format, version, ..
+            } => {
+                if format != EXPORT_FORMAT || version > EXPORT_VERSION {
+                    eprintln!("Unsupported export format {} version {}", format, version);
+                    return Err(Error::from(ErrorKind::InvalidData));
+                }
+                seen_header = true;
+            }
+            ExportRecord::Job { .. } if !seen_header => {
+                eprintln!("Import file does not start with a header line");
+                return Err(Error::from(ErrorKind::InvalidData));
+            }
+            ExportRecord::Job { job } => jobs.push(job),
+        }
     }
 
-    let response_s = response_req.text().unwrap();
-    if dump_protocol {
-        println!("Received: {} ", response_s);
+    let request = Request::ImportJobs {
+        jobs,
+        preserve_ids,
+        dry_run,
+    };
+    match send_request(client, url, &request, dump_protocol)? {
+        Response::ImportReport(report) => {
+            let verb = if dry_run { "Would import" } else { "Imported" };
+            for (old_id, new_id) in &report.imported {
+                println!("{} job #{} as #{}", verb, old_id, new_id);
+            }
+            for (old_id, reason) in &report.skipped {
+                println!("Skipped job #{}: {}", old_id, reason);
+            }
+            println!(
+                "{} {} jobs, skipped {}.",
+                verb,
+                report.imported.len(),
+                report.skipped.len()
+            );
+            Ok(())
+        }
+        Response::Error(s) => {
+            eprintln!("Could not import jobs: {}", s);
+            Err(Error::from(ErrorKind::Other))
+        }
+        response => panic!("Unexpected response: {:?}", response),
     }
-    response = serde_json::from_str(&response_s)?;
-    match response {
-        Response::GetJobs(jobs) => print_jobs("FINISHED JOBS", jobs),
+}
+
+/// Requests a page of the given list of jobs
+fn list_jobs(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    list: JobList,
+    query: &JobQuery,
+    dump_protocol: bool,
+) -> Result<JobPage> {
+    let request = Request::ListJobs {
+        list,
+        query: query.clone(),
+    };
+    match send_request(client, url, &request, dump_protocol)? {
+        Response::JobPage(page) => Ok(page),
         Response::Error(s) => {
-            eprintln!("Could not get finished jobs: {}", s);
+            eprintln!("Could not list jobs: {}", s);
+            Err(Error::from(ErrorKind::Other))
+        }
+        response => panic!("Unexpected response: {:?}", response),
+    }
+}
+
+/// Requests the job queue state, the list of queued, running and finished jobs respectively
+pub fn handle_queue_status(
+    client: &reqwest::Client,
+    url: reqwest::Url,
+    query: JobQuery,
+    style: &TableStyle,
+    output: OutputFormat,
+    dump_protocol: bool,
+) -> Result<()> {
+    let queues = get_queues(client, url.clone(), dump_protocol)?;
+    let selected = query.filter.queue.as_deref().unwrap_or(DEFAULT_QUEUE);
+    let state = match queues.iter().find(|q| q.name == selected) {
+        Some(q) => q.state,
+        None => {
+            eprintln!("Could not get queue status: Unknown queue '{}'", selected);
+            return Err(Error::from(ErrorKind::Other));
+        }
+    };
+
+    let resources = get_resources(client, url.clone(), dump_protocol)?;
+    let queued = list_jobs(client, url.clone(), JobList::Queued, &query, dump_protocol)?;
+    let finished = list_jobs(client, url, JobList::Finished, &query, dump_protocol)?;
+    match output {
+        OutputFormat::Table => {
+            if queues.len() == 1 || query.filter.queue.is_some() {
+                println!("Current queue status: {:?}", state);
+            } else {
+                for q in &queues {
+                    println!(
+                        "Queue {}: {:?}, {} of {} slots busy, {} waiting",
+                        q.name, q.state, q.running, q.slots, q.waiting
+                    );
+                }
+            }
+            if let Some(usage) = resources {
+                let free = usage.total - usage.allocated;
+                println!(
+                    "Resources: {} of {} CPUs and {} of {} memory allocated, {} CPUs and {} free",
+                    usage.allocated.cpus,
+                    usage.total.cpus,
+                    format_mem(usage.allocated.mem),
+                    format_mem(usage.total.mem),
+                    free.cpus,
+                    format_mem(free.mem)
+                );
+            }
+            print_page("QUEUED JOBS", &queued, style, &finished.jobs);
+            print_page("FINISHED JOBS", &finished, style, &finished.jobs);
         }
         _ => {
-            panic!("Unexpected response: {:?}", response);
+            let state = format!("{:?}", state).to_lowercase();
+            output::print_status(output, &state, &queues, resources, &queued, &finished);
         }
     }
 
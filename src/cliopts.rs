@@ -3,8 +3,23 @@ use std::path::PathBuf;
 
 use config::Config;
 use std::collections::HashMap;
+use structopt::clap::ArgGroup;
 use structopt::StructOpt;
 
+use hooks::Hooks;
+use job_queue::{JobEvent, JobFilter, JobQuery, SortKey};
+use job_runner::DEFAULT_SPOOL_DIR;
+use limits::{self, AppkeyLimits};
+use mailer::Mailer;
+use notifier::NotifyPolicy;
+use output::OutputFormat;
+use queues::Queues;
+use resources::{parse_mem, Resources};
+use retention::RetentionPolicy;
+use shutdown::ShutdownPolicy;
+use state::{DEFAULT_JOURNAL_COMPACT_AFTER, DEFAULT_STORAGE};
+use table::TableStyle;
+
 /// Default port for use with both daemon and client code
 pub const DEFAULT_PORT: u16 = 1337;
 
@@ -38,6 +53,10 @@ pub struct Opt {
     /// Dump client requests and responses to stdout
     pub dump_json: bool,
 
+    #[structopt(long, global = true, default_value = "table")]
+    /// Output format of client commands (table, json, jsonl or csv)
+    pub output: OutputFormat,
+
     #[structopt(long, default_value = "")]
     /// The log level (default: Info, possible: Error, Warn, Info, Debug)
     pub loglevel: String,
@@ -50,11 +69,55 @@ pub struct Opt {
     /// Application keys
     pub appkeys: HashMap<String, PathBuf>,
 
+    #[structopt(skip)]
+    /// Limits on running jobs per appkey
+    pub appkey_limits: AppkeyLimits,
+
+    #[structopt(skip)]
+    /// CPUs and memory of the host, None for no limit
+    pub resources: Option<Resources>,
+
     #[structopt(subcommand)]
     pub cmd: OptCommand,
 
     #[structopt(long, parse(from_os_str))]
     pub state_file: Option<PathBuf>,
+
+    #[structopt(long, default_value = "")]
+    /// Storage backend for the program state (default: json, possible: json, sqlite, journal)
+    pub storage: String,
+
+    #[structopt(skip)]
+    /// Rules for removing finished jobs (daemon only)
+    pub retention: RetentionPolicy,
+
+    #[structopt(skip)]
+    /// How job reports are delivered to the notify URL (daemon only)
+    pub notify: NotifyPolicy,
+
+    #[structopt(skip)]
+    /// Mails sent when jobs have ended, if configured (daemon only)
+    pub mail: Option<Mailer>,
+
+    #[structopt(skip)]
+    /// Local commands run on job events (daemon only)
+    pub hooks: Hooks,
+
+    #[structopt(skip)]
+    /// What to do with a running job on SIGTERM (daemon only)
+    pub shutdown: ShutdownPolicy,
+
+    #[structopt(skip)]
+    /// Named queues (daemon only)
+    pub queues: Queues,
+
+    #[structopt(skip)]
+    /// Directory for output and exit status of running jobs (daemon only)
+    pub spool_dir: PathBuf,
+
+    #[structopt(skip)]
+    /// Number of journal events after which a snapshot is written (journal storage only)
+    pub journal_compact_after: u64,
 }
 
 #[derive(Debug, StructOpt)]
@@ -82,19 +145,67 @@ pub enum OptCommand {
         notify_url: Option<String>,
     },
 
-    /// Requests the queue to be stopped
-    Stop {},
+    /// Requests the queue to be stopped. Running jobs are finished first.
+    Stop {
+        /// Queue to stop (default: the default queue)
+        #[structopt(long)]
+        queue: Option<String>,
+    },
 
     /// Requests queue operations to be resumed
-    Start {},
+    Start {
+        /// Queue to start (default: the default queue)
+        #[structopt(long)]
+        queue: Option<String>,
+    },
+
+    /// Lists the queues with their state and number of jobs
+    Queues {},
 
     /// Requests queue status
-    Status {},
+    Status {
+        #[structopt(flatten)]
+        list: ListArgs,
+
+        #[structopt(flatten)]
+        table: TableArgs,
+    },
 
     /// Submits a job to the queue
     Submit {
         #[structopt(name = "CMDLINE", parse(from_str))]
         cmdline: String,
+
+        /// URL that reports on this job are POSTed to. Must be allowed by the daemon.
+        #[structopt(long)]
+        notify_url: Option<String>,
+
+        /// Events to send reports for, comma-separated (queued, started,
+        /// finished, failed, killed, timed_out). Default: finished, failed, killed
+        #[structopt(long, use_delimiter = true, requires = "notify-url")]
+        notify_on: Vec<JobEvent>,
+
+        /// Address that a mail is sent to when the job has ended. By default,
+        /// the daemon may derive an address from your user name.
+        #[structopt(long)]
+        mail_to: Option<String>,
+
+        /// Queue to run the job in (default: the appkey's default queue)
+        #[structopt(long)]
+        queue: Option<String>,
+
+        /// Number of CPUs the job needs (default: the appkey's default, or 1)
+        #[structopt(long)]
+        cpus: Option<u32>,
+
+        /// Memory the job needs, i.e. 512M or 64G (default: the appkey's
+        /// default, or none)
+        #[structopt(long, parse(try_from_str = parse_mem))]
+        mem: Option<u64>,
+
+        /// Print only the ID of the job
+        #[structopt(short, long)]
+        quiet: bool,
     },
 
     /// Removes a finished job from the queue
@@ -104,6 +215,17 @@ pub enum OptCommand {
         job_id: u64,
     },
 
+    /// Shows a single job in detail
+    Show {
+        /// Job ID to show
+        #[structopt(name = "JOB_ID")]
+        job_id: u64,
+
+        /// Print the job as JSON, same as '--output json'
+        #[structopt(long)]
+        json: bool,
+    },
+
     /// Asks a running job to terminate
     Kill {
         /// Job ID to terminate
@@ -111,19 +233,283 @@ pub enum OptCommand {
         job_id: u64,
     },
 
-    /// Removes finished jobs from the queue based on timestamps
+    /// Keeps a queued job from being started until it is released. The other
+    /// jobs in the queue are started as usual.
+    Hold {
+        /// Job ID to hold
+        #[structopt(long)]
+        job_id: u64,
+    },
+
+    /// Releases a held job, so that it is started at its position in the queue
+    Release {
+        /// Job ID to release
+        #[structopt(long)]
+        job_id: u64,
+    },
+
+    /// Submits a copy of a job that has ended with the same command line and
+    /// options, i.e. to re-run a failed job. The copy refers to the original.
+    Resubmit {
+        /// Job ID to copy
+        #[structopt(name = "JOB_ID")]
+        job_id: u64,
+
+        /// Edit the command line in $EDITOR before submitting
+        #[structopt(long)]
+        edit: bool,
+
+        /// Queue to run the copy in (default: the queue of the original)
+        #[structopt(long)]
+        queue: Option<String>,
+
+        /// Only print the ID of the new job
+        #[structopt(short, long)]
+        quiet: bool,
+    },
+
+    /// Changes a queued or held job before it is started. Each change is
+    /// recorded in the job's history, see 'show'.
+    #[structopt(group = ArgGroup::with_name("changes").required(true).multiple(true))]
+    Update {
+        /// Job ID to change
+        #[structopt(name = "JOB_ID")]
+        job_id: u64,
+
+        /// New arguments, i.e. the command line without the appkey
+        #[structopt(long, group = "changes", allow_hyphen_values = true)]
+        args: Option<String>,
+
+        /// New URL that reports on this job are POSTed to, '' turns reports off
+        #[structopt(long, group = "changes")]
+        notify_url: Option<String>,
+
+        /// New events to send reports for, comma-separated
+        #[structopt(long, group = "changes", use_delimiter = true)]
+        notify_on: Option<Vec<JobEvent>>,
+
+        /// New address that a mail is sent to when the job has ended, '' removes it
+        #[structopt(long, group = "changes")]
+        mail_to: Option<String>,
+    },
+
+    /// Moves a queued job to another position in the queue
+    #[structopt(group = ArgGroup::with_name("to").required(true))]
+    Move {
+        /// Job ID to move
+        #[structopt(name = "JOB_ID")]
+        job_id: u64,
+
+        /// Move the job in front of all waiting jobs
+        #[structopt(long, group = "to")]
+        to_top: bool,
+
+        /// Move the job directly in front of this job
+        #[structopt(long, group = "to", value_name = "JOB_ID")]
+        before: Option<u64>,
+
+        /// Move the job directly behind this job
+        #[structopt(long, group = "to", value_name = "JOB_ID")]
+        after: Option<u64>,
+
+        /// Move the job to this position among the waiting jobs, starting at 1
+        #[structopt(long, group = "to", value_name = "N")]
+        position: Option<usize>,
+    },
+
+    /// Removes finished jobs from the queue based on timestamps. The daemon
+    /// can do this by itself, see the [retention] section of the config file.
     Cleanup {
         /// Maximum age of a job's 'finished' timestamp, i.e. '8 days 3 seconds'
         #[structopt(long)]
         max_age: humantime::Duration,
     },
+
+    /// Searches queued and finished jobs
+    History {
+        #[structopt(flatten)]
+        list: ListArgs,
+
+        #[structopt(flatten)]
+        table: TableArgs,
+    },
+
+    /// Writes all queued and finished jobs to a JSON-lines file
+    Export {
+        /// Output file (default: standard output)
+        #[structopt(long, parse(from_os_str))]
+        file: Option<PathBuf>,
+    },
+
+    /// Loads jobs from a file written by 'export' into the daemon
+    Import {
+        /// File written by 'export'
+        #[structopt(name = "FILE", parse(from_os_str))]
+        file: PathBuf,
+
+        /// Keep the job IDs from the file and skip jobs whose ID is taken.
+        /// By default, imported jobs are assigned new IDs.
+        #[structopt(long)]
+        preserve_ids: bool,
+
+        /// Only report what would be imported
+        #[structopt(long)]
+        dry_run: bool,
+    },
+
+    /// Runs a single job on behalf of the daemon (internal use only)
+    #[structopt(setting = structopt::clap::AppSettings::Hidden)]
+    RunJob {
+        /// File that stdout of the job is written to
+        #[structopt(long, parse(from_os_str))]
+        stdout_file: PathBuf,
+
+        /// File that stderr of the job is written to
+        #[structopt(long, parse(from_os_str))]
+        stderr_file: PathBuf,
+
+        /// File that the exit status of the job is written to
+        #[structopt(long, parse(from_os_str))]
+        status_file: PathBuf,
+
+        /// Command to run
+        #[structopt(name = "COMMAND", last = true)]
+        command: Vec<String>,
+    },
+
+    /// Asks the daemon to re-read its configuration file, like on SIGHUP
+    ReloadConfig {},
+
+    /// Prints queue and job events as they happen, until interrupted
+    Events {
+        /// Only show events of this job
+        #[structopt(long)]
+        job: Option<u64>,
+
+        /// Only show events of jobs of this owner
+        #[structopt(long)]
+        owner: Option<String>,
+    },
+
+    /// Shows the queue and its jobs in a full-screen view that refreshes
+    /// itself, like top(1). Press 'q' to quit.
+    Top {},
+
+    /// Copies the program state from a JSON state file into the configured
+    /// storage backend. The daemon should not be running.
+    MigrateState {
+        /// JSON state file to import
+        #[structopt(long, parse(from_os_str))]
+        from: PathBuf,
+    },
+}
+
+// Filter, order and page of job listings (`status` and `history`). Not a doc
+// comment, see `TableArgs`.
+#[derive(Debug, StructOpt)]
+pub struct ListArgs {
+    /// Only jobs in the given state (queued, held, running, terminated, killed,
+    /// failed)
+    #[structopt(long)]
+    state: Option<String>,
+
+    /// Only jobs with the given appkey
+    #[structopt(long)]
+    appkey: Option<String>,
+
+    /// Only jobs submitted by the given user
+    #[structopt(long)]
+    owner: Option<String>,
+
+    /// Only jobs of the given queue
+    #[structopt(long)]
+    queue: Option<String>,
+
+    /// Only jobs submitted at or after the given time, i.e. '2020-03-01 12:00:00'
+    #[structopt(long)]
+    since: Option<humantime::Timestamp>,
+
+    /// Only jobs submitted before the given time
+    #[structopt(long)]
+    until: Option<humantime::Timestamp>,
+
+    /// Only jobs whose command line contains the given text
+    #[structopt(long)]
+    grep: Option<String>,
+
+    /// Only copies of the job with the given ID made by 'resubmit'
+    #[structopt(long, value_name = "JOB_ID")]
+    resubmitted_from: Option<u64>,
+
+    /// Sort by id, submitted, started, finished, appkey, owner or state
+    #[structopt(long)]
+    sort: Option<SortKey>,
+
+    /// Sort in descending order
+    #[structopt(long)]
+    desc: bool,
+
+    /// Show at most this many jobs (per list)
+    #[structopt(long)]
+    limit: Option<usize>,
+
+    /// Skip this many matching jobs (per list)
+    #[structopt(long, default_value = "0")]
+    offset: usize,
+}
+
+impl ListArgs {
+    pub fn query(self) -> JobQuery {
+        JobQuery {
+            filter: JobFilter {
+                state: self.state,
+                appkey: self.appkey,
+                owner: self.owner,
+                queue: self.queue,
+                since: self.since.map(|t| *t),
+                until: self.until.map(|t| *t),
+                cmdline: self.grep,
+                resubmitted_from: self.resubmitted_from,
+                ..JobFilter::default()
+            },
+            sort: self.sort,
+            descending: self.desc,
+            offset: self.offset,
+            limit: self.limit,
+        }
+    }
+}
+
+// Layout of job listings. Not a doc comment, which would replace the help
+// text of the subcommands that flatten this.
+#[derive(Debug, StructOpt)]
+pub struct TableArgs {
+    /// Show all time stamps and the PID, and never cut off command lines
+    #[structopt(long)]
+    wide: bool,
+
+    /// Print each job through a template instead of a table, i.e.
+    /// '{id}\t{state}\t{cmdline}'. Placeholders: id, appkey, owner, queue,
+    /// state, exit, submitted, started, finished, runtime, pid, cmdline and
+    /// resubmitted_from
+    #[structopt(long)]
+    format: Option<String>,
+}
+
+impl TableArgs {
+    pub fn style(self) -> TableStyle {
+        TableStyle {
+            wide: self.wide,
+            format: self.format,
+        }
+    }
 }
 
 impl Opt {
     /// Merges a config file with the command-line options.
     /// CLI options generally take precedence over options imported from
-    /// the config file.
-    pub fn merge_config(&mut self, conf: Config) {
+    /// the config file. Fails if the config file contains invalid settings.
+    pub fn merge_config(&mut self, conf: Config) -> std::result::Result<(), String> {
         // if --insecure is not present on the CL, check config for CA.
         // Certs and keys will be checked when destructuring the self.cmd.
         if !self.insecure {
@@ -157,10 +543,21 @@ impl Opt {
         if self.state_file.is_none() {
             self.state_file = Some(PathBuf::from(
                 conf.get_str("state-file")
-                    .unwrap_or(DEFAULT_STATE.to_string()),
+                    .unwrap_or_else(|_| DEFAULT_STATE.to_string()),
             ));
         }
 
+        // storage backend for the state file (daemon only)
+        if self.storage.is_empty() {
+            self.storage = conf
+                .get_str("storage")
+                .unwrap_or_else(|_| DEFAULT_STORAGE.to_string());
+        }
+        self.journal_compact_after = conf
+            .get_int("journal-compact-after")
+            .map(|n| n.max(1) as u64)
+            .unwrap_or(DEFAULT_JOURNAL_COMPACT_AFTER);
+
         // daemon-specific opts
         if let OptCommand::Daemon {
             ref mut cert,
@@ -187,11 +584,15 @@ impl Opt {
             }
         }
 
-        let appkeys = conf
-            .get_table("appkeys")
-            .expect("Could not load appkeys from config file!");
-        for (k, v) in appkeys {
-            self.appkeys.insert(k, PathBuf::from(v.into_str().unwrap()));
+        self.spool_dir = PathBuf::from(
+            conf.get_str("spool-dir")
+                .unwrap_or_else(|_| DEFAULT_SPOOL_DIR.to_string()),
+        );
+
+        // the remaining sections are only used by the daemon, so that
+        // client commands keep working with a broken daemon configuration
+        if let OptCommand::Daemon { .. } = self.cmd {
+            self.merge_daemon_config(&conf)?;
         }
 
         // set log level
@@ -200,6 +601,47 @@ impl Opt {
                 .get_str("loglevel")
                 .unwrap_or_else(|_| "Info".to_owned());
         }
+
+        Ok(())
+    }
+
+    /// Merges the sections of the config file that only the daemon uses.
+    /// Fails if any of them contains invalid settings.
+    fn merge_daemon_config(&mut self, conf: &Config) -> std::result::Result<(), String> {
+        self.retention = RetentionPolicy::from_config(conf)
+            .map_err(|e| format!("Could not parse retention settings: {}", e))?;
+
+        self.notify = NotifyPolicy::from_config(conf)
+            .map_err(|e| format!("Could not parse notify settings: {}", e))?;
+
+        self.mail = Mailer::from_config(conf)
+            .map_err(|e| format!("Could not parse mail settings: {}", e))?;
+
+        self.hooks =
+            Hooks::from_config(conf).map_err(|e| format!("Could not parse hooks: {}", e))?;
+
+        self.shutdown = ShutdownPolicy::from_config(conf)
+            .map_err(|e| format!("Could not parse shutdown settings: {}", e))?;
+
+        self.resources = Resources::from_config(conf)
+            .map_err(|e| format!("Could not parse resources: {}", e))?;
+
+        // without a slot count, queues run as many jobs as fit into the host
+        let default_slots = self.resources.map_or(1, |r| r.cpus as usize);
+        self.queues = Queues::from_config(conf, default_slots)
+            .map_err(|e| format!("Could not parse queues: {}", e))?;
+
+        let appkeys = conf
+            .get_table("appkeys")
+            .map_err(|e| format!("Could not load appkeys from config file: {}", e))?;
+        for (k, v) in appkeys {
+            let (path, limit) =
+                limits::parse_appkey(v).map_err(|e| format!("Invalid appkey '{}': {}", k, e))?;
+            self.appkeys.insert(k.clone(), path);
+            self.appkey_limits.insert(k, limit);
+        }
+
+        Ok(())
     }
 
     /// Checks general validity of the option occurrences
@@ -233,6 +675,14 @@ impl Opt {
             }
         }
 
+        if !["json", "sqlite", "journal"].contains(&self.storage.as_str()) {
+            eprintln!(
+                "Unknown storage backend '{}', use json, sqlite or journal!",
+                self.storage
+            );
+            return Err(std::io::Error::from(ErrorKind::InvalidInput));
+        }
+
         // PathBuf validity is checked when the path is actually opened later, no need to check here.
         Ok(())
     }
@@ -1,4 +1,3 @@
-use std::collections::HashMap;
 /// daemon.rs
 ///
 /// Contains all code that the daemon needs to run.
@@ -15,9 +14,12 @@ use std::collections::HashMap;
 /// 4. The condition variable governing the communication between the queue
 ///    and the client handler is set up.
 ///
-/// 5. A new thread is spawned to process the job queue (see `fn run_queue`)
+/// 5. A thread is spawned for each slot of the job queues to process them
+///    (see `fn run_queue`)
 ///
-/// 6. A new thread is spawned to process external signals like SIGTERM
+/// 6. A new thread is spawned to process external signals like SIGTERM,
+///    which shuts the daemon down gracefully (see `shutdown`), and SIGHUP,
+///    which reloads the config file (see `settings`)
 ///
 /// 7. The job queue is notified that it may start/resume operating
 ///
@@ -28,22 +30,25 @@ use std::collections::HashMap;
 /// Once everything is properly set up, the main thread takes care of
 /// accepting and processing client requests. For each client request,
 /// the function `handle_client` is invoked that decodes the JSON block
-/// and acts upon the request.
+/// and acts upon the request. Requests to the REST API (see `rest.rs`) are
+/// translated to the same requests.
 ///
-/// To conserve CPU time, the job queue thread is blocking on a condition
-/// variable when it is idle. Once a client requests that a job is submitted
-/// to the queue, it is moved into the job queue structure and the thread
-/// is woken up. It then starts processing one job after another until the
-/// queue is empty again where it blocks on the variable again.
+/// To conserve CPU time, the job queue threads are blocking on a condition
+/// variable when they are idle. Once a client requests that a job is submitted
+/// to the queue, it is moved into the job queue structure and the threads
+/// are woken up. Each of them starts processing one job after another until
+/// no job can be started anymore, where it blocks on the variable again.
 // std
+use std::collections::BTreeSet;
+use std::collections::HashMap;
 use std::error::Error;
-use std::io::Result;
+use std::io::{ErrorKind, Result};
 use std::net::SocketAddr;
-use std::os::unix::process::ExitStatusExt;
-use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::sync::{Arc, Condvar, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock};
 use std::thread;
+use std::time::SystemTime;
 
 // crates
 use daemonize::Daemonize;
@@ -53,8 +58,19 @@ use systemd::daemon;
 use tiny_http::{Server, SslConfig};
 
 // modules
-use job_queue::{FailReason, Job, JobQueue, JobState, QueueState};
+use events::{self, BusEvent, EventBus};
+use job_queue::{
+    FailReason, Job, JobEvent, JobList, JobQueue, JobState, QueueState, SubmitOptions,
+    DEFAULT_QUEUE,
+};
+use job_runner::{self, JobFiles, Launcher};
+use notifier::{self, Outbox};
 use protocol::{Request, Response};
+use resources::{format_mem, Resources};
+use rest::{self, Reply};
+use retention;
+use settings::{self, Settings};
+use shutdown::{self, Termination, Terminations};
 use state::State;
 
 /// Detaches the current process from the terminal and the current task
@@ -98,150 +114,653 @@ fn spawn_https(
     }
 }
 
-/// Handles a single HTTP request sent by a single client.
-/// Translates the JSON block to a Request, evaluates the
-/// request (i.e. adds a job to the queue) and returns
-/// a JSON result to the client.
-fn handle_client(
-    mut httprequest: tiny_http::Request,
-    q_mutex: Arc<(Mutex<JobQueue>, Condvar)>,
-    dump_protocol: bool,
-    state: Arc<Mutex<State>>,
-) {
-    let (ref q_mutex, ref cvar) = *q_mutex;
+/// Why a request could not be handled
+#[derive(Debug)]
+enum RequestError {
+    /// The job does not exist
+    NotFound(String),
 
-    let mut s = String::from("");
-    httprequest.as_reader().read_to_string(&mut s).unwrap();
+    /// The job is not in a state that allows the request
+    Conflict(String),
 
-    if dump_protocol {
-        debug!("[handle_client] Got data: {}", &s);
+    /// The request itself is not acceptable
+    Invalid(String),
+
+    /// The daemon failed to carry out the request
+    Internal(String),
+}
+
+impl RequestError {
+    /// Status code used by the REST API
+    fn status(&self) -> u16 {
+        match *self {
+            RequestError::NotFound(_) => 404,
+            RequestError::Conflict(_) => 409,
+            RequestError::Invalid(_) => 422,
+            RequestError::Internal(_) => 500,
+        }
     }
-    let request = serde_json::from_str(&s);
 
-    debug!("[handle_client] Processing request: {:?}", request);
+    /// Status code used by the protocol of `Request` and `Response`
+    fn protocol_status(&self) -> u16 {
+        match *self {
+            RequestError::Internal(_) => 500,
+            _ => 422,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match *self {
+            RequestError::NotFound(ref s)
+            | RequestError::Conflict(ref s)
+            | RequestError::Invalid(ref s)
+            | RequestError::Internal(ref s) => s,
+        }
+    }
+}
+
+/// Evaluates a single request, i.e. adds a job to the queue
+fn process_request(
+    request: Request,
+    q_mutex: &(Mutex<JobQueue>, Condvar),
+    state: &Mutex<State>,
+    settings: &RwLock<Settings>,
+    outbox: &(Mutex<Outbox>, Condvar),
+) -> std::result::Result<Response, RequestError> {
+    let (ref q_mutex, ref cvar) = *q_mutex;
 
-    let (status_code, response_s) = match request {
-        Ok(Request::GetQueuedJobs) => {
+    // clients that do not know about named queues refer to the default queue
+    let default_queue = || Some(DEFAULT_QUEUE.to_string());
+    let request = match request {
+        Request::GetQueuedJobs => Request::GetQueuedJobsIn {
+            queue: default_queue(),
+        },
+        Request::GetFinishedJobs => Request::GetFinishedJobsIn {
+            queue: default_queue(),
+        },
+        Request::GetQueueState => Request::GetQueueStateIn { queue: None },
+        Request::SetQueueState(state) => Request::SetQueueStateIn { queue: None, state },
+        request => request,
+    };
+
+    match request {
+        Request::GetQueuedJobs
+        | Request::GetFinishedJobs
+        | Request::GetQueueState
+        | Request::SetQueueState(_) => unreachable!("translated above"),
+
+        Request::GetQueuedJobsIn { queue } => {
             let q = q_mutex.lock().unwrap();
-            let items = q.iter_queued().cloned().collect();
-            (
-                200,
-                serde_json::to_string_pretty(&Response::GetJobs(items)).unwrap(),
-            )
+            let jobs = q
+                .iter_queued()
+                .filter(|j| queue.as_ref().is_none_or(|n| &j.queue == n));
+            Ok(Response::GetJobs(jobs.cloned().collect()))
         }
 
-        Ok(Request::GetQueueState) => {
+        Request::GetQueueStateIn { queue } => {
+            let queue = known_queue(queue, settings)?;
             let q = q_mutex.lock().unwrap();
-            let state = q.get_state();
-            (
-                200,
-                serde_json::to_string_pretty(&Response::QueueState(state)).unwrap(),
-            )
+            Ok(Response::QueueState(q.get_state(&queue)))
         }
 
-        Ok(Request::SetQueueState(new_state)) => {
+        Request::SetQueueStateIn {
+            queue,
+            state: new_state,
+        } => {
+            let queue = known_queue(queue, settings)?;
             let mut q = q_mutex.lock().unwrap();
-            q.set_state(new_state);
-            cvar.notify_one();
-            let state = state.lock().unwrap();
-            state.save(&q).expect("Could not write program state");
-            (
-                200,
-                serde_json::to_string_pretty(&Response::QueueState(new_state)).unwrap(),
-            )
+            q.set_state(&queue, new_state);
+            cvar.notify_all();
+            let mut state = state.lock().unwrap();
+            state.save(&mut q).expect("Could not write program state");
+            Ok(Response::QueueState(q.get_state(&queue)))
         }
 
-        Ok(Request::GetFinishedJobs) => {
+        Request::GetQueues => {
+            let queues = settings.read().unwrap().queues.clone();
             let q = q_mutex.lock().unwrap();
-            let items = q.iter_finished().cloned().collect();
-            (
-                200,
-                serde_json::to_string_pretty(&Response::GetJobs(items)).unwrap(),
-            )
+            Ok(Response::Queues(queues.info(&q)))
         }
 
-        Ok(Request::RemoveJob(id)) => {
+        Request::GetResources => {
+            let q = q_mutex.lock().unwrap();
+            Ok(Response::Resources(q.resource_usage()))
+        }
+
+        Request::GetFinishedJobsIn { queue } => {
+            let q = q_mutex.lock().unwrap();
+            let jobs = q
+                .iter_finished()
+                .filter(|j| queue.as_ref().is_none_or(|n| &j.queue == n));
+            Ok(Response::GetJobs(jobs.cloned().collect()))
+        }
+
+        Request::GetJob(id) => {
+            let q = q_mutex.lock().unwrap();
+            match q.get_job(id) {
+                Some(job) => Ok(Response::GetJob(job.clone())),
+                None => Err(RequestError::NotFound("No such job".to_string())),
+            }
+        }
+
+        Request::RemoveJob(id) => {
             let mut q = q_mutex.lock().unwrap();
             let s = q.remove(id);
-            let state = state.lock().unwrap();
-            state.save(&q).expect("Could not write program state");
+            let mut state = state.lock().unwrap();
+            state.save(&mut q).expect("Could not write program state");
             match s {
-                Ok(job) => (
-                    200,
-                    serde_json::to_string_pretty(&Response::GetJob(job)).unwrap(),
-                ),
-                Err(FailReason::NoSuchJob) => (
-                    422,
-                    serde_json::to_string_pretty(&Response::Error("No such job".to_string()))
-                        .unwrap(),
-                ),
-                Err(FailReason::WrongJobState) => (
-                    422,
-                    serde_json::to_string_pretty(&Response::Error(
-                        "Job is currently running and cannot be removed".to_string(),
-                    ))
-                    .unwrap(),
-                ),
+                Ok(job) => Ok(Response::GetJob(job)),
+                Err(FailReason::NoSuchJob) => {
+                    Err(RequestError::NotFound("No such job".to_string()))
+                }
+                Err(FailReason::WrongJobState) => Err(RequestError::Conflict(
+                    "Job is currently running and cannot be removed".to_string(),
+                )),
             }
         }
 
-        Ok(Request::KillJob(id)) => {
+        Request::KillJob(id) => {
             let mut q = q_mutex.lock().unwrap();
             match q.send_sigterm(id) {
-                Ok(_) => (200, serde_json::to_string_pretty(&Response::Ok).unwrap()),
-                _ => (
-                    422,
-                    serde_json::to_string_pretty(&Response::Error(
-                        "Job is currently not running.".to_string(),
-                    ))
-                    .unwrap(),
-                ),
+                Ok(_) => Ok(Response::Ok),
+                Err(_) if q.get_job(id).is_none() => {
+                    Err(RequestError::NotFound("No such job".to_string()))
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => Err(RequestError::Conflict(
+                    "Job is just being started, try again.".to_string(),
+                )),
+                Err(_) => Err(RequestError::Conflict(
+                    "Job is currently not running.".to_string(),
+                )),
+            }
+        }
+
+        Request::HoldJob(id) | Request::ReleaseJob(id) => {
+            let hold = matches!(request, Request::HoldJob(_));
+            let mut q = q_mutex.lock().unwrap();
+            let result = if hold { q.hold(id) } else { q.release(id) };
+            if result.is_ok() {
+                cvar.notify_all();
+                let mut state = state.lock().unwrap();
+                state.save(&mut q).expect("Could not write program state");
+            }
+            match result {
+                Ok(()) => Ok(Response::GetJob(q.get_job(id).unwrap().clone())),
+                Err(FailReason::NoSuchJob) => {
+                    Err(RequestError::NotFound("No such job".to_string()))
+                }
+                Err(FailReason::WrongJobState) if hold => Err(RequestError::Conflict(
+                    "Only queued jobs that have not been started can be held".to_string(),
+                )),
+                Err(FailReason::WrongJobState) => {
+                    Err(RequestError::Conflict("Job is not held".to_string()))
+                }
+            }
+        }
+
+        Request::UpdateJob { id, changes } => {
+            if let Some(ref url) = changes.notify_url {
+                if !url.is_empty() {
+                    check_notify_url(url, settings)?;
+                }
+            }
+            if let Some(ref mail_to) = changes.mail_to {
+                if !mail_to.is_empty() {
+                    check_mail_to(mail_to, settings)?;
+                }
+            }
+
+            let mut q = q_mutex.lock().unwrap();
+            let has_notify = q.get_job(id).is_some_and(|j| j.notify.is_some());
+            if changes.notify_on.is_some() && changes.notify_url.is_none() && !has_notify {
+                return Err(RequestError::Invalid(
+                    "The job has no notify URL to send reports to".to_string(),
+                ));
+            }
+            match q.update(id, changes) {
+                Ok(job) => {
+                    let mut state = state.lock().unwrap();
+                    state.save(&mut q).expect("Could not write program state");
+                    Ok(Response::GetJob(job))
+                }
+                Err(FailReason::NoSuchJob) => {
+                    Err(RequestError::NotFound("No such job".to_string()))
+                }
+                Err(FailReason::WrongJobState) => Err(RequestError::Conflict(
+                    "Only queued jobs that have not been started can be changed".to_string(),
+                )),
             }
         }
 
-        Ok(Request::SubmitJob(cmdline)) => {
+        Request::MoveJob { id, to } => {
             let mut q = q_mutex.lock().unwrap();
-            let id = q.submit(cmdline);
+            match q.move_job(id, to) {
+                Ok(()) => {
+                    let mut state = state.lock().unwrap();
+                    state.save(&mut q).expect("Could not write program state");
+                    Ok(Response::GetJobs(q.iter_queued().cloned().collect()))
+                }
+                Err(FailReason::NoSuchJob) if q.get_job(id).is_none() => {
+                    Err(RequestError::NotFound("No such job".to_string()))
+                }
+                Err(FailReason::NoSuchJob) => Err(RequestError::Invalid(
+                    "The job to move it next to is not waiting in the same queue".to_string(),
+                )),
+                Err(FailReason::WrongJobState) => Err(RequestError::Conflict(
+                    "Only queued jobs that have not been started can be moved".to_string(),
+                )),
+            }
+        }
+
+        Request::FindJobs(filter) => {
+            let q = q_mutex.lock().unwrap();
             let state = state.lock().unwrap();
-            state.save(&q).expect("Could not write program state");
-            cvar.notify_one();
-            (
-                200,
-                serde_json::to_string_pretty(&Response::SubmitJob(id)).unwrap(),
-            )
+            state
+                .find_jobs(&q, &filter)
+                .map(Response::GetJobs)
+                .map_err(|e| RequestError::Internal(e.to_string()))
         }
 
-        Err(e) => {
-            if e.is_io() {
-                (500, e.to_string().to_owned())
-            } else {
-                (400, e.to_string().to_owned())
+        Request::ListJobs { list, query } => match list {
+            JobList::Queued => {
+                let q = q_mutex.lock().unwrap();
+                Ok(Response::JobPage(query.apply(q.iter_queued())))
+            }
+            JobList::Finished => {
+                let q = q_mutex.lock().unwrap();
+                Ok(Response::JobPage(query.apply(q.iter_finished())))
+            }
+            JobList::All => {
+                let q = q_mutex.lock().unwrap();
+                let state = state.lock().unwrap();
+                state
+                    .query_jobs(&q, &query)
+                    .map(Response::JobPage)
+                    .map_err(|e| RequestError::Internal(e.to_string()))
+            }
+        },
+
+        Request::Cleanup { filter } => {
+            let archive = settings.read().unwrap().retention.archive.clone();
+            let select = |q: &JobQueue| {
+                q.iter_finished()
+                    .filter(|j| filter.matches(j))
+                    .cloned()
+                    .collect()
+            };
+            match prune_finished(q_mutex, state, archive.as_deref(), select) {
+                Ok(jobs) => {
+                    info!("[handle_client] Cleanup removed {} jobs", jobs.len());
+                    Ok(Response::GetJobs(jobs))
+                }
+                Err(e) => Err(RequestError::Internal(format!(
+                    "Could not archive jobs: {}",
+                    e
+                ))),
+            }
+        }
+
+        Request::ExportJobs => {
+            let q = q_mutex.lock().unwrap();
+            let jobs = q.iter_queued().chain(q.iter_finished()).cloned();
+            Ok(Response::GetJobs(jobs.collect()))
+        }
+
+        Request::ImportJobs {
+            jobs,
+            preserve_ids,
+            dry_run,
+        } => {
+            let mut rejected = Vec::new();
+            let jobs = jobs
+                .into_iter()
+                .filter(|job| match check_imported(job, settings) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        rejected.push((job.id, e.message().to_string()));
+                        false
+                    }
+                })
+                .collect();
+
+            let mut q = q_mutex.lock().unwrap();
+            let mut report = q.import(jobs, preserve_ids, dry_run);
+            report.skipped.extend(rejected);
+            if !dry_run {
+                info!(
+                    "[handle_client] Imported {} jobs, skipped {}",
+                    report.imported.len(),
+                    report.skipped.len()
+                );
+                let mut state = state.lock().unwrap();
+                state.save(&mut q).expect("Could not write program state");
+                cvar.notify_all();
+            }
+            Ok(Response::ImportReport(report))
+        }
+
+        Request::ReloadConfig => reload_config(settings, (q_mutex, cvar))
+            .map(Response::ConfigReloaded)
+            .map_err(RequestError::Invalid),
+
+        Request::SubmitJob(cmdline) => submit_job(
+            cmdline,
+            SubmitOptions::default(),
+            (q_mutex, cvar),
+            state,
+            settings,
+            outbox,
+        ),
+
+        Request::SubmitJobWith { cmdline, options } => {
+            submit_job(cmdline, options, (q_mutex, cvar), state, settings, outbox)
+        }
+
+        Request::ResubmitJob { id, cmdline, queue } => {
+            // the settings are checked without holding the queue, the queue
+            // checks again that the job has ended
+            let job = match q_mutex.lock().unwrap().get_job(id) {
+                Some(job) if job.finished.is_none() => {
+                    return Err(RequestError::Conflict(
+                        "Job has not ended yet and cannot be resubmitted".to_string(),
+                    ))
+                }
+                Some(job) => job.clone(),
+                None => return Err(RequestError::NotFound("No such job".to_string())),
+            };
+            // the allowed URLs and queues may have changed since the original
+            // was submitted
+            if let Some(ref notify) = job.notify {
+                check_notify_url(&notify.url, settings)?;
+            }
+            let cmdline_ref = cmdline.as_ref().unwrap_or(&job.cmdline);
+            let appkey = cmdline_ref.split_ascii_whitespace().next().unwrap_or("");
+            let queue = settings
+                .read()
+                .unwrap()
+                .queues
+                .resolve(Some(queue.as_deref().unwrap_or(&job.queue)), appkey)
+                .map_err(RequestError::Invalid)?;
+            check_fits_host(job.needs(), settings)?;
+
+            let mut q = q_mutex.lock().unwrap();
+            match q.resubmit(id, cmdline, Some(queue)) {
+                Ok(new_id) => {
+                    submitted(q, new_id, cvar, state, settings, outbox);
+                    Ok(Response::SubmitJob(new_id))
+                }
+                Err(FailReason::NoSuchJob) => {
+                    Err(RequestError::NotFound("No such job".to_string()))
+                }
+                Err(FailReason::WrongJobState) => Err(RequestError::Conflict(
+                    "Job has not ended yet and cannot be resubmitted".to_string(),
+                )),
+            }
+        }
+    }
+}
+
+/// Adds a job to its queue after checking its notify URL, queue and needs
+fn submit_job(
+    cmdline: String,
+    mut options: SubmitOptions,
+    (q_mutex, cvar): (&Mutex<JobQueue>, &Condvar),
+    state: &Mutex<State>,
+    settings: &RwLock<Settings>,
+    outbox: &(Mutex<Outbox>, Condvar),
+) -> std::result::Result<Response, RequestError> {
+    if let Some(ref notify) = options.notify {
+        check_notify_url(&notify.url, settings)?;
+    }
+    if let Some(ref mail_to) = options.mail_to {
+        check_mail_to(mail_to, settings)?;
+    }
+    let appkey = cmdline.split_ascii_whitespace().next().unwrap_or("");
+    let queue = settings
+        .read()
+        .unwrap()
+        .queues
+        .resolve(options.queue.as_deref(), appkey)
+        .map_err(RequestError::Invalid)?;
+    options.queue = Some(queue);
+    check_resources(appkey, &mut options, settings)?;
+
+    let mut q = q_mutex.lock().unwrap();
+    let id = q.submit(cmdline, options);
+    submitted(q, id, cvar, state, settings, outbox);
+    Ok(Response::SubmitJob(id))
+}
+
+/// Checks the parts of an imported job that a client could not have given
+/// when submitting it
+fn check_imported(job: &Job, settings: &RwLock<Settings>) -> std::result::Result<(), RequestError> {
+    if let Some(ref notify) = job.notify {
+        check_notify_url(&notify.url, settings)?;
+    }
+    if let Some(ref mail_to) = job.mail_to {
+        check_mail_to(mail_to, settings)?;
+    }
+    // jobs that end up in the queue must be able to run
+    if matches!(
+        job.state,
+        JobState::Queued | JobState::Held | JobState::Running
+    ) {
+        settings
+            .read()
+            .unwrap()
+            .queues
+            .resolve(Some(&job.queue), job.appkey())
+            .map_err(RequestError::Invalid)?;
+        check_fits_host(job.needs(), settings)?;
+    }
+    Ok(())
+}
+
+/// Returns the name of the given queue, or of the default queue. Fails if
+/// there is no such queue.
+fn known_queue(
+    queue: Option<String>,
+    settings: &RwLock<Settings>,
+) -> std::result::Result<String, RequestError> {
+    let queue = queue.unwrap_or_else(|| DEFAULT_QUEUE.to_string());
+    if settings.read().unwrap().queues.contains(&queue) {
+        Ok(queue)
+    } else {
+        Err(RequestError::NotFound(format!("Unknown queue '{}'", queue)))
+    }
+}
+
+/// Fills in the resources that jobs of the appkey need by default, unless
+/// the job declares them, and checks that the job can run on the host at all
+fn check_resources(
+    appkey: &str,
+    options: &mut SubmitOptions,
+    settings: &RwLock<Settings>,
+) -> std::result::Result<(), RequestError> {
+    let (cpus, mem) = settings.read().unwrap().appkey_limits.default_needs(appkey);
+    options.cpus = options.cpus.or(cpus);
+    options.mem = options.mem.or(mem);
+    if options.cpus == Some(0) {
+        return Err(RequestError::Invalid(
+            "A job needs at least one CPU".to_string(),
+        ));
+    }
+    check_fits_host(Resources::declared(options.cpus, options.mem), settings)
+}
+
+/// Checks that a job with the given needs fits into the host's resources
+/// when nothing else is running
+fn check_fits_host(
+    needs: Resources,
+    settings: &RwLock<Settings>,
+) -> std::result::Result<(), RequestError> {
+    match settings.read().unwrap().resources {
+        Some(total) if !total.holds(&needs) => Err(RequestError::Invalid(format!(
+            "The job needs {} CPUs, {} memory, but the host has only {} CPUs, {} memory",
+            needs.cpus,
+            format_mem(needs.mem),
+            total.cpus,
+            format_mem(total.mem)
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Checks whether reports may be sent to the given notify URL
+fn check_notify_url(
+    url: &str,
+    settings: &RwLock<Settings>,
+) -> std::result::Result<(), RequestError> {
+    match Url::parse(url) {
+        Ok(ref parsed) if settings.read().unwrap().notify.allows(parsed) => Ok(()),
+        Ok(_) => Err(RequestError::Invalid(format!(
+            "Notify URL {} is not allowed",
+            url
+        ))),
+        Err(e) => Err(RequestError::Invalid(format!(
+            "Invalid notify URL {}: {}",
+            url, e
+        ))),
+    }
+}
+
+/// Checks that mails may be sent to an address given by the client
+fn check_mail_to(
+    address: &str,
+    settings: &RwLock<Settings>,
+) -> std::result::Result<(), RequestError> {
+    match settings.read().unwrap().mail {
+        Some(ref mailer) if !mailer.allows(address) => Err(RequestError::Invalid(format!(
+            "Mails to {} are not allowed",
+            address
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Saves the queue after a job has been submitted, wakes up the queue runner
+/// and reports the new job. The queue is released before the settings are
+/// read.
+fn submitted(
+    mut q: MutexGuard<JobQueue>,
+    id: u64,
+    cvar: &Condvar,
+    state: &Mutex<State>,
+    settings: &RwLock<Settings>,
+    outbox: &(Mutex<Outbox>, Condvar),
+) {
+    state
+        .lock()
+        .unwrap()
+        .save(&mut q)
+        .expect("Could not write program state");
+    cvar.notify_all();
+    let job = q.iter_queued().find(|j| j.id == id).cloned();
+    drop(q);
+
+    if let Some(job) = job {
+        let settings = settings.read().unwrap();
+        notifier::enqueue(outbox, &settings, &job, JobEvent::Queued);
+    }
+}
+
+/// Handles a request of the REST API, see `rest.rs`
+fn process_rest_request(
+    method: &tiny_http::Method,
+    url: &str,
+    body: &str,
+    q_mutex: &(Mutex<JobQueue>, Condvar),
+    state: &Mutex<State>,
+    settings: &RwLock<Settings>,
+    outbox: &(Mutex<Outbox>, Condvar),
+) -> Reply {
+    let request = match rest::route(method, url, body) {
+        Ok(request) => request,
+        Err(reply) => return reply,
+    };
+
+    debug!("[handle_client] Processing request: {:?}", request);
+
+    match process_request(request, q_mutex, state, settings, outbox) {
+        Ok(response) => Reply::from_response(response),
+        Err(e) => Reply::error(e.status(), e.message()),
+    }
+}
+
+/// Handles a single HTTP request sent by a single client.
+/// Requests POSTed to `/` are JSON-encoded `Request`s that are answered
+/// with a JSON-encoded `Response`. All other requests go to the REST API.
+fn handle_client(
+    mut httprequest: tiny_http::Request,
+    q_mutex: Arc<(Mutex<JobQueue>, Condvar)>,
+    dump_protocol: bool,
+    state: Arc<Mutex<State>>,
+    settings: &RwLock<Settings>,
+    outbox: &(Mutex<Outbox>, Condvar),
+) {
+    let mut s = String::from("");
+    httprequest.as_reader().read_to_string(&mut s).unwrap();
+
+    if dump_protocol {
+        debug!("[handle_client] Got data: {}", &s);
+    }
+
+    let reply = if *httprequest.method() == tiny_http::Method::Post && httprequest.url() == "/" {
+        let request = serde_json::from_str(&s);
+
+        debug!("[handle_client] Processing request: {:?}", request);
+
+        let (status_code, response_s) = match request {
+            Ok(request) => match process_request(request, &q_mutex, &state, settings, outbox) {
+                Ok(response) => (200, serde_json::to_string_pretty(&response).unwrap()),
+                Err(e) => (
+                    e.protocol_status(),
+                    serde_json::to_string_pretty(&Response::Error(e.message().to_string()))
+                        .unwrap(),
+                ),
+            },
+            Err(e) => {
+                if e.is_io() {
+                    (500, e.to_string())
+                } else {
+                    (400, e.to_string())
+                }
             }
+        };
+
+        let content_type = if status_code == 200 {
+            &b"application/json"[..]
+        } else {
+            &b"text/plain"[..]
+        };
+        Reply {
+            status: status_code,
+            body: response_s,
+            headers: vec![
+                tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type).unwrap(),
+            ],
         }
+    } else {
+        let method = httprequest.method().clone();
+        let url = httprequest.url().to_string();
+        process_rest_request(&method, &url, &s, &q_mutex, &state, settings, outbox)
     };
 
     if dump_protocol {
         debug!(
             "[handle_client] Returning {} response: {}",
-            status_code, &response_s
+            reply.status, &reply.body
         );
-    } else if status_code != 200 {
+    } else if reply.status >= 400 {
         info!(
             "[handle_client] Sending errorneous status code {} with response: {}",
-            status_code, &response_s
+            reply.status, &reply.body
         );
     }
 
-    let mut response = tiny_http::Response::from_string(response_s).with_status_code(status_code);
-
-    if status_code == 200 {
-        response.add_header(
-            tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
-        );
-    } else {
-        response.add_header(
-            tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"text/plain"[..]).unwrap(),
-        );
+    let mut response = tiny_http::Response::from_string(reply.body).with_status_code(reply.status);
+    for header in reply.headers {
+        response.add_header(header);
     }
 
     if let Err(err) = httprequest.respond(response) {
@@ -249,39 +768,102 @@ fn handle_client(
     }
 }
 
-/// Calls the notification URL for the given job
-fn run_notify_command(job: Job, url: &Url) -> Result<()> {
-    let mut url = url.clone();
-    url.set_query(Some(&format!("jobid={}", job.id)));
-    let s = url.as_str().to_string();
-
-    match reqwest::get(url) {
+/// Re-reads the config file and swaps the settings that can be changed at
+/// runtime. Returns the changed settings that require a restart, or an error
+/// if the config file is invalid, in which case nothing is changed.
+fn reload_config(
+    settings: &RwLock<Settings>,
+    (q_mutex, cvar): (&Mutex<JobQueue>, &Condvar),
+) -> std::result::Result<Vec<String>, String> {
+    let config_file = settings.read().unwrap().config_file.clone();
+    let new = settings::read_opt(&config_file).and_then(|opt| Settings::from_opt(&opt));
+    let new = match new {
+        Ok(new) => new,
         Err(e) => {
-            error!("Failed to call notify url {:#?}: {}", s, e.to_string());
-            Ok(())
-        }
-        Ok(ref r) if r.status().is_success() => {
-            debug!(
-                "Notification call to {:#?} succeeded. Response: {}",
-                s,
-                r.status().as_str()
-            );
-            Ok(())
-        }
-        Ok(r) => {
-            debug!(
-                "Notification call to {:#?} failed. Response: {}",
-                s,
-                r.status().as_str()
-            );
-            Ok(())
+            error!("Not reloading invalid configuration: {}", e);
+            return Err(e);
         }
+    };
+    new.check_appkeys();
+
+    log::set_max_level(new.loglevel);
+    let restart_required = settings.write().unwrap().update(new);
+    info!("Configuration reloaded");
+
+    // raised appkey limits may allow waiting jobs to start
+    {
+        let _q = q_mutex.lock().unwrap();
+        cvar.notify_all();
+    }
+    if !restart_required.is_empty() {
+        warn!(
+            "Changes to these settings require a restart: {}",
+            restart_required.join(", ")
+        );
     }
+    Ok(restart_required)
 }
 
-/// Starts working the job queue.
+/// Turns the command line of a job into a shell command line, replacing
+/// the appkey with the executable it refers to
+fn shell_cmdline(job: &Job, appkeys: &HashMap<String, PathBuf>) -> String {
+    /*
+    We need to prepend 'exec' to the command line. Otherwise, the command
+    spawner would yield the PID of 'sh'. exec replaces the shell with
+    the acutal process that we would like to run, keeping the pid.
+    */
+    let appkey = job.appkey();
+    let args: Vec<&str> = job.cmdline.split_ascii_whitespace().skip(1).collect();
+    let cmdline_remainder = args.join(" ");
+    let mut actual_cmd = appkeys.get(appkey).cloned();
+    if appkey.is_empty() || actual_cmd.is_none() {
+        error!("Invalid appkey");
+        actual_cmd = Some(PathBuf::from("invalid-appkey"));
+    }
+    let actual_cmd = actual_cmd.unwrap();
+    format!(
+        "exec {} {}",
+        actual_cmd.to_str().unwrap(),
+        cmdline_remainder
+    )
+}
+
+/// Runs `wait` while publishing the output of the given running job on the
+/// event bus
+fn with_output_events<T, F: FnOnce() -> T>(
+    bus: &Arc<EventBus>,
+    job: &Job,
+    files: &JobFiles,
+    wait: F,
+) -> T {
+    let done = Arc::new(AtomicBool::new(false));
+    let follower = {
+        let bus = Arc::clone(bus);
+        let done = Arc::clone(&done);
+        let files = files.clone();
+        let (id, owner) = (job.id, job.owner.clone());
+        thread::Builder::new()
+            .name("Output Follower".to_owned())
+            .spawn(move || {
+                job_runner::follow_output(&files, &done, |stream, data| {
+                    bus.publish(BusEvent::output(id, owner.clone(), stream, data))
+                })
+            })
+            .unwrap()
+    };
+
+    let result = wait();
+    done.store(true, Ordering::SeqCst);
+    follower.join().unwrap();
+    result
+}
+
+/// Starts working the job queues. One runner is started per slot, each
+/// running one job at a time.
 ///
-/// First, it checks whether a job is available in the queue.
+/// If the runner is given a job that was still running when the daemon was
+/// stopped, it first waits for it to terminate (see `job_runner`). Then, it
+/// checks whether a job is available in a queue with a free slot.
 /// Then,
 /// 1. no job is available. The thread goes to sleep and waits for a signal
 ///    on the condition variable within the `q_mutex` tuple.
@@ -289,109 +871,116 @@ fn run_notify_command(job: Job, url: &Url) -> Result<()> {
 /// 1.1 If the thread is woken up, it checks again for an available job. If
 ///     there is none, it returns to sleep. If there is, proceed to (2).
 ///
-/// 2. Mark the job as `Running` and execute it
+/// 2. Mark the job as `Running` and execute it through the job wrapper
 ///
 /// 3. Collect the return value, stdout and stderr of the job
 ///
-/// 4. Call the notification handler
+/// 4. Mark the job as `Finished` and store the program state
 ///
-/// 5. Mark the job as `Finished` and return to (1).
+/// 5. Hand a report of the job to the notifier, run the hooks and return to (1).
 fn run_queue(
     q_mutex: &Arc<(Mutex<JobQueue>, Condvar)>,
-    notify_url: Option<String>,
-    appkeys: HashMap<String, PathBuf>,
+    settings: Arc<RwLock<Settings>>,
+    state: Arc<Mutex<State>>,
+    outbox: Arc<(Mutex<Outbox>, Condvar)>,
+    bus: Arc<EventBus>,
+    launcher: Launcher,
+    mut detached: Option<Job>,
 ) -> ! {
     let (ref q_mutex, ref cvar) = **q_mutex;
 
-    let notify_url = notify_url.map(|s| Url::parse(&s).unwrap());
-
     // main loop
     loop {
-        let mut job: Option<Job> = None;
+        let (job, outcome) = if let Some(job) = detached.take() {
+            info!("[queue runner] Reattaching to job {}", job.id);
+            let files = launcher.files(job.id);
+            if let Some(pid) = job.pid {
+                with_output_events(&bus, &job, &files, || {
+                    job_runner::wait_detached(pid, &files)
+                });
+            }
+            (job, Ok(job_runner::collect(&files)))
+        } else {
+            let mut job: Option<Job> = None;
 
-        // acquire a new job to run
-        while job.is_none() {
-            let mut q = q_mutex.lock().unwrap();
+            // acquire a new job to run
+            while job.is_none() {
+                let limits = settings.read().unwrap().appkey_limits.clone();
+                let mut q = q_mutex.lock().unwrap();
 
-            if let Some(j) = q.schedule() {
-                job = Some(j);
-                break;
-            } else {
-                debug!("[queue runner] Falling asleep");
-                q = cvar.wait(q).unwrap();
-                debug!("[queue runner] Woke up");
-                job = q.schedule();
+                job = q.schedule(&limits);
+                if job.is_none() {
+                    debug!("[queue runner] Falling asleep");
+                    q = cvar.wait(q).unwrap();
+                    debug!("[queue runner] Woke up");
+                    job = q.schedule(&limits);
+                }
             }
-        }
 
-        let job = job.unwrap();
-        info!("[queue runner] Running job {}", job.id);
-
-        /*
-        We need to prepend 'exec' to the command line. Otherwise, the command
-        spawner would yield the PID of 'sh'. exec replaces the shell with
-        the acutal process that we would like to run, keeping the pid.
-        */
-        let mut cmditer = job.cmdline.split_ascii_whitespace();
-        let appkey = cmditer.next().unwrap_or("");
-        let args: Vec<&str> = cmditer.collect();
-        let cmdline_remainder = args.join(" ");
-        let mut actual_cmd = appkeys.get(appkey).cloned();
-        if appkey.is_empty() || actual_cmd.is_none() {
-            error!("Invalid appkey");
-            actual_cmd = Some(PathBuf::from("invalid-appkey"));
-        }
-        let actual_cmd = actual_cmd.unwrap();
-        let cmdline_wrapper = format!(
-            "exec {} {}",
-            actual_cmd.to_str().unwrap(),
-            cmdline_remainder
-        );
+            let job = job.unwrap();
+            info!("[queue runner] Running job {}", job.id);
 
-        // Spawn the process, collect stdout, stderr and pid.
-        // Continues once the job is terminated (one way or another).
-        let cmd = Command::new("sh")
-            .arg("-c")
-            .arg(cmdline_wrapper)
-            .current_dir("/")
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .and_then(|child| {
-                {
-                    let mut q = q_mutex.lock().unwrap();
-                    q.assign_pid(job.id, child.id());
-                }
-                child.wait_with_output()
-            });
+            // Spawn the process and persist its pid right away, so that it can
+            // be found again after a restart. Continues once the job is
+            // terminated (one way or another).
+            let files = launcher.files(job.id);
+            let cmdline = shell_cmdline(&job, &settings.read().unwrap().appkeys);
+            let outcome = launcher
+                .launch(&files, &cmdline)
+                .and_then(|mut child| {
+                    {
+                        let mut q = q_mutex.lock().unwrap();
+                        q.assign_pid(job.id, child.id());
+                        if let Err(e) = state.lock().unwrap().save(&mut q) {
+                            error!("[queue runner] Could not write program state: {}", e);
+                        }
+                    }
+                    {
+                        let settings = settings.read().unwrap();
+                        notifier::enqueue(&outbox, &settings, &job, JobEvent::Started);
+                        settings.hooks.run(&job, JobEvent::Started);
+                    }
+                    with_output_events(&bus, &job, &files, || child.wait())
+                })
+                .map(|_| job_runner::collect(&files));
+            (job, outcome)
+        };
 
         // Collect status of finished job and forward status to the queue
-        let job = match cmd {
+        let job = match outcome {
             // Job was successfully launched. This does not mean that the
             // process itself was successful.
-            Ok(output) => {
+            Ok((new_state, stdout, stderr)) => {
                 let mut q = q_mutex.lock().unwrap();
-
-                // Job was terminated due to a signal, e.g. unhandled SIGTERM,
-                // SIGSEGV, etc. see signal(7) for default signal actions.
-                if let Some(signum) = output.status.signal() {
-                    info!("[queue runner] Job was killed with signal {}", signum);
-                    q.finish(
-                        JobState::Killed(signum),
-                        String::from_utf8_lossy(&output.stdout).to_string(),
-                        String::from_utf8_lossy(&output.stderr).to_string(),
-                    )
-                } else {
+                if q.requeue_on_exit() {
+                    // The daemon is shutting down and has terminated the job
+                    // on purpose, so that it is started again next time.
+                    info!("[queue runner] Job {} has been requeued", job.id);
+                    q.requeue(job.id);
+                    let mut state = state.lock().unwrap();
+                    if let Err(e) = state.save(&mut q) {
+                        error!("[queue runner] Could not write program state: {}", e);
+                    }
+                    continue;
+                }
+                match new_state {
+                    // Job was terminated due to a signal, e.g. unhandled SIGTERM,
+                    // SIGSEGV, etc. see signal(7) for default signal actions.
+                    JobState::Killed(signum) => {
+                        info!(
+                            "[queue runner] Job {} was killed with signal {}",
+                            job.id, signum
+                        )
+                    }
                     // Job has terminated by itself and a regular exit code
                     // was returned.
-                    let status = output.status.code().unwrap();
-                    info!("[queue runner] Job has terminated with code {}", status);
-                    q.finish(
-                        JobState::Terminated(status),
-                        String::from_utf8_lossy(&output.stdout).to_string(),
-                        String::from_utf8_lossy(&output.stderr).to_string(),
-                    )
+                    JobState::Terminated(status) => info!(
+                        "[queue runner] Job {} has terminated with code {}",
+                        job.id, status
+                    ),
+                    ref s => error!("[queue runner] Job {} failed: {:?}", job.id, s),
                 }
+                q.finish(job.id, new_state, stdout, stderr)
             }
             // Job could not be started.
             Err(e) => {
@@ -399,6 +988,7 @@ fn run_queue(
                 let message = e.to_string();
                 error!("[queue runner] Failed to launch job: {}", message);
                 q.finish(
+                    job.id,
                     JobState::Failed(message),
                     String::from(""),
                     String::from(""),
@@ -406,22 +996,82 @@ fn run_queue(
             }
         };
 
+        // Persist the result right away instead of waiting for the next client request
+        {
+            let mut q = q_mutex.lock().unwrap();
+            let mut state = state.lock().unwrap();
+            if let Err(e) = state.save(&mut q) {
+                error!("[queue runner] Could not write program state: {}", e);
+            }
+        }
+        // the other runners may be waiting for this job's slot or appkey
+        cvar.notify_all();
+
         // Notify the server of job completion regardless of the result
         if let Some(j) = job {
-            if notify_url.is_some() {
-                let id = j.id;
-                if let Err(e) = run_notify_command(j, notify_url.as_ref().unwrap()) {
-                    error!(
-                        "Failed to run notify command for job {}: {}",
-                        id,
-                        e.to_string()
-                    );
+            if let Some(event) = JobEvent::for_state(&j.state) {
+                let settings = settings.read().unwrap();
+                notifier::enqueue(&outbox, &settings, &j, event);
+                settings.hooks.run(&j, event);
+                if let Some(ref mailer) = settings.mail {
+                    mailer.notify(&j, event);
                 }
             }
         }
     }
 }
 
+/// Appends the finished jobs chosen by `select` to the archive, if given,
+/// and removes them. The archive is written while the queue is released, so
+/// that compressing many jobs does not hold up other requests. If that fails,
+/// the jobs are left in the queue. Returns the removed jobs.
+fn prune_finished<F: FnOnce(&JobQueue) -> Vec<Job>>(
+    q_mutex: &Mutex<JobQueue>,
+    state: &Mutex<State>,
+    archive: Option<&Path>,
+    select: F,
+) -> Result<Vec<Job>> {
+    let jobs = select(&q_mutex.lock().unwrap());
+    if jobs.is_empty() {
+        return Ok(jobs);
+    }
+
+    if let Some(archive) = archive {
+        retention::archive_jobs(archive, &jobs)?;
+    }
+
+    let mut q = q_mutex.lock().unwrap();
+    retention::take_jobs(&mut q, &jobs);
+    state.lock().unwrap().save(&mut q)?;
+    Ok(jobs)
+}
+
+/// Enforces the retention rules for finished jobs, first at startup and then
+/// periodically. Removed jobs are archived if configured. The rules are
+/// looked up on each run, as they may be changed by reloading the config.
+fn run_retention(
+    q_mutex: &Arc<(Mutex<JobQueue>, Condvar)>,
+    state: Arc<Mutex<State>>,
+    settings: Arc<RwLock<Settings>>,
+) -> ! {
+    let (ref q_mutex, _) = **q_mutex;
+
+    loop {
+        let retention = settings.read().unwrap().retention.clone();
+        if retention.is_enabled() {
+            let select = |q: &JobQueue| retention.expired_jobs(q, SystemTime::now());
+            match prune_finished(q_mutex, &state, retention.archive.as_deref(), select) {
+                Ok(ref jobs) if jobs.is_empty() => {}
+                Ok(jobs) => info!("[retention] Removed {} finished jobs", jobs.len()),
+                Err(e) => error!("[retention] Could not archive finished jobs: {}", e),
+            }
+        }
+
+        thread::sleep(retention.interval);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn handle(
     tcp_port: u16,
     pidfile: Option<PathBuf>,
@@ -429,10 +1079,11 @@ pub fn handle(
     key: Option<Vec<u8>>,
     foreground: bool,
     dump_protocol: bool,
-    appkeys: HashMap<String, PathBuf>,
-    notify_url: Option<String>,
+    settings: Settings,
+    spool_dir: PathBuf,
     state: State,
 ) -> Result<()> {
+    let launcher = Launcher::new(spool_dir.clone())?;
     if !foreground {
         daemonize(pidfile)?;
     }
@@ -442,89 +1093,213 @@ pub fn handle(
         Err(e) => {
             error!(
                 "Could not set up listening socket on port {}: {}",
-                tcp_port,
-                e.to_string()
+                tcp_port, e
             );
             panic!(
                 "Could not set up listening socket on port {}: {}",
-                tcp_port,
-                e.to_string()
+                tcp_port, e
             )
         }
     };
 
     daemon::notify(false, [(daemon::STATE_READY, "1")].iter())?;
     info!("Daemon version {} ready.", crate_version!());
-    info!("Application keys available: {:?}", appkeys.keys());
+    info!("Application keys available: {:?}", settings.appkeys.keys());
 
-    let job_queue = Arc::new((Mutex::new(state.load_queue()), Condvar::new()));
+    // all changes to the queue are published on the event bus
+    let bus = Arc::new(EventBus::new());
+    let mut q = state.load_queue();
+    q.set_event_bus(Arc::clone(&bus));
+    q.set_slots(settings.queues.slots());
+    q.set_resources(settings.resources);
+    let job_queue = Arc::new((Mutex::new(q), Condvar::new()));
 
-    // Reset first job to a defined state if the daemon has been interrupted
-    {
+    std::fs::create_dir_all(&spool_dir)?;
+
+    // Reset running jobs to a defined state if the daemon has been interrupted,
+    // unless the job is still running or has left an exit status. The queue
+    // runners will reattach to those, and to the ones of stopped queues.
+    let detached: Vec<Job> = {
         let (ref q_mutex, _) = *job_queue;
         let mut q = q_mutex.lock().unwrap();
-        match q.get_state() {
-            QueueState::Stopped => {}
-            QueueState::Stopping => q.reset_first_job(JobState::Queued),
-            QueueState::Running => q.reset_first_job(JobState::Failed(
-                "Interrupted by system failure, please re-submit or ask for assistence".to_owned(),
-            )),
+        let running: Vec<Job> = q.all_running_jobs().cloned().collect();
+        for j in running {
+            let files = JobFiles::new(&spool_dir, j.id);
+            if job_runner::is_detached(j.pid, &files) {
+                info!(
+                    "Job {} of the previous daemon is still running or has terminated, reattaching",
+                    j.id
+                );
+            } else {
+                match q.get_state(&j.queue) {
+                    QueueState::Stopped => {}
+                    QueueState::Stopping => q.reset_job(j.id, JobState::Queued),
+                    QueueState::Running => q.reset_job(
+                        j.id,
+                        JobState::Failed(
+                            "Interrupted by system failure, please re-submit or ask for assistence"
+                                .to_owned(),
+                        ),
+                    ),
+                }
+            }
         }
-    }
 
-    // spawn queue runner
-    let queue_runner_q = job_queue.clone();
-    let queue_runner = thread::Builder::new()
-        .name("Queue Runner".to_owned())
-        .spawn(move || run_queue(&queue_runner_q, notify_url, appkeys))
-        .unwrap();
+        let unknown: BTreeSet<&String> = q
+            .iter_queued()
+            .map(|j| &j.queue)
+            .filter(|name| !settings.queues.contains(name))
+            .collect();
+        for queue in unknown {
+            warn!(
+                "Jobs of queue '{}' are not started, it is not configured",
+                queue
+            );
+        }
+
+        let running = q.all_running_jobs().cloned().collect();
+        running
+    };
 
     // set up the program state to be shared among threads,
-    // namely the signal handler (ought to save state on SIGTERM)
-    // and the current thread, handling client requests
+    // namely the queue runner, the signal handler (ought to save state
+    // on SIGTERM) and the current thread, handling client requests
     let state = Arc::new(Mutex::new(state));
 
+    // settings that may be changed at runtime by reloading the config file
+    let settings = Arc::new(RwLock::new(settings));
+
+    // spawn notifier that delivers job reports to the notify URL
+    let outbox = Arc::new((
+        Mutex::new(Outbox::open(notifier::outbox_path(&spool_dir))),
+        Condvar::new(),
+    ));
+    let notifier_outbox = Arc::clone(&outbox);
+    let notifier_settings = Arc::clone(&settings);
+    thread::Builder::new()
+        .name("Notifier".to_owned())
+        .spawn(move || notifier::run_notifier(&notifier_outbox, notifier_settings))
+        .unwrap();
+
+    // spawn a queue runner per slot, and one per detached job beyond that.
+    // Every job needs a CPU, so no more jobs than CPUs run at once.
+    let slots: usize = {
+        let settings = settings.read().unwrap();
+        let slots = settings.queues.slots().values().sum();
+        settings
+            .resources
+            .map_or(slots, |r| slots.min(r.cpus as usize))
+    };
+    let mut detached = detached.into_iter();
+    let mut queue_runners = Vec::new();
+    for _ in 0..slots.max(detached.len()) {
+        let queue_runner_q = job_queue.clone();
+        let queue_runner_state = Arc::clone(&state);
+        let queue_runner_settings = Arc::clone(&settings);
+        let queue_runner_outbox = Arc::clone(&outbox);
+        let queue_runner_bus = Arc::clone(&bus);
+        let queue_runner_launcher = launcher.clone();
+        let queue_runner_detached = detached.next();
+        let queue_runner = thread::Builder::new()
+            .name("Queue Runner".to_owned())
+            .spawn(move || {
+                run_queue(
+                    &queue_runner_q,
+                    queue_runner_settings,
+                    queue_runner_state,
+                    queue_runner_outbox,
+                    queue_runner_bus,
+                    queue_runner_launcher,
+                    queue_runner_detached,
+                )
+            })
+            .unwrap();
+        queue_runners.push(queue_runner);
+    }
+
+    // spawn retention thread that removes old finished jobs
+    let retention_q = Arc::clone(&job_queue);
+    let retention_state = Arc::clone(&state);
+    let retention_settings = Arc::clone(&settings);
+    thread::Builder::new()
+        .name("Retention".to_owned())
+        .spawn(move || run_retention(&retention_q, retention_state, retention_settings))
+        .unwrap();
+
     // spawn signal handler to collect SIGTERM signals sent by systemd unit
     // create clones before spawning, otherwise the "originals" would be moved into the closure
     let sig_q = Arc::clone(&job_queue);
     let sig_state = Arc::clone(&state);
-    let signal_handler = setup_signal_handler(sig_q, sig_state);
+    let sig_settings = Arc::clone(&settings);
+    let signal_handler = setup_signal_handler(sig_q, sig_state, sig_settings);
 
     // handle incoming TCP connections
     for request in httpd.incoming_requests() {
         debug!("Request: {:?}", request);
 
-        handle_client(request, job_queue.clone(), dump_protocol, state.clone());
+        // event streams are served on threads of their own
+        let path = request.url().split('?').next().unwrap_or("");
+        if *request.method() == tiny_http::Method::Get && path == "/events" {
+            events::serve_events(request, &bus);
+            continue;
+        }
+
+        handle_client(
+            request,
+            job_queue.clone(),
+            dump_protocol,
+            state.clone(),
+            &settings,
+            &outbox,
+        );
     }
 
     // collect threads in case of program termination
-    queue_runner.join().unwrap();
+    for queue_runner in queue_runners {
+        queue_runner.join().unwrap();
+    }
     signal_handler.join().unwrap();
     Ok(())
 }
 
-// Creates a thread waiting for SIGTERM. Once encountered, state save is triggered and
-// the main process terminates.
+// Creates a thread waiting for SIGTERM and SIGHUP. The first SIGTERM starts a
+// graceful shutdown in a separate thread (see `shutdown`), a second one saves
+// the program state and terminates the process right away. SIGHUP reloads the
+// config file.
 fn setup_signal_handler(
     job_queue: Arc<(Mutex<JobQueue>, Condvar)>,
     state: Arc<Mutex<State>>,
+    settings: Arc<RwLock<Settings>>,
 ) -> std::thread::JoinHandle<()> {
-    let signals = signal_hook::iterator::Signals::new(&[signal_hook::SIGTERM]).unwrap();
+    let signals =
+        signal_hook::iterator::Signals::new([signal_hook::SIGTERM, signal_hook::SIGHUP]).unwrap();
 
     thread::Builder::new()
         .name("Signal Handler".to_owned())
         .spawn(move || {
+            let mut terminations = Terminations::default();
             for signal in signals.forever() {
-                match signal {
-                    signal_hook::SIGTERM => {
-                        info!("Caught SIGTERM, initiating state saving");
-                        let state = state.lock().unwrap();
-                        let q = job_queue.0.lock().unwrap();
-                        state.save(&q).expect("Could not write program state");
-                        std::process::exit(0);
-                    }
-                    _ => unreachable!(),
+                if signal == signal_hook::SIGHUP {
+                    info!("Caught SIGHUP, reloading configuration");
+                    // errors have been logged already
+                    let (ref q_mutex, ref cvar) = *job_queue;
+                    let _ = reload_config(&settings, (q_mutex, cvar));
+                    continue;
+                }
+
+                if terminations.next() == Termination::Immediate {
+                    info!("Caught second SIGTERM, exiting immediately");
+                    shutdown::exit(&job_queue, &state);
                 }
+
+                let policy = settings.read().unwrap().shutdown.clone();
+                info!("Caught SIGTERM, shutting down ({:?} mode)", policy.mode);
+                let job_queue = Arc::clone(&job_queue);
+                let state = Arc::clone(&state);
+                thread::Builder::new()
+                    .name("Shutdown".to_owned())
+                    .spawn(move || shutdown::shutdown(job_queue, state, policy))
+                    .unwrap();
             }
         })
         .unwrap()
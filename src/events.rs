@@ -0,0 +1,248 @@
+/// events.rs
+///
+/// Event bus and Server-Sent Events stream.
+///
+/// Every change to the job queue is published on the event bus (see
+/// `JobQueue::set_event_bus`), as well as output of running jobs. Clients
+/// subscribe with `GET /events`, optionally filtered by job ID or owner
+/// (`/events?job=42`, `/events?owner=alice`), and receive the events as
+/// Server-Sent Events:
+///
+/// ```text
+/// event: finished
+/// data: {"id":42,"cmdline":"gwas ...",...}
+/// ```
+///
+/// Each subscriber is served by a thread of its own. Subscribers that have
+/// disconnected are dropped when the next event is published, and so are
+/// subscribers that read too slowly to keep up with the events.
+use std::fmt;
+use std::io::prelude::*;
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use reqwest::Url;
+use serde_json::{json, Value};
+
+use job_queue::QueueEvent;
+
+/// Time after which a comment is sent to idle subscribers, so that
+/// disconnected clients are noticed
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Number of events buffered for a subscriber before it is dropped
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+/// A single event on the bus
+#[derive(Debug, Clone)]
+pub struct BusEvent {
+    /// Event name: submitted, imported, started, output, finished, requeued,
+    /// removed, held, released, updated, moved or queue_state
+    pub kind: &'static str,
+
+    /// The job the event refers to, None for queue state changes
+    pub job_id: Option<u64>,
+
+    /// Owner of the job, if known
+    pub owner: Option<String>,
+
+    /// Payload sent to subscribers
+    pub data: Value,
+}
+
+impl BusEvent {
+    /// Translates a change of the job queue. Returns None for internal
+    /// changes that are of no interest to subscribers.
+    pub fn from_queue_event(event: &QueueEvent, owner: Option<String>) -> Option<BusEvent> {
+        let (kind, job_id, data) = match event {
+            QueueEvent::Submitted(job) => ("submitted", Some(job.id), json!(job)),
+            QueueEvent::Imported(job) => ("imported", Some(job.id), json!(job)),
+            QueueEvent::Started(id, at) => ("started", Some(*id), json!({"id": id, "started": at})),
+            QueueEvent::PidAssigned(..) => return None,
+            QueueEvent::Finished(job) => ("finished", Some(job.id), json!(job)),
+            QueueEvent::Requeued(id) => ("requeued", Some(*id), json!({ "id": id })),
+            QueueEvent::Removed(id) => ("removed", Some(*id), json!({ "id": id })),
+            QueueEvent::Held(id) => ("held", Some(*id), json!({ "id": id })),
+            QueueEvent::Released(id) => ("released", Some(*id), json!({ "id": id })),
+            QueueEvent::Updated(job) => ("updated", Some(job.id), json!(job)),
+            QueueEvent::Moved(id, _) => ("moved", Some(*id), json!({ "id": id })),
+            QueueEvent::QueueStateChanged(queue, state) => (
+                "queue_state",
+                None,
+                json!({ "queue": queue, "state": state }),
+            ),
+        };
+        Some(BusEvent {
+            kind,
+            job_id,
+            owner,
+            data,
+        })
+    }
+
+    /// Creates an event for new output of a running job
+    pub fn output(id: u64, owner: Option<String>, stream: &str, data: String) -> BusEvent {
+        BusEvent {
+            kind: "output",
+            job_id: Some(id),
+            owner,
+            data: json!({"id": id, "stream": stream, "data": data}),
+        }
+    }
+
+    /// Formats the event as a Server-Sent Events message
+    fn to_sse(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.kind, self.data)
+    }
+}
+
+/// Selects the events a subscriber receives. Queue state changes are sent
+/// to all subscribers.
+#[derive(Debug, Default, Clone)]
+pub struct EventFilter {
+    pub job: Option<u64>,
+    pub owner: Option<String>,
+}
+
+impl EventFilter {
+    /// Reads the filter from the query of a request URL, i.e.
+    /// `/events?job=42&owner=alice`
+    pub fn from_url(url: &str) -> Result<EventFilter, String> {
+        let url = Url::parse("http://localhost")
+            .and_then(|base| base.join(url))
+            .map_err(|e| e.to_string())?;
+
+        let mut filter = EventFilter::default();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                "job" => {
+                    filter.job = Some(
+                        value
+                            .parse()
+                            .map_err(|_| format!("Invalid job ID '{}'", value))?,
+                    )
+                }
+                "owner" => filter.owner = Some(value.into_owned()),
+                _ => return Err(format!("Unknown filter '{}'", key)),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, event: &BusEvent) -> bool {
+        if event.job_id.is_none() {
+            return true;
+        }
+        self.job.is_none_or(|id| event.job_id == Some(id))
+            && self
+                .owner
+                .as_ref()
+                .is_none_or(|owner| event.owner.as_ref() == Some(owner))
+    }
+}
+
+/// Distributes events to all subscribers
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<SyncSender<Arc<BusEvent>>>>,
+}
+
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "EventBus {{ {} subscribers }}",
+            self.subscribers.lock().unwrap().len()
+        )
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus::default()
+    }
+
+    /// Sends an event to all subscribers, forgetting those that are gone or
+    /// whose buffer is full
+    pub fn publish(&self, event: BusEvent) {
+        let event = Arc::new(event);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|s| match s.try_send(Arc::clone(&event)) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!("[events] Dropping a subscriber that does not keep up");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    /// Returns a channel that receives all events published from now on
+    pub fn subscribe(&self) -> Receiver<Arc<BusEvent>> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_BUFFER);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+/// Streams events to the client of the given request on a separate thread
+pub fn serve_events(request: tiny_http::Request, bus: &EventBus) {
+    let filter = match EventFilter::from_url(request.url()) {
+        Ok(filter) => filter,
+        Err(e) => {
+            let response = tiny_http::Response::from_string(e).with_status_code(400);
+            if let Err(err) = request.respond(response) {
+                error!("Failed to send response to client: {:?}", err);
+            }
+            return;
+        }
+    };
+
+    let events = bus.subscribe();
+    thread::Builder::new()
+        .name("Event Stream".to_owned())
+        .spawn(move || {
+            debug!("[events] Client subscribed with {:?}", filter);
+            // tiny_http buffers chunked responses, so write the raw response
+            // and end it by closing the connection
+            let mut writer = request.into_writer();
+            let result = stream_events(&mut *writer, &events, &filter);
+            debug!("[events] Client disconnected: {:?}", result);
+        })
+        .unwrap();
+}
+
+/// Writes events to the client until it disconnects
+fn stream_events(
+    writer: &mut dyn Write,
+    events: &Receiver<Arc<BusEvent>>,
+    filter: &EventFilter,
+) -> std::io::Result<()> {
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+          Content-Type: text/event-stream\r\n\
+          Cache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    writer.flush()?;
+
+    loop {
+        match events.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(event) => {
+                if filter.matches(&event) {
+                    writer.write_all(event.to_sse().as_bytes())?;
+                    writer.flush()?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                writer.write_all(b": keepalive\n\n")?;
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
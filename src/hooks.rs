@@ -0,0 +1,212 @@
+/// hooks.rs
+///
+/// Local commands that are run on job lifecycle events.
+///
+/// Hooks are configured in the `[hooks]` section of the config file, and per
+/// appkey in `[hooks.appkey.<appkey>]`, which replaces the global hook for
+/// the same event. Each hook is run through `sh -c` with the job serialized
+/// as JSON on its standard input. Hooks run in the background, each in its own
+/// process group, which is killed as a whole if the hook exceeds the
+/// configured timeout. Their outcome is logged.
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::{Config, Value};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+
+use job_queue::{Job, JobEvent};
+
+/// Default time limit for a hook
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Time between two checks whether a hook has terminated
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Commands for each kind of event
+#[derive(Debug, Clone, Default)]
+pub struct HookSet {
+    /// Run when a job has been started
+    pub on_start: Option<String>,
+
+    /// Run when a job has ended, successfully or not
+    pub on_finish: Option<String>,
+
+    /// Run when a job has ended with an exit code other than 0, could not
+    /// be launched or has been killed
+    pub on_failure: Option<String>,
+}
+
+impl HookSet {
+    /// Reads a hook set from a config table
+    fn from_table(table: HashMap<String, Value>) -> HookSet {
+        let get = |key: &str| {
+            table
+                .get(key)
+                .and_then(|v| v.clone().into_str().ok())
+                .filter(|s| !s.is_empty())
+        };
+        HookSet {
+            on_start: get("on-start"),
+            on_finish: get("on-finish"),
+            on_failure: get("on-failure"),
+        }
+    }
+}
+
+/// All configured hooks
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    pub global: HookSet,
+    pub per_appkey: HashMap<String, HookSet>,
+    pub timeout: Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Hooks {
+            global: HookSet::default(),
+            per_appkey: HashMap::new(),
+            timeout: DEFAULT_HOOK_TIMEOUT,
+        }
+    }
+}
+
+impl Hooks {
+    /// Reads the hooks from the `[hooks]` section of the config file
+    pub fn from_config(conf: &Config) -> Result<Self, String> {
+        let mut hooks = Hooks::default();
+
+        if let Ok(table) = conf.get_table("hooks") {
+            hooks.global = HookSet::from_table(table);
+        }
+        if let Ok(s) = conf.get_str("hooks.timeout") {
+            hooks.timeout =
+                humantime::parse_duration(&s).map_err(|e| format!("hooks.timeout: {}", e))?;
+        }
+        if let Ok(appkeys) = conf.get_table("hooks.appkey") {
+            for (appkey, table) in appkeys {
+                let table = table
+                    .into_table()
+                    .map_err(|e| format!("hooks.appkey.{}: {}", appkey, e))?;
+                hooks.per_appkey.insert(appkey, HookSet::from_table(table));
+            }
+        }
+
+        Ok(hooks)
+    }
+
+    /// Returns the commands to run for the given event of the given job
+    fn commands(&self, job: &Job, event: JobEvent) -> Vec<String> {
+        let appkey = self.per_appkey.get(job.appkey());
+        let pick = |f: fn(&HookSet) -> &Option<String>| {
+            appkey
+                .and_then(|set| f(set).clone())
+                .or_else(|| f(&self.global).clone())
+        };
+
+        let mut commands = Vec::new();
+        match event {
+            JobEvent::Started => commands.extend(pick(|s| &s.on_start)),
+            JobEvent::Queued => {}
+            _ => {
+                commands.extend(pick(|s| &s.on_finish));
+                if event != JobEvent::Finished {
+                    commands.extend(pick(|s| &s.on_failure));
+                }
+            }
+        }
+        commands
+    }
+
+    /// Runs the hooks for the given event of the given job in the background
+    pub fn run(&self, job: &Job, event: JobEvent) {
+        for command in self.commands(job, event) {
+            let input = serde_json::to_vec(job).unwrap();
+            let id = job.id;
+            let timeout = self.timeout;
+            thread::Builder::new()
+                .name("Hook".to_owned())
+                .spawn(move || run_hook(&command, input, id, event, timeout))
+                .unwrap();
+        }
+    }
+}
+
+/// Runs a single hook and logs its outcome
+fn run_hook(command: &str, input: Vec<u8>, id: u64, event: JobEvent, timeout: Duration) {
+    let mut child = match Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir("/")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            error!(
+                "[hooks] Could not run hook '{}' for job {}: {}",
+                command, id, e
+            );
+            return;
+        }
+    };
+
+    // Feed the job on a separate thread, a hook that does not read its
+    // input must not keep us from enforcing the timeout
+    if let Some(mut stdin) = child.stdin.take() {
+        thread::spawn(move || {
+            let _ = stdin.write_all(&input);
+        });
+    }
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => {
+                info!(
+                    "[hooks] Hook '{}' for job {} ({}) succeeded",
+                    command,
+                    id,
+                    event.name()
+                );
+                return;
+            }
+            Ok(Some(status)) => {
+                warn!(
+                    "[hooks] Hook '{}' for job {} ({}) failed: {}",
+                    command,
+                    id,
+                    event.name(),
+                    status
+                );
+                return;
+            }
+            Ok(None) if Instant::now() >= deadline => {
+                // kill the commands started by the shell as well
+                let _ = kill(Pid::from_raw(-(child.id() as i32)), Signal::SIGKILL);
+                let _ = child.wait();
+                warn!(
+                    "[hooks] Hook '{}' for job {} ({}) killed after {}",
+                    command,
+                    id,
+                    event.name(),
+                    humantime::format_duration(timeout)
+                );
+                return;
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                error!("[hooks] Could not wait for hook '{}': {}", command, e);
+                return;
+            }
+        }
+    }
+}
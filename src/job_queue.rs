@@ -1,14 +1,32 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::io::{Error, ErrorKind};
 use std::process::Command;
+use std::str::FromStr;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+use events::{BusEvent, EventBus};
+use limits::AppkeyLimits;
+use resources::{ResourceUsage, Resources};
+
+/// Name of the queue that always exists, see `queues.rs`
+pub const DEFAULT_QUEUE: &str = "default";
+
+fn default_queue() -> String {
+    DEFAULT_QUEUE.to_string()
+}
+
 /// The current state of a single job
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub enum JobState {
     /// queued and waiting for execution
     Queued,
 
-    /// currently running (top of 'queued' queue)
+    /// queued, but not started until it is released
+    Held,
+
+    /// currently running
     Running,
 
     /// the process has exited with the given value
@@ -30,19 +48,72 @@ pub enum FailReason {
     NoSuchJob,
 }
 
-/// The state of the job queue
+/// The outcome of importing jobs into the queue
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ImportReport {
+    /// Imported jobs as pairs of (ID in the import, ID assigned in this queue)
+    pub imported: Vec<(u64, u64)>,
+
+    /// Jobs that were not imported as pairs of (ID in the import, reason)
+    pub skipped: Vec<(u64, String)>,
+}
+
+/// The state of a named queue
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
 pub enum QueueState {
-    /// queue is idle or executing a job
+    /// queue is idle or executing jobs
     Running,
 
-    /// the last process is executed before the queue is stopped
+    /// the running jobs are executed before the queue is stopped
     Stopping,
 
     /// the queue is stopped and is not precessing jobs
     Stopped,
 }
 
+/// A point in the life of a job that notifications can be sent for
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum JobEvent {
+    /// the job has been submitted
+    Queued,
+
+    /// the job has been started
+    Started,
+
+    /// the process has exited with code 0
+    Finished,
+
+    /// the process has exited with another code or could not be launched
+    Failed,
+
+    /// the process has been killed by a signal
+    Killed,
+
+    /// the job has exceeded its time limit. Reserved, jobs have no time
+    /// limit yet.
+    TimedOut,
+}
+
+/// Events that a job's notify URL is called for unless chosen otherwise
+pub const DEFAULT_JOB_EVENTS: [JobEvent; 3] =
+    [JobEvent::Finished, JobEvent::Failed, JobEvent::Killed];
+
+/// Per-job notification settings, given on submission
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct JobNotify {
+    /// URL the job reports are POSTed to
+    pub url: String,
+
+    /// Events that trigger a report
+    #[serde(default = "default_job_events")]
+    pub events: Vec<JobEvent>,
+}
+
+fn default_job_events() -> Vec<JobEvent> {
+    DEFAULT_JOB_EVENTS.to_vec()
+}
+
 /// The Job
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Job {
@@ -72,6 +143,497 @@ pub struct Job {
 
     /// PID of the process (only if running or finished)
     pub pid: Option<u32>,
+
+    /// Name of the user who submitted the job, if known
+    #[serde(default)]
+    pub owner: Option<String>,
+
+    /// Name of the queue that runs the job
+    #[serde(default = "default_queue")]
+    pub queue: String,
+
+    /// Notifications requested by the submitter
+    #[serde(default)]
+    pub notify: Option<JobNotify>,
+
+    /// Address that a mail is sent to when the job has ended
+    #[serde(default)]
+    pub mail_to: Option<String>,
+
+    /// ID of the job that this one is a copy of, see `JobQueue::resubmit`
+    #[serde(default)]
+    pub resubmitted_from: Option<u64>,
+
+    /// Changes made while the job was queued, oldest first
+    #[serde(default)]
+    pub changes: Vec<JobChange>,
+
+    /// CPUs and bytes of memory the job needs, see `Job::needs`
+    #[serde(default)]
+    pub cpus: Option<u32>,
+    #[serde(default)]
+    pub mem: Option<u64>,
+}
+
+/// A change of a queued job, see `JobQueue::update`
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
+pub struct JobChange {
+    pub time: SystemTime,
+
+    /// Changed property: `cmdline`, `notify` or `mail_to`
+    pub field: String,
+
+    /// Old and new value in readable form
+    pub old: String,
+    pub new: String,
+}
+
+/// Changes to be made to a queued job. Properties that are not given are
+/// left as they are.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct JobChanges {
+    /// New arguments, i.e. the command line without the appkey
+    #[serde(default)]
+    pub args: Option<String>,
+
+    /// New notify URL, an empty URL turns notifications off
+    #[serde(default)]
+    pub notify_url: Option<String>,
+
+    /// New events to send reports for. Ignored if there is no notify URL.
+    #[serde(default)]
+    pub notify_on: Option<Vec<JobEvent>>,
+
+    /// New mail address, an empty address removes it
+    #[serde(default)]
+    pub mail_to: Option<String>,
+}
+
+/// Optional properties of a job, given on submission
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct SubmitOptions {
+    /// Name of the submitting user
+    #[serde(default)]
+    pub owner: Option<String>,
+
+    /// Notify URL and events, see `JobNotify`
+    #[serde(default)]
+    pub notify: Option<JobNotify>,
+
+    /// Address that a mail is sent to when the job has ended
+    #[serde(default)]
+    pub mail_to: Option<String>,
+
+    /// Queue to run the job in. The daemon picks the appkey's default queue
+    /// if none is given.
+    #[serde(default)]
+    pub queue: Option<String>,
+
+    /// CPUs and bytes of memory the job needs. The daemon fills in the
+    /// appkey's defaults if not given.
+    #[serde(default)]
+    pub cpus: Option<u32>,
+    #[serde(default)]
+    pub mem: Option<u64>,
+}
+
+impl JobState {
+    /// Returns a short, lower-case name for the state without its payload,
+    /// i.e. `failed` for `Failed(_)`. Used for filtering and indexing.
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Held => "held",
+            JobState::Running => "running",
+            JobState::Terminated(_) => "terminated",
+            JobState::Killed(_) => "killed",
+            JobState::Failed(_) => "failed",
+        }
+    }
+}
+
+impl JobEvent {
+    /// Returns the name of the event as used in the protocol, i.e. `timed_out`
+    pub fn name(&self) -> &'static str {
+        match self {
+            JobEvent::Queued => "queued",
+            JobEvent::Started => "started",
+            JobEvent::Finished => "finished",
+            JobEvent::Failed => "failed",
+            JobEvent::Killed => "killed",
+            JobEvent::TimedOut => "timed_out",
+        }
+    }
+
+    /// Returns the event for a job that has ended in the given state
+    pub fn for_state(state: &JobState) -> Option<JobEvent> {
+        match state {
+            JobState::Queued | JobState::Held | JobState::Running => None,
+            JobState::Terminated(0) => Some(JobEvent::Finished),
+            JobState::Terminated(_) | JobState::Failed(_) => Some(JobEvent::Failed),
+            JobState::Killed(_) => Some(JobEvent::Killed),
+        }
+    }
+
+    /// Checks whether the event marks the end of a job
+    pub fn is_final(&self) -> bool {
+        !matches!(self, JobEvent::Queued | JobEvent::Started)
+    }
+}
+
+impl FromStr for JobEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let events = [
+            JobEvent::Queued,
+            JobEvent::Started,
+            JobEvent::Finished,
+            JobEvent::Failed,
+            JobEvent::Killed,
+            JobEvent::TimedOut,
+        ];
+        events
+            .iter()
+            .find(|e| e.name() == s.to_lowercase().replace('-', "_"))
+            .cloned()
+            .ok_or_else(|| {
+                format!(
+                    "Unknown event '{}', use queued, started, finished, failed, killed or timed_out",
+                    s
+                )
+            })
+    }
+}
+
+impl Job {
+    /// Returns the appkey of the job, i.e. the first word of its command line
+    pub fn appkey(&self) -> &str {
+        self.cmdline.split_ascii_whitespace().next().unwrap_or("")
+    }
+
+    /// Returns the resources the job needs, see `Resources::declared`
+    pub fn needs(&self) -> Resources {
+        Resources::declared(self.cpus, self.mem)
+    }
+
+    /// Records a change in the job's history, unless the value is the same
+    fn log_change(&mut self, time: SystemTime, field: &str, old: String, new: String) {
+        if old != new {
+            self.changes.push(JobChange {
+                time,
+                field: field.to_string(),
+                old,
+                new,
+            });
+        }
+    }
+}
+
+/// Describes notification settings in a job's history
+fn describe_notify(notify: &Option<JobNotify>) -> String {
+    match notify {
+        Some(n) => {
+            let events: Vec<&str> = n.events.iter().map(|e| e.name()).collect();
+            format!("{} on {}", n.url, events.join(","))
+        }
+        None => "none".to_string(),
+    }
+}
+
+/// Criteria for selecting jobs from the queue or the job history.
+/// Unset fields match any job.
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct JobFilter {
+    /// State name as returned by `JobState::name`, i.e. `failed`
+    #[serde(default)]
+    pub state: Option<String>,
+
+    /// Appkey of the job
+    #[serde(default)]
+    pub appkey: Option<String>,
+
+    /// Submitting user
+    #[serde(default)]
+    pub owner: Option<String>,
+
+    /// Queue of the job
+    #[serde(default)]
+    pub queue: Option<String>,
+
+    /// Only jobs submitted at or after this point in time
+    #[serde(default)]
+    pub since: Option<SystemTime>,
+
+    /// Only jobs submitted before this point in time
+    #[serde(default)]
+    pub until: Option<SystemTime>,
+
+    /// Only jobs that have finished before this point in time
+    #[serde(default)]
+    pub finished_before: Option<SystemTime>,
+
+    /// Only jobs whose command line contains this text
+    #[serde(default)]
+    pub cmdline: Option<String>,
+
+    /// Only copies of the job with this ID
+    #[serde(default)]
+    pub resubmitted_from: Option<u64>,
+}
+
+impl JobFilter {
+    /// Checks whether the given job satisfies all criteria of the filter
+    pub fn matches(&self, job: &Job) -> bool {
+        if let Some(ref state) = self.state {
+            if job.state.name() != state {
+                return false;
+            }
+        }
+        if let Some(ref appkey) = self.appkey {
+            if job.appkey() != appkey {
+                return false;
+            }
+        }
+        if let Some(ref owner) = self.owner {
+            if job.owner.as_ref() != Some(owner) {
+                return false;
+            }
+        }
+        if let Some(ref queue) = self.queue {
+            if &job.queue != queue {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if job.scheduled < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if job.scheduled >= until {
+                return false;
+            }
+        }
+        if let Some(finished_before) = self.finished_before {
+            match job.finished {
+                Some(t) if t < finished_before => {}
+                _ => return false,
+            }
+        }
+        if let Some(ref text) = self.cmdline {
+            if !job.cmdline.contains(text.as_str()) {
+                return false;
+            }
+        }
+        if self.resubmitted_from.is_some() && job.resubmitted_from != self.resubmitted_from {
+            return false;
+        }
+        true
+    }
+}
+
+/// The list of the queue that a listing covers
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum JobList {
+    /// Queued and running jobs
+    Queued,
+
+    /// Terminated jobs
+    Finished,
+
+    /// Both, searched in the program state
+    All,
+}
+
+/// Where a queued job is moved to. Positions count the jobs waiting in the
+/// job's queue, i.e. not the running ones, starting at 1.
+#[derive(Debug, PartialEq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum QueuePosition {
+    /// In front of all waiting jobs
+    Top,
+
+    /// Directly in front of the job with the given ID
+    Before(u64),
+
+    /// Directly behind the job with the given ID
+    After(u64),
+
+    /// At the given position, or at the end if there are fewer jobs
+    Position(usize),
+}
+
+/// Property that job listings are sorted by
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SortKey {
+    Id,
+    Submitted,
+    Started,
+    Finished,
+    Appkey,
+    Owner,
+    State,
+}
+
+impl SortKey {
+    pub fn compare(self, a: &Job, b: &Job) -> Ordering {
+        match self {
+            SortKey::Id => a.id.cmp(&b.id),
+            SortKey::Submitted => a.scheduled.cmp(&b.scheduled),
+            SortKey::Started => a.started.cmp(&b.started),
+            SortKey::Finished => a.finished.cmp(&b.finished),
+            SortKey::Appkey => a.appkey().cmp(b.appkey()),
+            SortKey::Owner => a.owner.cmp(&b.owner),
+            SortKey::State => a.state.name().cmp(b.state.name()),
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "id" => Ok(SortKey::Id),
+            "submitted" => Ok(SortKey::Submitted),
+            "started" => Ok(SortKey::Started),
+            "finished" => Ok(SortKey::Finished),
+            "appkey" => Ok(SortKey::Appkey),
+            "owner" => Ok(SortKey::Owner),
+            "state" => Ok(SortKey::State),
+            _ => Err(format!(
+                "Unknown sort key '{}', use id, submitted, started, finished, appkey, owner or state",
+                s
+            )),
+        }
+    }
+}
+
+/// Selects, orders and pages the jobs of a listing
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct JobQuery {
+    #[serde(default)]
+    pub filter: JobFilter,
+
+    /// Sort key, unset keeps the order of the list
+    #[serde(default)]
+    pub sort: Option<SortKey>,
+
+    /// Reverse the order
+    #[serde(default)]
+    pub descending: bool,
+
+    /// Number of matching jobs to skip
+    #[serde(default)]
+    pub offset: usize,
+
+    /// Maximum number of jobs returned
+    #[serde(default)]
+    pub limit: Option<usize>,
+}
+
+/// A page of a job listing
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JobPage {
+    pub jobs: Vec<Job>,
+
+    /// Number of jobs matching the filter, on all pages
+    pub total: usize,
+
+    /// Position of the first job of this page among all matching jobs
+    pub offset: usize,
+}
+
+impl JobQuery {
+    /// Filters, sorts and pages the given jobs
+    pub fn apply<'a, I: Iterator<Item = &'a Job>>(&self, jobs: I) -> JobPage {
+        let mut jobs: Vec<&Job> = jobs.filter(|j| self.filter.matches(j)).collect();
+        if let Some(key) = self.sort {
+            jobs.sort_by(|a, b| key.compare(a, b));
+        }
+        if self.descending {
+            jobs.reverse();
+        }
+
+        let total = jobs.len();
+        let jobs = jobs
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect();
+        JobPage {
+            jobs,
+            total,
+            offset: self.offset,
+        }
+    }
+}
+
+/// A single modification of the job queue. Every change made through the
+/// methods of `JobQueue` is recorded as an event, so that storage backends can
+/// persist changes incrementally and replay them after a restart.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum QueueEvent {
+    /// A job has been added to the end of the queue
+    Submitted(Job),
+
+    /// The job with the given ID has been started at the given time
+    Started(u64, SystemTime),
+
+    /// The running job with the given ID has been assigned a PID
+    PidAssigned(u64, u32),
+
+    /// A job has left the queue and was moved to the finished jobs
+    Finished(Job),
+
+    /// The job with the given ID has been reset to the `Queued` state
+    Requeued(u64),
+
+    /// The job with the given ID has been removed
+    Removed(u64),
+
+    /// The queued job with the given ID has been held
+    Held(u64),
+
+    /// The held job with the given ID has been released
+    Released(u64),
+
+    /// A queued job has been changed
+    Updated(Job),
+
+    /// The queued job with the given ID has been moved to the given index of
+    /// the queue, counted without the job itself
+    Moved(u64, usize),
+
+    /// The state of the named queue has changed
+    QueueStateChanged(String, QueueState),
+
+    /// A job has been imported from another daemon, queued or finished
+    Imported(Job),
+}
+
+impl QueueEvent {
+    /// Returns the ID of the job that the event changes, if any
+    pub fn job_id(&self) -> Option<u64> {
+        match *self {
+            QueueEvent::Submitted(ref job)
+            | QueueEvent::Finished(ref job)
+            | QueueEvent::Updated(ref job)
+            | QueueEvent::Imported(ref job) => Some(job.id),
+            QueueEvent::Started(id, _)
+            | QueueEvent::PidAssigned(id, _)
+            | QueueEvent::Requeued(id)
+            | QueueEvent::Removed(id)
+            | QueueEvent::Held(id)
+            | QueueEvent::Released(id)
+            | QueueEvent::Moved(id, _) => Some(id),
+            QueueEvent::QueueStateChanged(..) => None,
+        }
+    }
 }
 
 /// The Job Queue itself
@@ -80,28 +642,93 @@ pub struct JobQueue {
     /// The last ID assigned to a job
     last_id: u64,
 
-    /// Queue state
+    /// State of the default queue
     state: QueueState,
 
-    /// The list of queued jobs, including the currently running one
+    /// States of the other named queues, Running unless set otherwise
+    #[serde(default)]
+    queue_states: BTreeMap<String, QueueState>,
+
+    /// The list of queued jobs of all queues, including the running ones
     queue: Vec<Job>,
 
     /// List of finished jobs
     finished: Vec<Job>,
+
+    /// Events recorded since they have last been taken by the program state
+    #[serde(skip)]
+    events: Vec<QueueEvent>,
+
+    /// Set while the daemon is shutting down. No further jobs are started.
+    #[serde(skip)]
+    draining: bool,
+
+    /// Whether a running job is put back into the queue once it terminates
+    /// during shutdown, instead of being marked as finished
+    #[serde(skip)]
+    requeue_running: bool,
+
+    /// Bus that all changes are published to, if set
+    #[serde(skip)]
+    bus: Option<Arc<EventBus>>,
+
+    /// Number of jobs that each queue runs at the same time. Jobs of queues
+    /// that are not listed are not started.
+    #[serde(skip, default = "default_slots")]
+    slots: BTreeMap<String, usize>,
+
+    /// Resources of the host that running jobs share, None for no limit
+    #[serde(skip)]
+    resources: Option<Resources>,
+}
+
+fn default_slots() -> BTreeMap<String, usize> {
+    let mut slots = BTreeMap::new();
+    slots.insert(default_queue(), 1);
+    slots
 }
 
 impl JobQueue {
     /// Creates a new JobQueue with the given last ID. The first ID
     /// to be assigned will be last_id+1.
     pub fn new(last_id: u64) -> Self {
+        JobQueue::from_parts(
+            last_id,
+            QueueState::Running,
+            BTreeMap::new(),
+            Vec::new(),
+            Vec::new(),
+        )
+    }
+
+    /// Reassembles a JobQueue from its parts, i.e. when loaded from a storage backend
+    pub fn from_parts(
+        last_id: u64,
+        state: QueueState,
+        queue_states: BTreeMap<String, QueueState>,
+        queue: Vec<Job>,
+        finished: Vec<Job>,
+    ) -> Self {
         JobQueue {
             last_id,
-            state: QueueState::Running,
-            queue: Vec::new(),
-            finished: Vec::new(),
+            state,
+            queue_states,
+            queue,
+            finished,
+            events: Vec::new(),
+            draining: false,
+            requeue_running: false,
+            bus: None,
+            slots: default_slots(),
+            resources: None,
         }
     }
 
+    /// Returns the last ID assigned to a job
+    pub fn last_id(&self) -> u64 {
+        self.last_id
+    }
+
     /// Provides an iterator over the currently queued jobs, including the running
     pub fn iter_queued(&self) -> impl Iterator<Item = &Job> {
         self.queue.iter()
@@ -112,57 +739,251 @@ impl JobQueue {
         self.finished.iter()
     }
 
-    /// Returns the state of the queue
-    pub fn get_state(&self) -> QueueState {
-        self.state
+    /// Returns and forgets the events recorded since the last call
+    pub fn take_events(&mut self) -> Vec<QueueEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Hands back events taken by `take_events` that could not be stored,
+    /// so that they are passed on again with the next save
+    pub fn return_events(&mut self, mut events: Vec<QueueEvent>) {
+        events.append(&mut self.events);
+        self.events = events;
+    }
+
+    /// Applies an event to the queue without recording it. Used to replay
+    /// events from a journal. Events referring to unknown jobs are ignored,
+    /// as are jobs added with an ID that is already taken.
+    pub fn apply(&mut self, event: &QueueEvent) {
+        match event {
+            QueueEvent::Submitted(job) | QueueEvent::Imported(job)
+                if self.get_job(job.id).is_some() =>
+            {
+                warn!("Ignoring job {} that has already been added", job.id);
+            }
+            QueueEvent::Submitted(job) => {
+                self.last_id = self.last_id.max(job.id);
+                self.queue.push(job.clone());
+            }
+            QueueEvent::Started(id, at) => {
+                if let Some(j) = self.queue.iter_mut().find(|j| j.id == *id) {
+                    j.started = Some(*at);
+                    j.state = JobState::Running;
+                }
+            }
+            QueueEvent::PidAssigned(id, pid) => {
+                if let Some(j) = self.queue.iter_mut().find(|j| j.id == *id) {
+                    j.pid = Some(*pid);
+                }
+            }
+            QueueEvent::Finished(job) => {
+                self.queue.retain(|j| j.id != job.id);
+                if !self.finished.iter().any(|j| j.id == job.id) {
+                    self.finished.push(job.clone());
+                }
+            }
+            QueueEvent::Requeued(id) => {
+                if let Some(j) = self.queue.iter_mut().find(|j| j.id == *id) {
+                    j.started = None;
+                    j.state = JobState::Queued;
+                    j.pid = None;
+                    j.stderr = String::from("");
+                    j.stdout = String::from("");
+                }
+            }
+            QueueEvent::Removed(id) => {
+                self.queue.retain(|j| j.id != *id);
+                self.finished.retain(|j| j.id != *id);
+            }
+            QueueEvent::Held(id) => {
+                if let Some(j) = self.queue.iter_mut().find(|j| j.id == *id) {
+                    j.state = JobState::Held;
+                }
+            }
+            QueueEvent::Released(id) => {
+                if let Some(j) = self.queue.iter_mut().find(|j| j.id == *id) {
+                    j.state = JobState::Queued;
+                }
+            }
+            QueueEvent::Updated(job) => {
+                if let Some(j) = self.queue.iter_mut().find(|j| j.id == job.id) {
+                    *j = job.clone();
+                }
+            }
+            QueueEvent::Moved(id, index) => {
+                if let Some(pos) = self.queue.iter().position(|j| j.id == *id) {
+                    let job = self.queue.remove(pos);
+                    let index = (*index).min(self.queue.len());
+                    self.queue.insert(index, job);
+                }
+            }
+            QueueEvent::QueueStateChanged(queue, state) => {
+                if queue == DEFAULT_QUEUE {
+                    self.state = *state;
+                } else {
+                    self.queue_states.insert(queue.clone(), *state);
+                }
+            }
+            QueueEvent::Imported(job) => {
+                self.last_id = self.last_id.max(job.id);
+                if job.state == JobState::Queued || job.state == JobState::Held {
+                    self.queue.push(job.clone());
+                } else {
+                    self.finished.push(job.clone());
+                }
+            }
+        }
+    }
+
+    /// Applies an event to the queue and records it for the program state
+    fn record(&mut self, event: QueueEvent) {
+        if let Some(ref bus) = self.bus {
+            // look up the owner before the job may be removed
+            let owner = match event {
+                QueueEvent::Submitted(ref job)
+                | QueueEvent::Finished(ref job)
+                | QueueEvent::Updated(ref job)
+                | QueueEvent::Imported(ref job) => job.owner.clone(),
+                _ => event
+                    .job_id()
+                    .and_then(|id| self.get_job(id))
+                    .and_then(|j| j.owner.clone()),
+            };
+            if let Some(bus_event) = BusEvent::from_queue_event(&event, owner) {
+                bus.publish(bus_event);
+            }
+        }
+
+        self.apply(&event);
+        self.events.push(event);
+    }
+
+    /// Publishes all changes from now on to the given event bus
+    pub fn set_event_bus(&mut self, bus: Arc<EventBus>) {
+        self.bus = Some(bus);
+    }
+
+    /// Sets the number of jobs that each queue runs at the same time
+    pub fn set_slots(&mut self, slots: BTreeMap<String, usize>) {
+        self.slots = slots;
     }
 
-    /// Sets the state of the queue
-    pub fn set_state(&mut self, mut new_state: QueueState) {
+    /// Sets the resources of the host that running jobs share
+    pub fn set_resources(&mut self, resources: Option<Resources>) {
+        self.resources = resources;
+    }
+
+    /// Returns the resources of the host and the ones allocated to running
+    /// jobs, None if there is no limit
+    pub fn resource_usage(&self) -> Option<ResourceUsage> {
+        let total = self.resources?;
+        let allocated = self
+            .all_running_jobs()
+            .fold(Resources::default(), |sum, j| sum + j.needs());
+        Some(ResourceUsage { total, allocated })
+    }
+
+    /// Looks up a queued or finished job
+    pub fn get_job(&self, id: u64) -> Option<&Job> {
+        self.queue
+            .iter()
+            .chain(self.finished.iter())
+            .find(|j| j.id == id)
+    }
+
+    /// Returns the state of the named queue
+    pub fn get_state(&self, queue: &str) -> QueueState {
+        if queue == DEFAULT_QUEUE {
+            self.state
+        } else {
+            self.queue_states
+                .get(queue)
+                .cloned()
+                .unwrap_or(QueueState::Running)
+        }
+    }
+
+    /// Returns the states of the named queues other than the default queue
+    /// that have been set
+    pub fn queue_states(&self) -> &BTreeMap<String, QueueState> {
+        &self.queue_states
+    }
+
+    /// Sets the state of the named queue
+    pub fn set_state(&mut self, queue: &str, mut new_state: QueueState) {
         debug!(
-            "Trying to set state {:?} to {:?}, queue size is {}",
-            self.state,
-            new_state,
-            self.queue.len()
+            "Trying to set state of queue {} from {:?} to {:?}",
+            queue,
+            self.get_state(queue),
+            new_state
         );
-        if new_state == QueueState::Stopping && self.queue.is_empty() {
+        if new_state == QueueState::Stopping && self.running_jobs(queue).next().is_none() {
             new_state = QueueState::Stopped;
         }
-        self.state = new_state;
+        self.record(QueueEvent::QueueStateChanged(queue.to_string(), new_state));
     }
 
-    /// Reset the first job of the queue if it was some variation of Running
-    /// during start/resume.
-    pub fn reset_first_job(&mut self, new_state: JobState) {
-        debug!("Setting status of first job in queue to {:?}", new_state);
+    /// Resets the running job with the given ID during start/resume
+    pub fn reset_job(&mut self, id: u64, new_state: JobState) {
+        debug!("Setting status of running job {} to {:?}", id, new_state);
 
-        // Only reset if there is a job
-        if let Some(j) = self.queue.first() {
-            // Only reset if the current job is not properly queued (i.e. Running)
-            if j.state != JobState::Queued {
-                match new_state {
-                    JobState::Running | JobState::Killed(_) | JobState::Terminated(_) => {
-                        panic!("Cannot manually set a job to Running, Terminated or Killed state")
-                    }
-                    JobState::Queued => {
-                        self.queue.first_mut().map(|j| {
-                            j.started = None;
-                            j.state = JobState::Queued;
-                            j.pid = None;
-                            j.stderr = String::from("");
-                            j.stdout = String::from("");
-                        });
-                    }
-                    JobState::Failed(s) => {
-                        self.finish(JobState::Failed(s), "".to_owned(), "".to_owned());
-                    }
+        // Only reset if the job is running
+        if self.all_running_jobs().any(|j| j.id == id) {
+            match new_state {
+                JobState::Running
+                | JobState::Held
+                | JobState::Killed(_)
+                | JobState::Terminated(_) => {
+                    panic!("Cannot manually set a job to Running, Held, Terminated or Killed state")
+                }
+                JobState::Queued => self.record(QueueEvent::Requeued(id)),
+                JobState::Failed(s) => {
+                    self.finish(id, JobState::Failed(s), "".to_owned(), "".to_owned());
                 }
             }
         }
     }
 
     /// Submits a new job to the queue and returns the assigned ID
-    pub fn submit(&mut self, cmdline: String) -> u64 {
+    pub fn submit(&mut self, cmdline: String, options: SubmitOptions) -> u64 {
+        self.add_job(cmdline, options, None)
+    }
+
+    /// Submits a copy of the finished job with the given ID: the same command
+    /// line and queue, unless others are given, and the same owner and
+    /// notifications. The copy is linked to the original. Returns the
+    /// assigned ID. Fails with `WrongJobState` for queued and running jobs,
+    /// which would otherwise be run twice.
+    pub fn resubmit(
+        &mut self,
+        id: u64,
+        cmdline: Option<String>,
+        queue: Option<String>,
+    ) -> Result<u64, FailReason> {
+        let job = match self.finished.iter().find(|j| j.id == id) {
+            Some(job) => job,
+            None if self.get_job(id).is_some() => return Err(FailReason::WrongJobState),
+            None => return Err(FailReason::NoSuchJob),
+        };
+        let options = SubmitOptions {
+            owner: job.owner.clone(),
+            notify: job.notify.clone(),
+            mail_to: job.mail_to.clone(),
+            queue: Some(queue.unwrap_or_else(|| job.queue.clone())),
+            cpus: job.cpus,
+            mem: job.mem,
+        };
+        let cmdline = cmdline.unwrap_or_else(|| job.cmdline.clone());
+        Ok(self.add_job(cmdline, options, Some(id)))
+    }
+
+    /// Appends a new job to the queue and returns the assigned ID
+    fn add_job(
+        &mut self,
+        cmdline: String,
+        options: SubmitOptions,
+        resubmitted_from: Option<u64>,
+    ) -> u64 {
         let job = Job {
             id: self.last_id + 1,
             cmdline,
@@ -173,30 +994,122 @@ impl JobQueue {
             stdout: String::from(""),
             state: JobState::Queued,
             pid: None,
+            owner: options.owner,
+            queue: options.queue.unwrap_or_else(default_queue),
+            notify: options.notify,
+            mail_to: options.mail_to,
+            resubmitted_from,
+            changes: Vec::new(),
+            cpus: options.cpus,
+            mem: options.mem,
         };
 
-        self.last_id += 1;
-        self.queue.push(job);
+        self.record(QueueEvent::Submitted(job));
         self.last_id
     }
 
-    /// Returns the topmost job of the "queued" queue if available.
+    /// Returns the topmost job that is not held among the queues that are
+    /// running and have a free slot, if available. Jobs whose appkey is at
+    /// its limit are skipped. If the host's resources are limited, a job
+    /// that does not fit into the free resources holds back the later jobs
+    /// of its queue, so that it is not overtaken forever by smaller ones.
     /// The job is expected to be executed.
-    pub fn schedule(&mut self) -> Option<Job> {
-        if self.state == QueueState::Running {
-            self.queue.first_mut().map(|j| {
-                j.started = Some(SystemTime::now());
-                j.state = JobState::Running;
-                j.clone()
-            })
-        } else {
-            None
+    pub fn schedule(&mut self, limits: &AppkeyLimits) -> Option<Job> {
+        if self.draining {
+            return None;
+        }
+        let usage = self.resource_usage();
+        let mut waiting_for_resources: Vec<&str> = Vec::new();
+        let id = self
+            .queue
+            .iter()
+            .find(|j| {
+                if j.state != JobState::Queued
+                    || waiting_for_resources.contains(&j.queue.as_str())
+                    || !self.has_free_slot(&j.queue)
+                    || !limits.allows(j.appkey(), self.all_running_jobs())
+                {
+                    return false;
+                }
+                match usage {
+                    // jobs that can never fit do not hold back the others
+                    Some(usage) if usage.total.holds(&j.needs()) => {
+                        if (usage.total - usage.allocated).holds(&j.needs()) {
+                            true
+                        } else {
+                            waiting_for_resources.push(&j.queue);
+                            false
+                        }
+                    }
+                    Some(_) => false,
+                    None => true,
+                }
+            })?
+            .id;
+        self.record(QueueEvent::Started(id, SystemTime::now()));
+        self.get_job(id).cloned()
+    }
+
+    /// Checks whether the named queue is running and runs fewer jobs than
+    /// it has slots
+    fn has_free_slot(&self, queue: &str) -> bool {
+        self.get_state(queue) == QueueState::Running
+            && self
+                .slots
+                .get(queue)
+                .is_some_and(|&slots| self.running_jobs(queue).count() < slots)
+    }
+
+    /// Returns the running jobs of the named queue
+    pub fn running_jobs<'a>(&'a self, queue: &'a str) -> impl Iterator<Item = &'a Job> + Clone {
+        self.queue
+            .iter()
+            .filter(move |j| j.state == JobState::Running && j.queue == queue)
+    }
+
+    /// Returns the running jobs of all queues
+    pub fn all_running_jobs(&self) -> impl Iterator<Item = &Job> {
+        self.queue.iter().filter(|j| j.state == JobState::Running)
+    }
+
+    /// Stops starting new jobs because the daemon is shutting down. If
+    /// `requeue_running` is set, a job that terminates from now on is put
+    /// back into the queue (see `requeue_on_exit`).
+    pub fn drain(&mut self, requeue_running: bool) {
+        self.draining = true;
+        self.requeue_running = requeue_running;
+    }
+
+    /// Checks whether a terminating job should be requeued instead of finished
+    pub fn requeue_on_exit(&self) -> bool {
+        self.draining && self.requeue_running
+    }
+
+    /// Puts the running job with the given ID back into the queue, so that
+    /// it is started again from scratch
+    pub fn requeue(&mut self, jobid: u64) {
+        if self.all_running_jobs().any(|j| j.id == jobid) {
+            self.record(QueueEvent::Requeued(jobid));
         }
     }
 
-    /// Sends SIGTERM to the associated pid of the given job ID
+    /// Sends SIGTERM to the process group of the given job ID. The job wrapper
+    /// ignores the signal, so only the job itself is terminated.
     pub fn send_sigterm(&mut self, jobid: u64) -> Result<(), Error> {
-        debug!("[job queue] Trying to kill job {}", jobid);
+        self.send_signal(jobid, "-SIGTERM")
+    }
+
+    /// Sends SIGKILL to the process group of the given job ID, which ends the
+    /// job wrapper as well, so that the job leaves no exit status
+    pub fn send_sigkill(&mut self, jobid: u64) -> Result<(), Error> {
+        self.send_signal(jobid, "-SIGKILL")
+    }
+
+    /// Sends the given signal to the process group of the given job ID.
+    /// Fails with `WouldBlock` if the job has been scheduled, but its process
+    /// has not been started yet.
+    fn send_signal(&mut self, jobid: u64, signal: &str) -> Result<(), Error> {
+        debug!("[job queue] Trying to send {} to job {}", signal, jobid);
 
         // find the currently running job
         match self
@@ -204,11 +1117,20 @@ impl JobQueue {
             .iter()
             .find(|j| j.state == JobState::Running && j.id == jobid)
         {
+            Some(job) if job.pid.is_none() => {
+                warn!("Job {} has no process yet", jobid);
+                Err(Error::new(
+                    ErrorKind::WouldBlock,
+                    "the job's process has not been started yet",
+                ))
+            }
+
             Some(job) => {
                 // It is okay to panic here, as failure to execute /bin/kill is a serious bug
                 let status = Command::new("/bin/kill")
-                    .arg("-SIGTERM")
-                    .arg(job.pid.unwrap().to_string())
+                    .arg(signal)
+                    .arg("--")
+                    .arg(format!("-{}", job.pid.unwrap()))
                     .status()
                     .expect("Failed to execute kill command");
 
@@ -232,37 +1154,46 @@ impl JobQueue {
         }
     }
 
-    /// Assigns a pid to the currently running job
+    /// Assigns a pid to a running job
     pub fn assign_pid(&mut self, jobid: u64, pid: u32) {
-        if let Some(ref mut job) = self
+        if self
             .queue
-            .iter_mut()
-            .find(|job| job.state == JobState::Running && job.id == jobid)
+            .iter()
+            .any(|job| job.state == JobState::Running && job.id == jobid)
         {
-            job.pid = Some(pid);
+            self.record(QueueEvent::PidAssigned(jobid, pid));
         }
     }
 
-    /// Sets a job to the "Finished" state and moves it to the appropriate queue.
-    /// Time stamps are updated.
-    pub fn finish(&mut self, new_state: JobState, stdout: String, stderr: String) -> Option<Job> {
-        if !self.queue.is_empty() {
-            let mut j = self.queue.remove(0);
+    /// Sets the running job with the given ID to the "Finished" state and moves
+    /// it to the finished jobs. Time stamps are updated. A stopping queue is
+    /// stopped once its last running job has finished.
+    pub fn finish(
+        &mut self,
+        id: u64,
+        new_state: JobState,
+        stdout: String,
+        stderr: String,
+    ) -> Option<Job> {
+        let running = self.all_running_jobs().find(|j| j.id == id).cloned();
+        if let Some(mut j) = running {
             debug!(
                 "Queue finish: job {} old state {:?} new state {:?}",
                 j.id, j.state, new_state
             );
-            if j.state != JobState::Running {
-                panic!("Trying to finish a job that is not running: {:?}", j);
-            }
 
             j.finished = Some(SystemTime::now());
             j.state = new_state;
             j.stdout = stdout;
             j.stderr = stderr;
-            self.finished.push(j.clone());
-            if self.state == QueueState::Stopping {
-                self.state = QueueState::Stopped;
+            self.record(QueueEvent::Finished(j.clone()));
+            if self.get_state(&j.queue) == QueueState::Stopping
+                && self.running_jobs(&j.queue).next().is_none()
+            {
+                self.record(QueueEvent::QueueStateChanged(
+                    j.queue.clone(),
+                    QueueState::Stopped,
+                ));
             }
             Some(j)
         } else {
@@ -275,37 +1206,480 @@ impl JobQueue {
     /// Only queued or finished jobs can be removed. Trying to remove a running
     /// job will fail.
     pub fn remove(&mut self, id: u64) -> Result<Job, FailReason> {
-        let mut item_index: Option<usize> = None;
+        let job = match self
+            .finished
+            .iter()
+            .chain(self.queue.iter())
+            .find(|j| j.id == id)
+        {
+            Some(job) if job.state == JobState::Running => return Err(FailReason::WrongJobState),
+            Some(job) => job.clone(),
+            None => return Err(FailReason::NoSuchJob),
+        };
+
+        self.record(QueueEvent::Removed(id));
+        Ok(job)
+    }
+
+    /// Holds the queued job with the given ID, so that it is skipped by
+    /// `schedule` until it is released
+    pub fn hold(&mut self, id: u64) -> Result<(), FailReason> {
+        match self.get_job(id) {
+            Some(job) if job.state == JobState::Queued => {
+                self.record(QueueEvent::Held(id));
+                Ok(())
+            }
+            Some(_) => Err(FailReason::WrongJobState),
+            None => Err(FailReason::NoSuchJob),
+        }
+    }
+
+    /// Releases the held job with the given ID. It keeps its position in the
+    /// queue.
+    pub fn release(&mut self, id: u64) -> Result<(), FailReason> {
+        match self.get_job(id) {
+            Some(job) if job.state == JobState::Held => {
+                self.record(QueueEvent::Released(id));
+                Ok(())
+            }
+            Some(_) => Err(FailReason::WrongJobState),
+            None => Err(FailReason::NoSuchJob),
+        }
+    }
 
-        // scan finished jobs
-        for (current, job) in self.finished.iter().enumerate() {
-            if job.id == id {
-                item_index = Some(current);
-                break;
+    /// Changes the queued or held job with the given ID and records each change
+    /// in the job's history. Held jobs are included, so that a job can be held
+    /// while it is being changed. Jobs that have been started cannot be
+    /// changed. Returns the updated job.
+    pub fn update(&mut self, id: u64, changes: JobChanges) -> Result<Job, FailReason> {
+        let mut job = match self.queue.iter().find(|j| j.id == id) {
+            Some(job) if job.state == JobState::Queued || job.state == JobState::Held => {
+                job.clone()
             }
+            Some(_) => return Err(FailReason::WrongJobState),
+            None if self.get_job(id).is_some() => return Err(FailReason::WrongJobState),
+            None => return Err(FailReason::NoSuchJob),
+        };
+        let now = SystemTime::now();
+        let logged = job.changes.len();
+
+        if let Some(args) = changes.args {
+            let cmdline = format!("{} {}", job.appkey(), args.trim())
+                .trim_end()
+                .to_string();
+            let old = std::mem::replace(&mut job.cmdline, cmdline.clone());
+            job.log_change(now, "cmdline", old, cmdline);
         }
 
-        // remove if found
-        if let Some(index) = item_index {
-            return Ok(self.finished.remove(index));
+        let old_notify = job.notify.clone();
+        if let Some(url) = changes.notify_url {
+            job.notify = if url.is_empty() {
+                None
+            } else {
+                let events = job.notify.take().map(|n| n.events);
+                Some(JobNotify {
+                    url,
+                    events: events.unwrap_or_else(default_job_events),
+                })
+            };
+        }
+        if let (Some(events), Some(notify)) = (changes.notify_on, job.notify.as_mut()) {
+            notify.events = events;
         }
+        let new_notify = describe_notify(&job.notify);
+        job.log_change(now, "notify", describe_notify(&old_notify), new_notify);
 
-        // scan queued jobs (that are not running)
-        for (current, job) in self.queue.iter().enumerate() {
-            if job.id == id {
-                if job.state == JobState::Running {
-                    return Err(FailReason::WrongJobState);
-                } else {
-                    item_index = Some(current);
-                    break;
+        if let Some(mail_to) = changes.mail_to {
+            let mail_to = Some(mail_to).filter(|m| !m.is_empty());
+            let old = std::mem::replace(&mut job.mail_to, mail_to.clone());
+            job.log_change(
+                now,
+                "mail_to",
+                old.unwrap_or_else(|| "none".to_string()),
+                mail_to.unwrap_or_else(|| "none".to_string()),
+            );
+        }
+
+        if job.changes.len() > logged {
+            self.record(QueueEvent::Updated(job.clone()));
+        }
+        Ok(job)
+    }
+
+    /// Moves the queued or held job with the given ID to another position in
+    /// its queue. Running jobs cannot be moved. Fails with `NoSuchJob` if the
+    /// job or the job given in `to` is not in the same queue.
+    pub fn move_job(&mut self, id: u64, to: QueuePosition) -> Result<(), FailReason> {
+        let queue = match self.queue.iter().find(|j| j.id == id) {
+            Some(job) if job.state == JobState::Running => return Err(FailReason::WrongJobState),
+            Some(job) => job.queue.clone(),
+            None if self.get_job(id).is_some() => return Err(FailReason::WrongJobState),
+            None => return Err(FailReason::NoSuchJob),
+        };
+
+        // indices refer to the jobs of all queues without the moved job
+        let others: Vec<&Job> = self.queue.iter().filter(|j| j.id != id).collect();
+        let index_of = |other: u64| {
+            others
+                .iter()
+                .position(|j| j.id == other && j.queue == queue)
+                .ok_or(FailReason::NoSuchJob)
+        };
+        let waiting = |n: usize| {
+            others
+                .iter()
+                .enumerate()
+                .filter(|(_, j)| j.state != JobState::Running && j.queue == queue)
+                .nth(n)
+                .map(|(i, _)| i)
+                .unwrap_or(others.len())
+        };
+        let index = match to {
+            // relative to itself, the job stays where it is
+            QueuePosition::Before(other) | QueuePosition::After(other) if other == id => {
+                self.queue.iter().position(|j| j.id == id).unwrap()
+            }
+            QueuePosition::Top => waiting(0),
+            QueuePosition::Before(other) => index_of(other)?,
+            QueuePosition::After(other) => index_of(other)? + 1,
+            QueuePosition::Position(n) => waiting(n.saturating_sub(1)),
+        };
+
+        self.record(QueueEvent::Moved(id, index));
+        Ok(())
+    }
+
+    /// Removes all finished jobs for which the given predicate holds and
+    /// returns the number of removed jobs
+    pub fn remove_finished_where<F: Fn(&Job) -> bool>(&mut self, predicate: F) -> usize {
+        let ids: Vec<u64> = self
+            .finished
+            .iter()
+            .filter(|j| predicate(j))
+            .map(|j| j.id)
+            .collect();
+
+        for id in &ids {
+            self.record(QueueEvent::Removed(*id));
+        }
+        ids.len()
+    }
+
+    /// Imports jobs exported from another daemon. Queued and running jobs are
+    /// appended to the queue as `Queued`, all others to the finished jobs.
+    /// Jobs that are already present (same submission time and command line)
+    /// are skipped. With `preserve_ids`, jobs keep their IDs and are skipped if
+    /// the ID is taken, otherwise they are assigned new IDs and references to
+    /// resubmitted jobs are changed accordingly.
+    /// With `dry_run`, the queue is not modified.
+    pub fn import(&mut self, jobs: Vec<Job>, preserve_ids: bool, dry_run: bool) -> ImportReport {
+        if dry_run {
+            // subscribers must not hear of jobs that are not imported
+            let mut copy = self.clone();
+            copy.bus = None;
+            return copy.import(jobs, preserve_ids, false);
+        }
+
+        let mut report = ImportReport::default();
+        let mut imported: Vec<Job> = Vec::new();
+        // IDs in the import to IDs in this queue
+        let mut new_ids = BTreeMap::new();
+        for mut job in jobs {
+            let old_id = job.id;
+            let mut existing = self
+                .queue
+                .iter()
+                .chain(self.finished.iter())
+                .chain(imported.iter());
+
+            if let Some(duplicate) = existing
+                .clone()
+                .find(|j| j.scheduled == job.scheduled && j.cmdline == job.cmdline)
+            {
+                new_ids.insert(old_id, duplicate.id);
+                report.skipped.push((old_id, "duplicate".to_owned()));
+                continue;
+            }
+
+            if preserve_ids {
+                if existing.any(|j| j.id == old_id) {
+                    report
+                        .skipped
+                        .push((old_id, "ID already in use".to_owned()));
+                    continue;
                 }
+            } else {
+                job.id = self.last_id + 1 + imported.len() as u64;
             }
+
+            if job.state == JobState::Running {
+                job.state = JobState::Queued;
+                job.started = None;
+                job.pid = None;
+            }
+
+            new_ids.insert(old_id, job.id);
+            report.imported.push((old_id, job.id));
+            imported.push(job);
         }
 
-        if let Some(index) = item_index {
-            return Ok(self.queue.remove(index));
+        for mut job in imported {
+            if !preserve_ids {
+                job.resubmitted_from = job
+                    .resubmitted_from
+                    .and_then(|id| new_ids.get(&id).cloned());
+            }
+            self.record(QueueEvent::Imported(job));
         }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a queue with jobs 1 to n, all with the given appkey
+    fn queue_with(n: usize, appkey: &str) -> JobQueue {
+        let mut q = JobQueue::new(0);
+        for _ in 0..n {
+            q.submit(appkey.to_string(), SubmitOptions::default());
+        }
+        q
+    }
+
+    fn queued_ids(q: &JobQueue) -> Vec<u64> {
+        q.iter_queued().map(|j| j.id).collect()
+    }
+
+    #[test]
+    fn replaying_events_restores_the_queue() {
+        let mut q = queue_with(4, "a");
+        assert_eq!(q.schedule(&AppkeyLimits::default()).unwrap().id, 1);
+        q.assign_pid(1, 42);
+        assert!(q.hold(3).is_ok());
+        assert!(q.move_job(4, QueuePosition::Before(2)).is_ok());
+        q.finish(1, JobState::Terminated(0), "out".into(), "err".into());
+        assert!(q.remove(2).is_ok());
+        q.set_state("other", QueueState::Stopped);
+        let events = q.take_events();
+
+        let mut replayed = JobQueue::new(0);
+        for event in &events {
+            replayed.apply(event);
+        }
+        assert_eq!(replayed.last_id(), 4);
+        assert_eq!(queued_ids(&replayed), vec![4, 3]);
+        assert_eq!(replayed.get_job(3).unwrap().state, JobState::Held);
+        let finished = replayed.get_job(1).unwrap();
+        assert_eq!(finished.state, JobState::Terminated(0));
+        assert_eq!(finished.pid, Some(42));
+        assert_eq!(finished.stdout, "out");
+        assert_eq!(replayed.get_state("other"), QueueState::Stopped);
+    }
+
+    #[test]
+    fn replaying_added_jobs_twice_does_not_duplicate_them() {
+        let mut q = queue_with(2, "a");
+        assert_eq!(q.schedule(&AppkeyLimits::default()).unwrap().id, 1);
+        q.finish(1, JobState::Terminated(0), String::new(), String::new());
+        let events = q.take_events();
+
+        let mut replayed = JobQueue::new(0);
+        for event in events.iter().chain(events.iter()) {
+            replayed.apply(event);
+        }
+        assert_eq!(queued_ids(&replayed), vec![2]);
+        assert_eq!(replayed.iter_finished().count(), 1);
+    }
+
+    /// Returns a queue with jobs 1 to 5, of which job 1 is running
+    fn queue_with_running_job() -> JobQueue {
+        let mut q = queue_with(5, "a");
+        assert_eq!(q.schedule(&AppkeyLimits::default()).unwrap().id, 1);
+        q
+    }
+
+    #[test]
+    fn jobs_without_a_process_cannot_be_signalled() {
+        let mut q = queue_with_running_job();
+        let e = q.send_sigterm(1).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::WouldBlock);
+        assert_eq!(
+            q.send_sigkill(2).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn dry_run_imports_publish_nothing() {
+        let bus = Arc::new(EventBus::new());
+        let events = bus.subscribe();
+        let mut q = JobQueue::new(0);
+        q.set_event_bus(Arc::clone(&bus));
+        let jobs: Vec<Job> = queue_with(2, "a").iter_queued().cloned().collect();
+
+        let report = q.import(jobs.clone(), false, true);
+        assert_eq!(report.imported, vec![(1, 1), (2, 2)]);
+        assert!(events.try_recv().is_err());
+        assert!(q.iter_queued().next().is_none());
+
+        q.import(jobs, false, false);
+        assert_eq!(events.try_recv().unwrap().kind, "imported");
+    }
+
+    #[test]
+    fn imports_with_new_ids_keep_resubmission_references() {
+        let mut other = queue_with(2, "a");
+        assert_eq!(other.schedule(&AppkeyLimits::default()).unwrap().id, 1);
+        other.finish(1, JobState::Terminated(1), "".into(), "".into());
+        assert!(other.resubmit(1, None, None).is_ok());
+        let mut jobs: Vec<Job> = other
+            .iter_queued()
+            .chain(other.iter_finished())
+            .cloned()
+            .collect();
+        jobs[0].resubmitted_from = Some(7);
+
+        let mut q = queue_with(3, "b");
+        let report = q.import(jobs, false, false);
+        assert_eq!(report.imported, vec![(2, 4), (3, 5), (1, 6)]);
+        let resubmitted_from: Vec<Option<u64>> = [4, 5, 6]
+            .iter()
+            .map(|&id| q.get_job(id).unwrap().resubmitted_from)
+            .collect();
+        assert_eq!(resubmitted_from, vec![None, Some(6), None]);
+    }
+
+    fn moved(id: u64, to: QueuePosition) -> Vec<u64> {
+        let mut q = queue_with_running_job();
+        assert!(q.move_job(id, to).is_ok());
+        queued_ids(&q)
+    }
+
+    #[test]
+    fn moving_jobs_to_the_front_keeps_running_jobs_first() {
+        assert_eq!(moved(4, QueuePosition::Top), vec![1, 4, 2, 3, 5]);
+        assert_eq!(moved(4, QueuePosition::Position(1)), vec![1, 4, 2, 3, 5]);
+        assert_eq!(moved(4, QueuePosition::Position(0)), vec![1, 4, 2, 3, 5]);
+        assert_eq!(moved(4, QueuePosition::Before(2)), vec![1, 4, 2, 3, 5]);
+        assert_eq!(moved(2, QueuePosition::Top), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn moving_jobs_before_and_after_others() {
+        assert_eq!(moved(2, QueuePosition::After(5)), vec![1, 3, 4, 5, 2]);
+        assert_eq!(moved(5, QueuePosition::After(2)), vec![1, 2, 5, 3, 4]);
+        assert_eq!(moved(2, QueuePosition::Before(5)), vec![1, 3, 4, 2, 5]);
+        assert_eq!(moved(5, QueuePosition::Before(3)), vec![1, 2, 5, 3, 4]);
+        assert_eq!(moved(3, QueuePosition::After(3)), vec![1, 2, 3, 4, 5]);
+        assert_eq!(moved(3, QueuePosition::Before(3)), vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn moving_jobs_to_a_position() {
+        assert_eq!(moved(2, QueuePosition::Position(2)), vec![1, 3, 2, 4, 5]);
+        assert_eq!(moved(2, QueuePosition::Position(4)), vec![1, 3, 4, 5, 2]);
+        assert_eq!(moved(2, QueuePosition::Position(100)), vec![1, 3, 4, 5, 2]);
+        assert_eq!(moved(5, QueuePosition::Position(3)), vec![1, 2, 3, 5, 4]);
+    }
+
+    #[test]
+    fn moving_jobs_counts_only_jobs_of_the_same_queue() {
+        let mut q = queue_with(2, "a");
+        let other = SubmitOptions {
+            queue: Some("other".to_string()),
+            ..SubmitOptions::default()
+        };
+        q.submit("b".to_string(), other.clone());
+        q.submit("a".to_string(), SubmitOptions::default());
+        q.submit("b".to_string(), other);
+
+        assert!(q.move_job(4, QueuePosition::Position(2)).is_ok());
+        assert_eq!(queued_ids(&q), vec![1, 4, 2, 3, 5]);
+        assert!(q.move_job(5, QueuePosition::Top).is_ok());
+        assert_eq!(queued_ids(&q), vec![1, 4, 2, 5, 3]);
+        assert!(matches!(
+            q.move_job(1, QueuePosition::After(3)),
+            Err(FailReason::NoSuchJob)
+        ));
+    }
+
+    #[test]
+    fn moving_running_or_unknown_jobs_fails() {
+        let mut q = queue_with_running_job();
+        assert!(matches!(
+            q.move_job(1, QueuePosition::Top),
+            Err(FailReason::WrongJobState)
+        ));
+        assert!(matches!(
+            q.move_job(9, QueuePosition::Top),
+            Err(FailReason::NoSuchJob)
+        ));
+        assert!(matches!(
+            q.move_job(2, QueuePosition::Before(9)),
+            Err(FailReason::NoSuchJob)
+        ));
+        assert_eq!(queued_ids(&q), vec![1, 2, 3, 4, 5]);
+    }
+
+    /// Returns a queue of jobs that need the given CPUs, in the given queues,
+    /// on a host with 8 CPUs and 16G of memory
+    fn queue_needing(jobs: &[(u32, &str)]) -> JobQueue {
+        let mut q = JobQueue::new(0);
+        let mut slots = BTreeMap::new();
+        slots.insert(DEFAULT_QUEUE.to_string(), 10);
+        slots.insert("other".to_string(), 10);
+        q.set_slots(slots);
+        q.set_resources(Some(Resources {
+            cpus: 8,
+            mem: 16 << 30,
+        }));
+        for &(cpus, queue) in jobs {
+            let options = SubmitOptions {
+                queue: Some(queue.to_string()),
+                cpus: Some(cpus),
+                ..SubmitOptions::default()
+            };
+            q.submit("a".to_string(), options);
+        }
+        q
+    }
+
+    fn started(q: &mut JobQueue) -> Vec<u64> {
+        let limits = AppkeyLimits::default();
+        std::iter::from_fn(|| q.schedule(&limits).map(|j| j.id)).collect()
+    }
+
+    #[test]
+    fn jobs_are_started_while_they_fit() {
+        let mut q = queue_needing(&[
+            (4, "default"),
+            (2, "default"),
+            (2, "default"),
+            (1, "default"),
+        ]);
+        assert_eq!(started(&mut q), vec![1, 2, 3]);
+        assert_eq!(q.resource_usage().unwrap().allocated.cpus, 8);
+
+        q.finish(2, JobState::Terminated(0), String::new(), String::new());
+        assert_eq!(started(&mut q), vec![4]);
+    }
+
+    #[test]
+    fn jobs_that_do_not_fit_hold_back_their_queue() {
+        let mut q = queue_needing(&[(4, "default"), (8, "default"), (1, "default"), (1, "other")]);
+        assert_eq!(started(&mut q), vec![1, 4]);
+
+        q.finish(1, JobState::Terminated(0), String::new(), String::new());
+        q.finish(4, JobState::Terminated(0), String::new(), String::new());
+        assert_eq!(started(&mut q), vec![2]);
+    }
 
-        Err(FailReason::NoSuchJob)
+    #[test]
+    fn jobs_that_never_fit_do_not_hold_back_their_queue() {
+        let mut q = queue_needing(&[(16, "default"), (1, "default")]);
+        assert_eq!(started(&mut q), vec![2]);
+        assert_eq!(queued_ids(&q), vec![1, 2]);
     }
 }
@@ -0,0 +1,326 @@
+/// job_runner.rs
+///
+/// Launches jobs so that they survive a restart of the daemon.
+///
+/// Jobs are not started by the daemon directly, but by a small wrapper
+/// (`qmanager run-job`) that runs in its own session. The wrapper redirects
+/// stdout and stderr of the job to files in the spool directory, waits for
+/// the job and writes its exit status to a status file. If the daemon is
+/// restarted while a job is running, the new daemon finds the wrapper by its
+/// PID and command line in /proc and waits for the status file to appear.
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Result, SeekFrom};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use job_queue::JobState;
+
+/// Default directory for job output and status files
+pub const DEFAULT_SPOOL_DIR: &str = "/var/lib/qmanager/jobs";
+
+/// Time between two checks whether a reattached job is still alive
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time between two checks for new output of a running job
+const OUTPUT_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The files a job's wrapper writes to
+#[derive(Clone)]
+pub struct JobFiles {
+    pub stdout: PathBuf,
+    pub stderr: PathBuf,
+    pub status: PathBuf,
+}
+
+impl JobFiles {
+    /// Returns the files for the job with the given ID
+    pub fn new(spool_dir: &Path, id: u64) -> Self {
+        JobFiles {
+            stdout: spool_dir.join(format!("{}.stdout", id)),
+            stderr: spool_dir.join(format!("{}.stderr", id)),
+            status: spool_dir.join(format!("{}.status", id)),
+        }
+    }
+
+    /// Removes all files, ignoring those that do not exist
+    fn remove(&self) {
+        for f in &[&self.stdout, &self.stderr, &self.status] {
+            let _ = fs::remove_file(f);
+        }
+    }
+}
+
+/// Body of the `run-job` wrapper. Detaches from the daemon's session, runs
+/// the given command with its output redirected and writes its exit status.
+/// The wrapper itself ignores SIGTERM, SIGINT and SIGHUP, so that signals
+/// sent to the process group only reach the job.
+pub fn run_wrapper(files: &JobFiles, command: &[String]) -> Result<()> {
+    if command.is_empty() {
+        return Err(Error::from(ErrorKind::InvalidInput));
+    }
+
+    // Fails if we are a process group leader already, which is fine
+    let _ = nix::unistd::setsid();
+
+    let ignored = Arc::new(AtomicBool::new(false));
+    for signal in &[
+        signal_hook::SIGTERM,
+        signal_hook::SIGINT,
+        signal_hook::SIGHUP,
+    ] {
+        signal_hook::flag::register(*signal, Arc::clone(&ignored))?;
+    }
+
+    let status = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(File::create(&files.stdout)?)
+        .stderr(File::create(&files.stderr)?)
+        .status();
+
+    let status = match status {
+        Ok(s) => match s.signal() {
+            Some(signum) => format!("signal {}", signum),
+            None => format!("exit {}", s.code().unwrap_or(-1)),
+        },
+        Err(e) => format!("failed {}", e),
+    };
+
+    // Write to a temporary file first, so that the daemon never reads a partial status
+    let mut tmp_file = files.status.clone().into_os_string();
+    tmp_file.push(".tmp");
+    fs::write(&tmp_file, status)?;
+    fs::rename(&tmp_file, &files.status)
+}
+
+/// Launches jobs through the `run-job` wrapper, which is this executable
+#[derive(Clone)]
+pub struct Launcher {
+    /// Directory for job output and status files
+    spool_dir: PathBuf,
+
+    /// Path of the executable, resolved once on startup. Once the binary has
+    /// been replaced, i.e. by an upgrade, `current_exe` refers to the deleted
+    /// file, while this path leads to the new one.
+    wrapper: PathBuf,
+}
+
+impl Launcher {
+    /// Uses the given spool directory and the path this executable has been
+    /// started from
+    pub fn new(spool_dir: PathBuf) -> Result<Self> {
+        let wrapper = std::env::current_exe()?.canonicalize()?;
+        debug!("Jobs are run by {}", wrapper.to_str().unwrap());
+        Ok(Launcher { spool_dir, wrapper })
+    }
+
+    /// Returns the files for the job with the given ID
+    pub fn files(&self, id: u64) -> JobFiles {
+        JobFiles::new(&self.spool_dir, id)
+    }
+
+    /// Launches the given shell command line through the wrapper. The PID
+    /// of the returned child is the one of the wrapper, which is also the
+    /// process group ID of the job.
+    pub fn launch(&self, files: &JobFiles, cmdline: &str) -> Result<Child> {
+        files.remove();
+
+        Command::new(&self.wrapper)
+            .arg("run-job")
+            .arg("--stdout-file")
+            .arg(&files.stdout)
+            .arg("--stderr-file")
+            .arg(&files.stderr)
+            .arg("--status-file")
+            .arg(&files.status)
+            .arg("--")
+            .arg("sh")
+            .arg("-c")
+            .arg(cmdline)
+            .current_dir("/")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+    }
+}
+
+/// Checks whether the wrapper for the given job files is still running
+/// with the given PID. The command line is compared to rule out that the
+/// PID has been reused by another process.
+pub fn is_alive(pid: u32, files: &JobFiles) -> bool {
+    let status = files.status.as_os_str().to_string_lossy().into_owned();
+    match fs::read(format!("/proc/{}/cmdline", pid)) {
+        Ok(cmdline) => cmdline
+            .split(|b| *b == 0)
+            .any(|arg| String::from_utf8_lossy(arg) == status),
+        Err(_) => false,
+    }
+}
+
+/// Checks whether the wrapper for the given job files has left a status
+pub fn has_status(files: &JobFiles) -> bool {
+    files.status.exists()
+}
+
+/// Checks whether a job left running by a previous daemon can be reattached
+/// to: its wrapper is still running or has left an exit status
+pub fn is_detached(pid: Option<u32>, files: &JobFiles) -> bool {
+    has_status(files) || pid.is_some_and(|pid| is_alive(pid, files))
+}
+
+/// Waits for a wrapper that is not a child of this process to terminate
+pub fn wait_detached(pid: u32, files: &JobFiles) {
+    while is_alive(pid, files) {
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Calls `f` with the name of the stream (`stdout` or `stderr`) and the new
+/// output whenever the output files of a running job grow. Returns once
+/// `done` is set and the remaining output has been passed on.
+pub fn follow_output<F: FnMut(&str, String)>(files: &JobFiles, done: &AtomicBool, mut f: F) {
+    let mut offsets = [0u64; 2];
+    loop {
+        let finished = done.load(Ordering::SeqCst);
+
+        for (i, (stream, path)) in [("stdout", &files.stdout), ("stderr", &files.stderr)]
+            .iter()
+            .enumerate()
+        {
+            let mut buf = Vec::new();
+            let read = File::open(path).and_then(|mut file| {
+                file.seek(SeekFrom::Start(offsets[i]))?;
+                file.read_to_end(&mut buf)
+            });
+            if let Ok(n) = read {
+                if n > 0 {
+                    offsets[i] += n as u64;
+                    f(stream, String::from_utf8_lossy(&buf).into_owned());
+                }
+            }
+        }
+
+        if finished {
+            return;
+        }
+        thread::sleep(OUTPUT_POLL_INTERVAL);
+    }
+}
+
+/// Reads the outcome of a terminated job and removes its files.
+/// Returns the job state, stdout and stderr.
+pub fn collect(files: &JobFiles) -> (JobState, String, String) {
+    let state = match fs::read_to_string(&files.status) {
+        Ok(s) => parse_status(s.trim()),
+        Err(e) => JobState::Failed(format!("Job wrapper left no exit status: {}", e)),
+    };
+    let stdout = fs::read(&files.stdout).unwrap_or_default();
+    let stderr = fs::read(&files.stderr).unwrap_or_default();
+    files.remove();
+
+    (
+        state,
+        String::from_utf8_lossy(&stdout).to_string(),
+        String::from_utf8_lossy(&stderr).to_string(),
+    )
+}
+
+/// Parses the contents of a status file as written by `run_wrapper`
+fn parse_status(s: &str) -> JobState {
+    let mut parts = s.splitn(2, ' ');
+    let kind = parts.next().unwrap_or("");
+    let value = parts.next().unwrap_or("");
+    match (kind, value.parse()) {
+        ("exit", Ok(code)) => JobState::Terminated(code),
+        ("signal", Ok(signum)) => JobState::Killed(signum),
+        ("failed", _) => JobState::Failed(value.to_owned()),
+        _ => JobState::Failed(format!("Invalid job status '{}'", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Instant;
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("qmanager-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parsing_wrapper_status() {
+        assert_eq!(parse_status("exit 0"), JobState::Terminated(0));
+        assert_eq!(parse_status("exit 3"), JobState::Terminated(3));
+        assert_eq!(parse_status("signal 9"), JobState::Killed(9));
+        assert_eq!(
+            parse_status("failed No such file or directory"),
+            JobState::Failed("No such file or directory".to_string())
+        );
+        for invalid in &["", "exit", "exit x", "signal", "done 0"] {
+            match parse_status(invalid) {
+                JobState::Failed(message) => assert!(message.starts_with("Invalid")),
+                state => panic!("{:?} parsed as {:?}", invalid, state),
+            }
+        }
+    }
+
+    #[test]
+    fn reattaching_to_jobs_of_a_previous_daemon() {
+        let files = JobFiles::new(&spool_dir("reattach"), 1);
+
+        // a process that carries the status file on its command line, like
+        // the wrapper does
+        let mut wrapper = Command::new("sh")
+            .arg("-c")
+            .arg("sleep 10")
+            .arg(&files.status)
+            .spawn()
+            .unwrap();
+        let pid = wrapper.id();
+        // the command line only changes once the child has exec'd
+        let start = Instant::now();
+        while !is_detached(Some(pid), &files) {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!is_detached(None, &files));
+        // a reused PID belongs to a process with another command line
+        assert!(!is_detached(Some(std::process::id()), &files));
+
+        wrapper.kill().unwrap();
+        wrapper.wait().unwrap();
+        assert!(!is_detached(Some(pid), &files));
+
+        // the wrapper has terminated while no daemon was running
+        fs::write(&files.stdout, "out").unwrap();
+        fs::write(&files.status, "exit 3").unwrap();
+        assert!(is_detached(Some(pid), &files));
+        assert!(is_detached(None, &files));
+
+        let (state, stdout, stderr) = collect(&files);
+        assert_eq!(state, JobState::Terminated(3));
+        assert_eq!((stdout.as_str(), stderr.as_str()), ("out", ""));
+        assert!(!has_status(&files) && !files.stdout.exists());
+    }
+
+    #[test]
+    fn jobs_without_status_have_failed() {
+        let files = JobFiles::new(&spool_dir("no-status"), 1);
+        match collect(&files).0 {
+            JobState::Failed(message) => assert!(message.contains("no exit status")),
+            state => panic!("{:?}", state),
+        }
+    }
+}
@@ -0,0 +1,198 @@
+/// limits.rs
+///
+/// Limits on running jobs per appkey.
+///
+/// An entry of `[appkeys]` is either the path of the executable or a table
+/// that also limits the jobs of the appkey:
+///
+/// - `max-concurrent`: at most this many jobs of the appkey run at the same
+///   time, across all queues
+/// - `mutex-groups`: names of groups of appkeys of which only a single job
+///   runs at a time, i.e. appkeys that write to the same database
+/// - `cpus`, `mem`: the resources that jobs of the appkey need unless they
+///   declare them on submission (see `resources.rs`)
+///
+/// The scheduler skips jobs whose appkey is at its limit and starts the next
+/// job of the queue instead (see `JobQueue::schedule`). Limits are changed
+/// by reloading the config file, like the appkeys themselves.
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use config::Value;
+
+use job_queue::Job;
+use resources::parse_mem;
+
+/// Limits of a single appkey
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppkeyLimit {
+    /// Number of jobs of the appkey that may run at the same time, None for
+    /// no limit
+    pub max_concurrent: Option<usize>,
+
+    /// Groups of appkeys of which only a single job runs at a time
+    pub mutex_groups: Vec<String>,
+
+    /// CPUs and bytes of memory that jobs of the appkey need by default
+    pub cpus: Option<u32>,
+    pub mem: Option<u64>,
+}
+
+/// Reads an entry of `[appkeys]`: the path of the executable and the limits
+/// of the appkey, if given as a table
+pub fn parse_appkey(value: Value) -> Result<(PathBuf, AppkeyLimit), String> {
+    let mut table = match value.clone().into_table() {
+        Ok(table) => table,
+        Err(_) => {
+            let path = value.into_str().map_err(|e| e.to_string())?;
+            return Ok((PathBuf::from(path), AppkeyLimit::default()));
+        }
+    };
+
+    let path = table
+        .remove("path")
+        .ok_or("path is missing")?
+        .into_str()
+        .map_err(|e| format!("path: {}", e))?;
+
+    let mut limit = AppkeyLimit::default();
+    if let Some(max) = table.remove("max-concurrent") {
+        limit.max_concurrent = match max.into_int() {
+            Ok(n) if n >= 1 => Some(n as usize),
+            _ => return Err("max-concurrent must be at least 1".to_string()),
+        };
+    }
+    if let Some(groups) = table.remove("mutex-groups") {
+        limit.mutex_groups = groups
+            .into_array()
+            .and_then(|values| values.into_iter().map(Value::into_str).collect())
+            .map_err(|e| format!("mutex-groups: {}", e))?;
+    }
+    if let Some(cpus) = table.remove("cpus") {
+        limit.cpus = match cpus.into_int() {
+            Ok(n) if n >= 1 => Some(n as u32),
+            _ => return Err("cpus must be at least 1".to_string()),
+        };
+    }
+    if let Some(mem) = table.remove("mem") {
+        let mem = mem.into_str().map_err(|e| e.to_string())?;
+        limit.mem = Some(parse_mem(&mem).map_err(|e| format!("mem: {}", e))?);
+    }
+
+    Ok((PathBuf::from(path), limit))
+}
+
+/// Limits of all appkeys that have any
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AppkeyLimits {
+    limits: HashMap<String, AppkeyLimit>,
+}
+
+impl AppkeyLimits {
+    /// Sets the limits of an appkey
+    pub fn insert(&mut self, appkey: String, limit: AppkeyLimit) {
+        if limit == AppkeyLimit::default() {
+            self.limits.remove(&appkey);
+        } else {
+            self.limits.insert(appkey, limit);
+        }
+    }
+
+    /// Checks whether a job with the given appkey may be started next to the
+    /// running jobs
+    pub fn allows<'a, I: Iterator<Item = &'a Job>>(&self, appkey: &str, running: I) -> bool {
+        let limit = match self.limits.get(appkey) {
+            Some(limit) => limit,
+            None => return true,
+        };
+
+        let mut same_appkey = 0;
+        for job in running {
+            if job.appkey() == appkey {
+                same_appkey += 1;
+            }
+            let excludes = self.limits.get(job.appkey()).is_some_and(|other| {
+                other
+                    .mutex_groups
+                    .iter()
+                    .any(|group| limit.mutex_groups.contains(group))
+            });
+            if excludes {
+                return false;
+            }
+        }
+        limit.max_concurrent.is_none_or(|max| same_appkey < max)
+    }
+
+    /// Returns the CPUs and memory that jobs of the appkey need by default
+    pub fn default_needs(&self, appkey: &str) -> (Option<u32>, Option<u64>) {
+        self.limits
+            .get(appkey)
+            .map_or((None, None), |limit| (limit.cpus, limit.mem))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use job_queue::{JobQueue, SubmitOptions};
+
+    /// Returns jobs with the given appkeys
+    fn jobs(appkeys: &[&str]) -> Vec<Job> {
+        let mut q = JobQueue::new(0);
+        for appkey in appkeys {
+            q.submit(appkey.to_string(), SubmitOptions::default());
+        }
+        q.iter_queued().cloned().collect()
+    }
+
+    fn limits(entries: &[(&str, Option<usize>, &[&str])]) -> AppkeyLimits {
+        let mut limits = AppkeyLimits::default();
+        for &(appkey, max_concurrent, groups) in entries {
+            let limit = AppkeyLimit {
+                max_concurrent,
+                mutex_groups: groups.iter().map(|g| g.to_string()).collect(),
+                ..AppkeyLimit::default()
+            };
+            limits.insert(appkey.to_string(), limit);
+        }
+        limits
+    }
+
+    #[test]
+    fn appkeys_without_limits_are_always_allowed() {
+        let limits = limits(&[("load", Some(1), &["db"])]);
+        assert!(limits.allows("other", jobs(&["other", "load"]).iter()));
+        assert!(AppkeyLimits::default().allows("load", jobs(&["load"]).iter()));
+    }
+
+    #[test]
+    fn max_concurrent_counts_jobs_of_the_same_appkey() {
+        let limits = limits(&[("load", Some(2), &[])]);
+        assert!(limits.allows("load", jobs(&[]).iter()));
+        assert!(limits.allows("load", jobs(&["load", "other"]).iter()));
+        assert!(!limits.allows("load", jobs(&["load", "other", "load"]).iter()));
+    }
+
+    #[test]
+    fn mutex_groups_exclude_all_appkeys_of_the_group() {
+        let limits = limits(&[
+            ("load", None, &["db"]),
+            ("dump", None, &["db", "backup"]),
+            ("sync", None, &["backup"]),
+        ]);
+        assert!(limits.allows("load", jobs(&["other"]).iter()));
+        assert!(!limits.allows("load", jobs(&["load"]).iter()));
+        assert!(!limits.allows("load", jobs(&["dump"]).iter()));
+        assert!(limits.allows("load", jobs(&["sync"]).iter()));
+        assert!(!limits.allows("sync", jobs(&["dump"]).iter()));
+    }
+
+    #[test]
+    fn limits_equal_to_the_default_are_not_kept() {
+        let mut limits = limits(&[("load", Some(1), &[])]);
+        limits.insert("load".to_string(), AppkeyLimit::default());
+        assert_eq!(limits, AppkeyLimits::default());
+    }
+}
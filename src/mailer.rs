@@ -0,0 +1,345 @@
+/// mailer.rs
+///
+/// Sends a mail when a job has ended.
+///
+/// Configured in the `[mail]` section of the config file, mails are handed
+/// to a local sendmail binary or to an SMTP server. The recipient is the
+/// address given on submission (`submit --mail-to`) or, if `owner-domain` is
+/// set, derived from the job's owner. Mails are only sent to the addresses
+/// and domains that `allowed-recipients` and `allowed-domains` list, see
+/// `Mailer::allows`. Subject and body are templates with `{placeholders}` for
+/// the job's properties, see `Mailer::render`. A body file is read whenever
+/// a mail is composed.
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+
+use config::{Config, ConfigError};
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SendmailTransport, SmtpTransport, Transport};
+
+use job_queue::{Job, JobEvent, JobState, DEFAULT_JOB_EVENTS};
+use notifier::format_time;
+use template;
+
+/// Default subject template
+pub const DEFAULT_SUBJECT: &str = "qmanager: job {id} ({appkey}) {state}";
+
+/// Default body template
+pub const DEFAULT_BODY: &str = "Job {id} has {status}.
+
+Command line: {cmdline}
+Submitted:    {scheduled}
+Started:      {started}
+Finished:     {finished}
+Duration:     {duration}
+
+Last lines of stderr:
+{stderr_tail}
+";
+
+/// Default number of stderr lines included in mails
+pub const DEFAULT_STDERR_TAIL_LINES: usize = 20;
+
+/// How mails are delivered
+#[derive(Debug, Clone)]
+pub enum MailTransport {
+    /// Pipe to the given sendmail binary
+    Sendmail(PathBuf),
+
+    /// Send to an SMTP server
+    Smtp {
+        host: String,
+        port: u16,
+        starttls: bool,
+        credentials: Option<(String, String)>,
+    },
+}
+
+/// The body template of mails
+#[derive(Debug, Clone)]
+pub enum MailBody {
+    /// Given in the config file, or the default one
+    Template(String),
+
+    /// Read from this file for each mail
+    File(PathBuf),
+}
+
+/// Mail settings, read from the `[mail]` section
+#[derive(Debug, Clone)]
+pub struct Mailer {
+    pub transport: MailTransport,
+
+    /// Sender address
+    pub from: Mailbox,
+
+    /// Domain appended to the owner's name if no address was given
+    pub owner_domain: Option<String>,
+
+    /// Domains that mails may be sent to, by default the owner domain
+    pub allowed_domains: Vec<String>,
+
+    /// Further addresses that mails may be sent to
+    pub allowed_recipients: Vec<String>,
+
+    /// Events that a mail is sent for
+    pub events: Vec<JobEvent>,
+
+    pub subject: String,
+    pub body: MailBody,
+
+    /// Number of stderr lines in `{stderr_tail}`
+    pub stderr_tail_lines: usize,
+}
+
+impl Mailer {
+    /// Reads the mail settings from the config file. Returns None if neither
+    /// `mail.sendmail` nor `mail.smtp-host` is set.
+    pub fn from_config(conf: &Config) -> Result<Option<Self>, String> {
+        let sendmail = conf.get_str("mail.sendmail").ok();
+        let smtp_host = conf.get_str("mail.smtp-host").ok();
+
+        let transport = match (sendmail, smtp_host) {
+            (None, None) => return Ok(None),
+            (Some(_), Some(_)) => {
+                return Err("Set either mail.sendmail or mail.smtp-host, not both".to_owned())
+            }
+            (Some(path), None) => MailTransport::Sendmail(PathBuf::from(path)),
+            (None, Some(host)) => MailTransport::Smtp {
+                host,
+                port: conf.get_int("mail.smtp-port").unwrap_or(25) as u16,
+                starttls: conf.get_bool("mail.smtp-starttls").unwrap_or(false),
+                credentials: match (
+                    conf.get_str("mail.smtp-user"),
+                    conf.get_str("mail.smtp-password"),
+                ) {
+                    (Ok(user), Ok(password)) => Some((user, password)),
+                    _ => None,
+                },
+            },
+        };
+
+        let from = conf
+            .get_str("mail.from")
+            .map_err(|_| "mail.from is required".to_owned())?
+            .parse()
+            .map_err(|e| format!("mail.from: {}", e))?;
+
+        let events = match conf.get_array("mail.events") {
+            Ok(events) => events
+                .into_iter()
+                .map(|e| e.into_str().map_err(|e| e.to_string())?.parse())
+                .collect::<Result<Vec<JobEvent>, String>>()
+                .map_err(|e| format!("mail.events: {}", e))?,
+            Err(_) => DEFAULT_JOB_EVENTS.to_vec(),
+        };
+
+        let body = match conf.get_str("mail.body-file") {
+            Ok(path) => MailBody::File(PathBuf::from(path)),
+            Err(_) => MailBody::Template(
+                conf.get_str("mail.body")
+                    .unwrap_or_else(|_| DEFAULT_BODY.to_owned()),
+            ),
+        };
+
+        let strings = |key: &str| match conf.get_array(key) {
+            Ok(values) => values
+                .into_iter()
+                .map(|v| v.into_str().map(|s| s.to_lowercase()))
+                .collect::<Result<Vec<String>, _>>()
+                .map(Some)
+                .map_err(|e| format!("{}: {}", key, e)),
+            Err(_) => Ok(None),
+        };
+        let stderr_tail_lines = match conf.get_int("mail.stderr-tail-lines") {
+            Ok(n) if n < 0 => return Err("mail.stderr-tail-lines: must not be negative".to_owned()),
+            Ok(n) => n as usize,
+            Err(ConfigError::NotFound(_)) => DEFAULT_STDERR_TAIL_LINES,
+            Err(e) => return Err(format!("mail.stderr-tail-lines: {}", e)),
+        };
+
+        let owner_domain = conf.get_str("mail.owner-domain").ok();
+        let allowed_domains = strings("mail.allowed-domains")?
+            .unwrap_or_else(|| owner_domain.iter().map(|d| d.to_lowercase()).collect());
+
+        Ok(Some(Mailer {
+            transport,
+            from,
+            owner_domain,
+            allowed_domains,
+            allowed_recipients: strings("mail.allowed-recipients")?.unwrap_or_default(),
+            events,
+            subject: conf
+                .get_str("mail.subject")
+                .unwrap_or_else(|_| DEFAULT_SUBJECT.to_owned()),
+            body,
+            stderr_tail_lines,
+        }))
+    }
+
+    /// Checks whether mails may be sent to the given address: it must be
+    /// listed in `allowed-recipients` or its domain in `allowed-domains`
+    pub fn allows(&self, address: &str) -> bool {
+        let address = address.trim().to_lowercase();
+        let domain = match address.rfind('@') {
+            Some(at) => &address[at + 1..],
+            None => return false,
+        };
+        self.allowed_recipients.contains(&address)
+            || self.allowed_domains.iter().any(|d| d == domain)
+    }
+
+    /// Returns the address a mail on the given job is sent to, if any
+    fn recipient(&self, job: &Job) -> Option<String> {
+        let to = match (&job.mail_to, &job.owner, &self.owner_domain) {
+            (Some(to), _, _) => to.clone(),
+            (None, Some(owner), _) if owner.contains('@') => owner.clone(),
+            (None, Some(owner), Some(domain)) => format!("{}@{}", owner, domain),
+            _ => return None,
+        };
+        if !self.allows(&to) {
+            warn!(
+                "[mailer] Not sending mail on job {} to {}, the address is not allowed",
+                job.id, to
+            );
+            return None;
+        }
+        Some(to)
+    }
+
+    /// Replaces the placeholders in the given template: `{id}`, `{cmdline}`,
+    /// `{appkey}`, `{owner}`, `{state}`, `{status}`, `{exit_code}`,
+    /// `{scheduled}`, `{started}`, `{finished}`, `{duration}` and `{stderr_tail}`
+    pub fn render(&self, template: &str, job: &Job) -> String {
+        let time = |t: Option<std::time::SystemTime>| t.map(format_time).unwrap_or_default();
+
+        let status = match job.state {
+            JobState::Terminated(0) => "finished successfully".to_owned(),
+            JobState::Terminated(code) => format!("failed with exit code {}", code),
+            JobState::Killed(signum) => format!("been killed by signal {}", signum),
+            JobState::Failed(ref reason) => format!("failed: {}", reason),
+            JobState::Queued | JobState::Held | JobState::Running => "not ended yet".to_owned(),
+        };
+        let exit_code = match job.state {
+            JobState::Terminated(code) => code.to_string(),
+            _ => String::new(),
+        };
+        let duration = match (job.started, job.finished) {
+            (Some(started), Some(finished)) => finished
+                .duration_since(started)
+                .map(|d| {
+                    humantime::format_duration(std::time::Duration::from_secs(d.as_secs()))
+                        .to_string()
+                })
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        let stderr_lines: Vec<&str> = job.stderr.lines().collect();
+        let stderr_tail =
+            stderr_lines[stderr_lines.len().saturating_sub(self.stderr_tail_lines)..].join("\n");
+
+        let mut values = HashMap::new();
+        values.insert("id", job.id.to_string());
+        values.insert("cmdline", job.cmdline.clone());
+        values.insert("appkey", job.appkey().to_owned());
+        values.insert("owner", job.owner.clone().unwrap_or_default());
+        values.insert("state", job.state.name().to_owned());
+        values.insert("status", status);
+        values.insert("exit_code", exit_code);
+        values.insert("scheduled", format_time(job.scheduled));
+        values.insert("started", time(job.started));
+        values.insert("finished", time(job.finished));
+        values.insert("duration", duration);
+        values.insert("stderr_tail", stderr_tail);
+
+        template::expand(template, &values)
+    }
+
+    /// Builds the mail on the given job
+    fn message(&self, job: &Job, to: &str) -> Result<Message, String> {
+        let to: Mailbox = to
+            .parse()
+            .map_err(|e| format!("Invalid address {}: {}", to, e))?;
+        let body = match self.body {
+            MailBody::Template(ref body) => body.clone(),
+            MailBody::File(ref path) => fs::read_to_string(path)
+                .map_err(|e| format!("Could not read {}: {}", path.to_string_lossy(), e))?,
+        };
+        Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(self.render(&self.subject, job))
+            .header(ContentType::TEXT_PLAIN)
+            .body(self.render(&body, job))
+            .map_err(|e| e.to_string())
+    }
+
+    /// Delivers a mail through the configured transport
+    fn send(&self, message: &Message) -> Result<(), String> {
+        match self.transport {
+            MailTransport::Sendmail(ref path) => SendmailTransport::new_with_command(path)
+                .send(message)
+                .map_err(|e| e.to_string()),
+            MailTransport::Smtp {
+                ref host,
+                port,
+                starttls,
+                ref credentials,
+            } => {
+                let mut builder = if starttls {
+                    SmtpTransport::starttls_relay(host).map_err(|e| e.to_string())?
+                } else {
+                    SmtpTransport::builder_dangerous(host)
+                }
+                .port(port);
+                if let Some((ref user, ref password)) = *credentials {
+                    builder = builder.credentials(Credentials::new(user.clone(), password.clone()));
+                }
+                builder
+                    .build()
+                    .send(message)
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Sends a mail on the given event of a job in the background, if the
+    /// event is selected and a recipient is known
+    pub fn notify(&self, job: &Job, event: JobEvent) {
+        if !self.events.contains(&event) {
+            return;
+        }
+        let to = match self.recipient(job) {
+            Some(to) => to,
+            None => return,
+        };
+
+        let id = job.id;
+        let message = match self.message(job, &to) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("[mailer] Could not compose mail on job {}: {}", id, e);
+                return;
+            }
+        };
+
+        let mailer = self.clone();
+        let spawned = thread::Builder::new()
+            .name("Mailer".to_owned())
+            .spawn(move || match mailer.send(&message) {
+                Ok(()) => info!("[mailer] Sent mail on job {} to {}", id, to),
+                Err(e) => error!(
+                    "[mailer] Could not send mail on job {} to {}: {}",
+                    id, to, e
+                ),
+            });
+        if let Err(e) = spawned {
+            error!("[mailer] Could not start sending mail on job {}: {}", id, e);
+        }
+    }
+}
@@ -5,12 +5,19 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 extern crate config;
+extern crate crossterm;
 extern crate daemonize;
+extern crate flate2;
+extern crate hex;
+extern crate hmac;
 extern crate humantime;
+extern crate lettre;
 extern crate nix;
 extern crate reqwest;
+extern crate rusqlite;
 extern crate serde;
 extern crate serde_json;
+extern crate sha2;
 extern crate signal_hook;
 extern crate simplelog;
 extern crate structopt;
@@ -21,9 +28,26 @@ extern crate tiny_http;
 mod clicommands;
 mod cliopts;
 mod daemon;
+mod events;
+mod hooks;
 mod job_queue;
+mod job_runner;
+mod limits;
+mod mailer;
+mod notifier;
+mod output;
 mod protocol;
+mod queues;
+mod resources;
+mod rest;
+mod retention;
+mod settings;
+mod shutdown;
 mod state;
+mod storage;
+mod table;
+mod template;
+mod top;
 
 use std::fs::File;
 use std::io::prelude::*;
@@ -32,7 +56,11 @@ use std::path::PathBuf;
 use std::str::FromStr;
 
 use cliopts::*;
-use job_queue::QueueState;
+use job_queue::{
+    JobChanges, JobNotify, QueuePosition, QueueState, SubmitOptions, DEFAULT_JOB_EVENTS,
+};
+use output::OutputFormat;
+use settings::Settings;
 use state::State;
 
 use reqwest::{Client, Url};
@@ -48,57 +76,100 @@ fn slurp_file(filename: &PathBuf) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
-/// Loads SSL certificates, if any, and sets up corresponding Client and Url objects
-fn create_client(
-    insecure: bool,
-    ca: Option<PathBuf>,
-    host: &str,
-    port: u16,
-) -> Result<(Client, Url)> {
-    let client = if insecure {
-        reqwest::Client::new()
+/// Loads SSL certificates, if any, and sets up a corresponding ClientBuilder
+fn client_builder(insecure: bool, ca: Option<PathBuf>) -> Result<reqwest::ClientBuilder> {
+    if insecure {
+        Ok(reqwest::Client::builder())
     } else {
         let mut buf = Vec::new();
         File::open(ca.unwrap())?.read_to_end(&mut buf).unwrap();
         let pkcs12 = reqwest::Certificate::from_pem(&buf).unwrap();
-        reqwest::Client::builder()
+        Ok(reqwest::Client::builder()
             .add_root_certificate(pkcs12)
             .danger_accept_invalid_certs(true)
-            .danger_accept_invalid_hostnames(true)
-            .build()
-            .unwrap()
-    };
+            .danger_accept_invalid_hostnames(true))
+    }
+}
 
-    let url = reqwest::Url::parse(&format!("http://{}:{}/", host, port)).unwrap();
+fn server_url(host: &str, port: u16) -> Url {
+    reqwest::Url::parse(&format!("http://{}:{}/", host, port)).unwrap()
+}
 
-    Ok((client, url))
+/// Loads SSL certificates, if any, and sets up corresponding Client and Url objects
+fn create_client(
+    insecure: bool,
+    ca: Option<PathBuf>,
+    host: &str,
+    port: u16,
+) -> Result<(Client, Url)> {
+    let client = client_builder(insecure, ca)?.build().unwrap();
+    Ok((client, server_url(host, port)))
+}
+
+/// Like `create_client`, but without a timeout, for long-lived streams
+fn create_streaming_client(
+    insecure: bool,
+    ca: Option<PathBuf>,
+    host: &str,
+    port: u16,
+) -> Result<(Client, Url)> {
+    let client = client_builder(insecure, ca)?.timeout(None).build().unwrap();
+    Ok((client, server_url(host, port)))
 }
 
 fn main() -> Result<()> {
     // Load command line args add config defaults for those not specified
     let mut opt = Opt::from_args();
+
+    // The job wrapper is started by the daemon and needs no configuration
+    if let OptCommand::RunJob {
+        stdout_file,
+        stderr_file,
+        status_file,
+        command,
+    } = opt.cmd
+    {
+        let files = job_runner::JobFiles {
+            stdout: stdout_file,
+            stderr: stderr_file,
+            status: status_file,
+        };
+        return job_runner::run_wrapper(&files, &command);
+    }
+
+    // The daemon leaves its working directory, but reads the config file
+    // again on reload
+    if let Ok(config_file) = opt.config.canonicalize() {
+        opt.config = config_file;
+    }
+
     let mut config = config::Config::default();
-    config
-        .merge(config::File::new(
-            opt.config.to_str().unwrap(),
-            config::FileFormat::Toml,
-        ))
-        .expect("Failed to read configuration file!");
-    opt.merge_config(config);
+    if let Err(e) = config.merge(config::File::new(
+        opt.config.to_str().unwrap(),
+        config::FileFormat::Toml,
+    )) {
+        eprintln!("Failed to read configuration file: {}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = opt.merge_config(config) {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    }
 
     // Check general option usefulness
     opt.verify()?;
 
     // Do mode-specific checking and set up logging
+    let mut settings = None;
     if let OptCommand::Daemon { .. } = &opt.cmd {
-        // Check if appkey executables are actually existing
-        for (k, v) in &opt.appkeys {
-            if !v.exists() {
-                error!("Appkey '{}' points to non-existent file '{:#?}'", k, v);
-            }
+        let s = Settings::from_opt(&opt).unwrap_or_else(|e| {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        });
 
-            debug!("Registered appkey '{}' => '{:#?}'", k, v);
-        }
+        // Check if appkey executables are actually existing
+        s.check_appkeys();
+        settings = Some(s);
 
         // Set up syslog daemon
         syslog::init(
@@ -130,8 +201,8 @@ fn main() -> Result<()> {
         }
     }
 
-    // Set up program state configuration file
-    let state = State::from(opt.state_file.unwrap());
+    // Program state location, only opened by the commands that need it
+    let state_file = opt.state_file.unwrap();
 
     // Handle subcommands
     match opt.cmd {
@@ -140,10 +211,11 @@ fn main() -> Result<()> {
             key,
             pidfile,
             foreground,
-            notify_url,
+            ..
         } => {
-            let cert = cert.and_then(|s| Some(slurp_file(&s))).transpose()?;
-            let key = key.and_then(|s| Some(slurp_file(&s))).transpose()?;
+            let cert = cert.map(|s| slurp_file(&s)).transpose()?;
+            let key = key.map(|s| slurp_file(&s)).transpose()?;
+            let state = State::open(&opt.storage, state_file, opt.journal_compact_after)?;
 
             daemon::handle(
                 opt.port,
@@ -152,52 +224,241 @@ fn main() -> Result<()> {
                 key,
                 foreground,
                 opt.dump_json,
-                opt.appkeys,
-                notify_url,
+                settings.unwrap(),
+                opt.spool_dir,
                 state,
             )
         }
 
-        OptCommand::Stop {} => {
+        OptCommand::Stop { queue } => {
             let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_set_queue_status(&client, url, QueueState::Stopping, opt.dump_json)
+            clicommands::handle_set_queue_status(
+                &client,
+                url,
+                queue,
+                QueueState::Stopping,
+                opt.dump_json,
+            )
+        }
+        OptCommand::Start { queue } => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_set_queue_status(
+                &client,
+                url,
+                queue,
+                QueueState::Running,
+                opt.dump_json,
+            )
         }
-        OptCommand::Start {} => {
+        OptCommand::Queues {} => {
             let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_set_queue_status(&client, url, QueueState::Running, opt.dump_json)
+            clicommands::handle_queues(&client, url, opt.output, opt.dump_json)
         }
-        OptCommand::Status {} => {
+        OptCommand::Status { list, table } => {
             let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_queue_status(&client, url, opt.dump_json)
+            clicommands::handle_queue_status(
+                &client,
+                url,
+                list.query(),
+                &table.style(),
+                opt.output,
+                opt.dump_json,
+            )
         }
 
-        OptCommand::Submit { cmdline } => {
+        OptCommand::Submit {
+            cmdline,
+            notify_url,
+            notify_on,
+            mail_to,
+            queue,
+            cpus,
+            mem,
+            quiet,
+        } => {
             let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_submit(&client, url, &cmdline, opt.dump_json)
+            let notify = notify_url.map(|url| JobNotify {
+                url,
+                events: if notify_on.is_empty() {
+                    DEFAULT_JOB_EVENTS.to_vec()
+                } else {
+                    notify_on
+                },
+            });
+            let options = SubmitOptions {
+                owner: std::env::var("USER")
+                    .or_else(|_| std::env::var("LOGNAME"))
+                    .ok(),
+                notify,
+                mail_to,
+                queue,
+                cpus,
+                mem,
+            };
+            clicommands::handle_submit(
+                &client,
+                url,
+                &cmdline,
+                options,
+                quiet,
+                opt.output,
+                opt.dump_json,
+            )
         }
 
         OptCommand::Remove { job_id } => {
             let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_remove(&client, url, job_id, opt.dump_json).and_then(|job| {
-                println!("{:?}", job);
-                Ok(())
-            })
+            clicommands::handle_remove(&client, url, job_id, opt.output, opt.dump_json)
+        }
+
+        OptCommand::Show { job_id, json } => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            let output = if json { OutputFormat::Json } else { opt.output };
+            clicommands::handle_show(&client, url, job_id, output, opt.dump_json)
         }
 
         OptCommand::Kill { job_id } => {
             let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_kill(&client, url, job_id, opt.dump_json).and_then(|job| {
-                println!("{:?}", job);
-                Ok(())
-            })
+            clicommands::handle_kill(&client, url, job_id, opt.output, opt.dump_json)
+        }
+
+        OptCommand::Resubmit {
+            job_id,
+            edit,
+            queue,
+            quiet,
+        } => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_resubmit(
+                &client,
+                url,
+                job_id,
+                edit,
+                queue,
+                quiet,
+                opt.output,
+                opt.dump_json,
+            )
+        }
+
+        OptCommand::Update {
+            job_id,
+            args,
+            notify_url,
+            notify_on,
+            mail_to,
+        } => {
+            let changes = JobChanges {
+                args,
+                notify_url,
+                notify_on,
+                mail_to,
+            };
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_update(&client, url, job_id, changes, opt.output, opt.dump_json)
+        }
+
+        OptCommand::Move {
+            job_id,
+            to_top,
+            before,
+            after,
+            position,
+        } => {
+            let to = match (to_top, before, after, position) {
+                (true, ..) => QueuePosition::Top,
+                (_, Some(id), _, _) => QueuePosition::Before(id),
+                (_, _, Some(id), _) => QueuePosition::After(id),
+                (_, _, _, Some(n)) => QueuePosition::Position(n),
+                _ => unreachable!("one of the positions is required"),
+            };
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_move(&client, url, job_id, to, opt.output, opt.dump_json)
+        }
+
+        OptCommand::Hold { job_id } => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_hold(&client, url, job_id, true, opt.output, opt.dump_json)
+        }
+
+        OptCommand::Release { job_id } => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_hold(&client, url, job_id, false, opt.output, opt.dump_json)
         }
 
         OptCommand::Cleanup { max_age } => {
             let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
-            clicommands::handle_cleanup(&client, url, max_age, opt.dump_json).and_then(|n| {
-                println!("{} jobs removed.", n);
-                Ok(())
-            })
+            clicommands::handle_cleanup(&client, url, max_age, opt.output, opt.dump_json)
+        }
+
+        OptCommand::History { list, table } => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_history(
+                &client,
+                url,
+                list.query(),
+                &table.style(),
+                opt.output,
+                opt.dump_json,
+            )
+        }
+
+        OptCommand::Export { file } => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            match file {
+                Some(file) => {
+                    clicommands::handle_export(&client, url, File::create(file)?, opt.dump_json)
+                }
+                None => clicommands::handle_export(
+                    &client,
+                    url,
+                    std::io::stdout().lock(),
+                    opt.dump_json,
+                ),
+            }
+        }
+
+        OptCommand::Import {
+            file,
+            preserve_ids,
+            dry_run,
+        } => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_import(
+                &client,
+                url,
+                File::open(file)?,
+                preserve_ids,
+                dry_run,
+                opt.dump_json,
+            )
+        }
+
+        OptCommand::ReloadConfig {} => {
+            let (client, url) = create_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_reload_config(&client, url, opt.dump_json)
+        }
+
+        OptCommand::Events { job, owner } => {
+            let (client, url) = create_streaming_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            clicommands::handle_events(&client, url, job, owner)
+        }
+
+        OptCommand::Top {} => {
+            let (client, url) = create_client(opt.insecure, opt.ca.clone(), &opt.host, opt.port)?;
+            let (stream_client, _) =
+                create_streaming_client(opt.insecure, opt.ca, &opt.host, opt.port)?;
+            top::handle_top(client, stream_client, url)
+        }
+
+        OptCommand::RunJob { .. } => unreachable!(),
+
+        OptCommand::MigrateState { from } => {
+            let source = State::open("json", from, opt.journal_compact_after)?;
+            let mut target = State::open(&opt.storage, state_file, opt.journal_compact_after)?;
+            let n = target.import_from(&source)?;
+            println!("{} jobs migrated to the {} backend.", n, opt.storage);
+            Ok(())
         }
     }
 }
@@ -0,0 +1,691 @@
+/// notifier.rs
+///
+/// Delivers job reports to the notify URL.
+///
+/// When a job has finished, a JSON report of the job is put into an outbox
+/// that is persisted in the spool directory. A separate thread POSTs the
+/// reports to the notify URL. Failed deliveries are retried with exponential
+/// backoff until the configured number of attempts is reached, also across
+/// daemon restarts.
+///
+/// Jobs may bring their own notify URL and choose the events it is called
+/// for (see `JobNotify`). Such URLs must match an entry of the configured
+/// allowlist. The global notify URL is called when any job has ended.
+///
+/// If a secret is configured, each request carries the headers
+/// `X-Qmanager-Timestamp: <seconds since the epoch>` and
+/// `X-Qmanager-Signature: sha256=<hex>`, an HMAC-SHA256 of the timestamp, a
+/// dot and the request body, keyed with the secret. Receivers can reject old
+/// timestamps, so that captured requests cannot be replayed.
+use std::fs::{self, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use config::Config;
+use hmac::{Hmac, Mac};
+use reqwest::Url;
+use sha2::Sha256;
+
+use job_queue::{Job, JobEvent, JobState};
+use settings::Settings;
+
+/// Name of the header carrying the request signature
+pub const SIGNATURE_HEADER: &str = "X-Qmanager-Signature";
+
+/// Name of the header carrying the signed time of the request
+pub const TIMESTAMP_HEADER: &str = "X-Qmanager-Timestamp";
+
+/// Maximum number of bytes of stdout and stderr each that a report carries
+pub const MAX_REPORT_OUTPUT: usize = 64 * 1024;
+
+/// Default number of delivery attempts per report
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 10;
+
+/// Default delay before the first retry, doubled for each further attempt
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Default upper limit for the delay between two attempts
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// Timeout for a single delivery
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How reports are delivered, read from the `notify-*` config keys
+#[derive(Debug, Clone)]
+pub struct NotifyPolicy {
+    /// Key for signing requests
+    pub secret: Option<String>,
+
+    /// Number of delivery attempts before a report is dropped
+    pub max_attempts: u32,
+
+    /// Delay before the first retry
+    pub retry_delay: Duration,
+
+    /// Upper limit for the delay between two attempts
+    pub max_retry_delay: Duration,
+
+    /// Prefixes of the URLs that jobs may be submitted with
+    pub allowed_urls: Vec<Url>,
+}
+
+impl Default for NotifyPolicy {
+    fn default() -> Self {
+        NotifyPolicy {
+            secret: None,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            max_retry_delay: DEFAULT_MAX_RETRY_DELAY,
+            allowed_urls: Vec::new(),
+        }
+    }
+}
+
+impl NotifyPolicy {
+    /// Reads the delivery settings from the config file
+    pub fn from_config(conf: &Config) -> std::result::Result<Self, String> {
+        let mut policy = NotifyPolicy {
+            secret: conf.get_str("notify-secret").ok(),
+            ..NotifyPolicy::default()
+        };
+
+        if let Ok(n) = conf.get_int("notify-max-attempts") {
+            policy.max_attempts = n.max(1) as u32;
+        }
+        if let Ok(s) = conf.get_str("notify-retry-delay") {
+            policy.retry_delay =
+                humantime::parse_duration(&s).map_err(|e| format!("notify-retry-delay: {}", e))?;
+        }
+        if let Ok(s) = conf.get_str("notify-max-retry-delay") {
+            policy.max_retry_delay = humantime::parse_duration(&s)
+                .map_err(|e| format!("notify-max-retry-delay: {}", e))?;
+        }
+        if let Ok(urls) = conf.get_array("notify-allowed-urls") {
+            for url in urls {
+                let url = url
+                    .into_str()
+                    .map_err(|e| e.to_string())
+                    .and_then(|s| Url::parse(&s).map_err(|e| format!("{}: {}", s, e)))
+                    .map_err(|e| format!("notify-allowed-urls: {}", e))?;
+                policy.allowed_urls.push(url);
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Checks whether a job may be submitted with the given notify URL. The
+    /// URL must have the scheme, host and port of an allowlist entry and its
+    /// path must be the entry's path or lie below it, i.e. `/hook` allows
+    /// `/hook` and `/hook/job`, but not `/hooks`.
+    pub fn allows(&self, url: &Url) -> bool {
+        self.allowed_urls.iter().any(|allowed| {
+            allowed.scheme() == url.scheme()
+                && allowed.host_str() == url.host_str()
+                && allowed.port_or_known_default() == url.port_or_known_default()
+                && path_within(url.path(), allowed.path())
+        })
+    }
+
+    /// Returns the delay before the next attempt after `attempts` failed ones
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u32 << attempts.saturating_sub(1).min(16);
+        (self.retry_delay * factor).min(self.max_retry_delay)
+    }
+}
+
+/// Checks whether `path` is `base` or a path below it
+fn path_within(path: &str, base: &str) -> bool {
+    match path.strip_prefix(base) {
+        Some(rest) => base.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Formats a timestamp as RFC 3339, i.e. `2020-03-01T12:00:00Z`
+pub fn format_time(t: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(t).to_string()
+}
+
+/// The report on a job that is sent to the notify URL
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JobReport {
+    /// The event the report is sent for
+    pub event: JobEvent,
+
+    pub id: u64,
+    pub cmdline: String,
+    pub appkey: String,
+    pub owner: Option<String>,
+
+    /// State name, see `JobState::name`
+    pub state: String,
+
+    /// Exit code if the job has terminated by itself
+    pub exit_code: Option<i32>,
+
+    /// Signal number if the job has been killed
+    pub signal: Option<i32>,
+
+    /// Reason if the job could not be run
+    pub error: Option<String>,
+
+    pub scheduled: String,
+    pub started: Option<String>,
+    pub finished: Option<String>,
+
+    /// Run time in seconds
+    pub duration: Option<f64>,
+
+    /// The end of the output, at most `MAX_REPORT_OUTPUT` bytes each
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Returns the last `max` bytes of the given output, marking the omission
+fn output_tail(output: &str, max: usize) -> String {
+    if output.len() <= max {
+        return output.to_owned();
+    }
+    let mut start = output.len() - max;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("[{} bytes omitted]\n{}", start, &output[start..])
+}
+
+impl JobReport {
+    pub fn new(job: &Job, event: JobEvent) -> Self {
+        let (exit_code, signal, error) = match job.state {
+            JobState::Terminated(code) => (Some(code), None, None),
+            JobState::Killed(signum) => (None, Some(signum), None),
+            JobState::Failed(ref reason) => (None, None, Some(reason.clone())),
+            JobState::Queued | JobState::Held | JobState::Running => (None, None, None),
+        };
+
+        let duration = match (job.started, job.finished) {
+            (Some(started), Some(finished)) => finished
+                .duration_since(started)
+                .ok()
+                .map(|d| d.as_secs_f64()),
+            _ => None,
+        };
+
+        JobReport {
+            event,
+            id: job.id,
+            cmdline: job.cmdline.clone(),
+            appkey: job.appkey().to_owned(),
+            owner: job.owner.clone(),
+            state: job.state.name().to_owned(),
+            exit_code,
+            signal,
+            error,
+            scheduled: format_time(job.scheduled),
+            started: job.started.map(format_time),
+            finished: job.finished.map(format_time),
+            duration,
+            stdout: output_tail(&job.stdout, MAX_REPORT_OUTPUT),
+            stderr: output_tail(&job.stderr, MAX_REPORT_OUTPUT),
+        }
+    }
+}
+
+/// A report waiting for delivery
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Delivery {
+    /// Sequence number, unique within the outbox
+    seq: u64,
+    url: String,
+    report: JobReport,
+
+    /// Number of failed attempts so far
+    attempts: u32,
+
+    /// Earliest time for the next attempt
+    next_attempt: SystemTime,
+}
+
+/// A change of the outbox, a line of the outbox file
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::large_enum_variant)]
+enum OutboxRecord {
+    /// A report has been added
+    Added(Delivery),
+
+    /// A delivery attempt has failed and is retried later
+    Retry {
+        seq: u64,
+        attempts: u32,
+        next_attempt: SystemTime,
+    },
+
+    /// A report has been delivered or dropped
+    Removed(u64),
+}
+
+/// Reports waiting for delivery, persisted to a file. Changes are appended
+/// to the file, which is rewritten once it holds many more records than
+/// there are reports waiting.
+pub struct Outbox {
+    path: PathBuf,
+    deliveries: Vec<Delivery>,
+
+    /// Number of records in the file
+    records: usize,
+}
+
+impl Outbox {
+    /// Opens the outbox stored at the given path. Reports left by a previous
+    /// daemon are delivered again.
+    pub fn open(path: PathBuf) -> Self {
+        let mut outbox = Outbox {
+            path,
+            deliveries: Vec::new(),
+            records: 0,
+        };
+        let data = match fs::read_to_string(&outbox.path) {
+            Ok(data) => data,
+            Err(_) => return outbox,
+        };
+
+        for line in data.lines() {
+            match serde_json::from_str(line) {
+                Ok(record) => outbox.apply(record),
+                Err(e) => {
+                    warn!(
+                        "Ignoring remainder of notification outbox {}: {}",
+                        outbox.path.to_str().unwrap(),
+                        e
+                    );
+                    break;
+                }
+            }
+            outbox.records += 1;
+        }
+
+        if !outbox.deliveries.is_empty() {
+            info!(
+                "{} notifications are waiting for delivery",
+                outbox.deliveries.len()
+            );
+        }
+        outbox
+    }
+
+    /// Applies a change to the reports in memory
+    fn apply(&mut self, record: OutboxRecord) {
+        match record {
+            OutboxRecord::Added(delivery) => self.deliveries.push(delivery),
+            OutboxRecord::Retry {
+                seq,
+                attempts,
+                next_attempt,
+            } => {
+                if let Some(d) = self.deliveries.iter_mut().find(|d| d.seq == seq) {
+                    d.attempts = attempts;
+                    d.next_attempt = next_attempt;
+                }
+            }
+            OutboxRecord::Removed(seq) => self.deliveries.retain(|d| d.seq != seq),
+        }
+    }
+
+    /// Appends a change to the outbox file and applies it
+    fn record(&mut self, record: OutboxRecord) {
+        let mut line = serde_json::to_vec(&record).unwrap();
+        line.push(b'\n');
+        self.apply(record);
+
+        if self.deliveries.is_empty() || self.records > 2 * self.deliveries.len() + 100 {
+            self.rewrite();
+            return;
+        }
+        let appended = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut f| f.write_all(&line));
+        match appended {
+            Ok(()) => self.records += 1,
+            Err(e) => error!("[notifier] Could not write notification outbox: {}", e),
+        }
+    }
+
+    /// Replaces the outbox file with one that only holds the waiting reports
+    fn rewrite(&mut self) {
+        let mut data = Vec::new();
+        for d in &self.deliveries {
+            serde_json::to_writer(&mut data, &OutboxRecord::Added(d.clone())).unwrap();
+            data.push(b'\n');
+        }
+
+        let mut tmp_file = self.path.clone().into_os_string();
+        tmp_file.push(".tmp");
+        match fs::write(&tmp_file, data).and_then(|_| fs::rename(&tmp_file, &self.path)) {
+            Ok(()) => self.records = self.deliveries.len(),
+            Err(e) => error!("[notifier] Could not write notification outbox: {}", e),
+        }
+    }
+
+    /// Adds a report for the given URL, to be delivered right away
+    pub fn push(&mut self, url: &Url, report: JobReport) {
+        let seq = self.deliveries.iter().map(|d| d.seq).max().unwrap_or(0) + 1;
+        self.record(OutboxRecord::Added(Delivery {
+            seq,
+            url: url.as_str().to_owned(),
+            report,
+            attempts: 0,
+            next_attempt: SystemTime::now(),
+        }));
+    }
+
+    /// Returns the next delivery that is due, or the time until one is due
+    fn next_due(&self, now: SystemTime) -> std::result::Result<Delivery, Option<Duration>> {
+        match self.deliveries.iter().min_by_key(|d| d.next_attempt) {
+            Some(d) => match d.next_attempt.duration_since(now) {
+                Ok(wait) if wait > Duration::from_secs(0) => Err(Some(wait)),
+                _ => Ok(d.clone()),
+            },
+            None => Err(None),
+        }
+    }
+
+    /// Records the outcome of a delivery attempt
+    fn complete(&mut self, seq: u64, delivered: bool, policy: &NotifyPolicy) {
+        let d = match self.deliveries.iter().find(|d| d.seq == seq) {
+            Some(d) => d,
+            None => return,
+        };
+
+        let attempts = d.attempts + 1;
+        if delivered {
+            self.record(OutboxRecord::Removed(seq));
+        } else if attempts >= policy.max_attempts {
+            error!(
+                "[notifier] Giving up notifying {} about job {} after {} attempts",
+                d.url, d.report.id, attempts
+            );
+            self.record(OutboxRecord::Removed(seq));
+        } else {
+            let delay = policy.backoff(attempts);
+            debug!(
+                "[notifier] Retrying notification for job {} in {}",
+                d.report.id,
+                humantime::format_duration(delay)
+            );
+            self.record(OutboxRecord::Retry {
+                seq,
+                attempts,
+                next_attempt: SystemTime::now() + delay,
+            });
+        }
+    }
+}
+
+/// Computes the signature header value for a request with the given
+/// timestamp and body
+fn sign(secret: &str, timestamp: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POSTs a single report. Returns whether the receiver accepted it.
+fn deliver(client: &reqwest::Client, delivery: &Delivery, secret: Option<&str>) -> bool {
+    let body = serde_json::to_vec(&delivery.report).unwrap();
+
+    let mut request = client
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json");
+    if let Some(secret) = secret {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
+            .to_string();
+        request = request
+            .header(SIGNATURE_HEADER, sign(secret, &timestamp, &body))
+            .header(TIMESTAMP_HEADER, timestamp);
+    }
+
+    match request.body(body).send() {
+        Ok(ref r) if r.status().is_success() => {
+            debug!(
+                "[notifier] Notification for job {} to {:#?} succeeded. Response: {}",
+                delivery.report.id,
+                delivery.url,
+                r.status().as_str()
+            );
+            true
+        }
+        Ok(r) => {
+            warn!(
+                "[notifier] Notification for job {} to {:#?} failed. Response: {}",
+                delivery.report.id,
+                delivery.url,
+                r.status().as_str()
+            );
+            false
+        }
+        Err(e) => {
+            warn!(
+                "[notifier] Failed to call notify url {:#?} for job {}: {}",
+                delivery.url, delivery.report.id, e
+            );
+            false
+        }
+    }
+}
+
+/// Queues reports on the given event of a job: for the job's own notify URL
+/// if it has chosen the event and the URL is allowed, and for the global
+/// notify URL if the job has ended. Wakes up the notifier thread.
+pub fn enqueue(outbox: &(Mutex<Outbox>, Condvar), settings: &Settings, job: &Job, event: JobEvent) {
+    let mut urls = Vec::new();
+    if let Some(ref notify) = job.notify {
+        if notify.events.contains(&event) {
+            match Url::parse(&notify.url) {
+                Ok(ref url) if !settings.notify.allows(url) => warn!(
+                    "[notifier] Notify URL {} of job {} is not allowed, not reporting",
+                    url, job.id
+                ),
+                Ok(url) => urls.push(url),
+                Err(e) => error!("[notifier] Invalid notify URL of job {}: {}", job.id, e),
+            }
+        }
+    }
+    if event.is_final() {
+        urls.extend(settings.notify_url.clone());
+    }
+
+    if urls.is_empty() {
+        return;
+    }
+
+    let (ref outbox, ref cvar) = *outbox;
+    let mut o = outbox.lock().unwrap();
+    for url in urls {
+        o.push(&url, JobReport::new(job, event));
+    }
+    cvar.notify_one();
+}
+
+/// Returns the location of the outbox within the spool directory
+pub fn outbox_path(spool_dir: &Path) -> PathBuf {
+    spool_dir.join("notify.outbox")
+}
+
+/// Delivers the reports in the outbox one after another. Sleeps on the
+/// condition variable until a report is added or a retry is due.
+pub fn run_notifier(outbox: &Arc<(Mutex<Outbox>, Condvar)>, settings: Arc<RwLock<Settings>>) -> ! {
+    let (ref outbox, ref cvar) = **outbox;
+
+    let client = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Could not set up HTTP client for notifications");
+
+    loop {
+        let delivery = {
+            let mut o = outbox.lock().unwrap();
+            loop {
+                match o.next_due(SystemTime::now()) {
+                    Ok(d) => break d,
+                    Err(Some(wait)) => o = cvar.wait_timeout(o, wait).unwrap().0,
+                    Err(None) => o = cvar.wait(o).unwrap(),
+                }
+            }
+        };
+
+        let policy = settings.read().unwrap().notify.clone();
+        let delivered = deliver(&client, &delivery, policy.secret.as_deref());
+        outbox
+            .lock()
+            .unwrap()
+            .complete(delivery.seq, delivered, &policy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use job_queue::{JobQueue, SubmitOptions};
+
+    fn outbox_file(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("qmanager-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        outbox_path(&dir)
+    }
+
+    fn report(id: u64) -> JobReport {
+        let mut q = JobQueue::new(id - 1);
+        let id = q.submit("true".to_string(), SubmitOptions::default());
+        JobReport::new(q.get_job(id).unwrap(), JobEvent::Finished)
+    }
+
+    fn seqs(outbox: &Outbox) -> Vec<(u64, u32)> {
+        outbox
+            .deliveries
+            .iter()
+            .map(|d| (d.seq, d.attempts))
+            .collect()
+    }
+
+    fn allowlist(allowed: &[&str]) -> NotifyPolicy {
+        NotifyPolicy {
+            allowed_urls: allowed.iter().map(|u| Url::parse(u).unwrap()).collect(),
+            ..NotifyPolicy::default()
+        }
+    }
+
+    fn allows(policy: &NotifyPolicy, url: &str) -> bool {
+        policy.allows(&Url::parse(url).unwrap())
+    }
+
+    #[test]
+    fn allowed_urls_match_whole_path_segments() {
+        let policy = allowlist(&["https://example.org/hook"]);
+        assert!(allows(&policy, "https://example.org/hook"));
+        assert!(allows(&policy, "https://example.org/hook/job?id=1"));
+        assert!(!allows(&policy, "https://example.org/hooks"));
+        assert!(!allows(&policy, "https://example.org/hook-evil"));
+        assert!(!allows(&policy, "http://example.org/hook"));
+        assert!(!allows(&policy, "https://example.org:8443/hook"));
+
+        let policy = allowlist(&["https://example.org/hook/"]);
+        assert!(allows(&policy, "https://example.org/hook/job"));
+        assert!(!allows(&policy, "https://example.org/hook"));
+    }
+
+    #[test]
+    fn outbox_is_replayed_after_a_restart() {
+        let path = outbox_file("outbox-replay");
+        let url = Url::parse("https://example.org/hook").unwrap();
+        let policy = NotifyPolicy {
+            max_attempts: 2,
+            ..NotifyPolicy::default()
+        };
+
+        let mut outbox = Outbox::open(path.clone());
+        outbox.push(&url, report(1));
+        outbox.push(&url, report(2));
+        outbox.push(&url, report(3));
+        outbox.complete(1, true, &policy);
+        outbox.complete(2, false, &policy);
+        assert_eq!(seqs(&outbox), vec![(2, 1), (3, 0)]);
+
+        let mut outbox = Outbox::open(path.clone());
+        assert_eq!(seqs(&outbox), vec![(2, 1), (3, 0)]);
+        assert_eq!(outbox.deliveries[0].report.id, 2);
+        assert_eq!(outbox.deliveries[0].url, url.as_str());
+        assert!(outbox.deliveries[0].next_attempt > SystemTime::now());
+
+        // the report is dropped after the last attempt
+        outbox.complete(2, false, &policy);
+        assert_eq!(seqs(&Outbox::open(path.clone())), vec![(3, 0)]);
+
+        // a torn last line is ignored
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"Removed\":").unwrap();
+        assert_eq!(seqs(&Outbox::open(path)), vec![(3, 0)]);
+    }
+
+    #[test]
+    fn retries_are_due_after_the_backoff() {
+        let mut outbox = Outbox::open(outbox_file("outbox-due"));
+        assert_eq!(outbox.next_due(SystemTime::now()).unwrap_err(), None);
+
+        let url = Url::parse("https://example.org/hook").unwrap();
+        outbox.push(&url, report(1));
+        assert_eq!(outbox.next_due(SystemTime::now()).unwrap().seq, 1);
+
+        let policy = NotifyPolicy::default();
+        outbox.complete(1, false, &policy);
+        let now = SystemTime::now();
+        let wait = outbox.next_due(now).unwrap_err().unwrap();
+        assert!(wait <= policy.retry_delay && wait > policy.retry_delay / 2);
+        let due = outbox.next_due(now + policy.retry_delay).unwrap();
+        assert_eq!((due.seq, due.attempts), (1, 1));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let policy = NotifyPolicy {
+            retry_delay: Duration::from_secs(30),
+            max_retry_delay: Duration::from_secs(200),
+            ..NotifyPolicy::default()
+        };
+        let delays: Vec<u64> = (1..=6).map(|n| policy.backoff(n).as_secs()).collect();
+        assert_eq!(delays, vec![30, 60, 120, 200, 200, 200]);
+        assert_eq!(policy.backoff(1000), policy.max_retry_delay);
+    }
+
+    #[test]
+    fn signatures_cover_timestamp_and_body() {
+        assert_eq!(
+            sign("secret", "1600000000", b"{\"id\":1}"),
+            "sha256=49847f6653f3434dc0d5563850815d91e18471282eeccadbf48380236b3ed25f"
+        );
+        assert_ne!(
+            sign("secret", "1600000001", b"{\"id\":1}"),
+            sign("secret", "1600000000", b"{\"id\":1}")
+        );
+    }
+
+    #[test]
+    fn long_output_is_cut_at_the_front() {
+        assert_eq!(output_tail("short", 10), "short");
+        assert_eq!(output_tail("0123456789", 4), "[6 bytes omitted]\n6789");
+        // the cut does not split characters
+        assert_eq!(output_tail("aäb", 2), "[3 bytes omitted]\nb");
+    }
+}
@@ -0,0 +1,413 @@
+/// output.rs
+///
+/// Machine-readable output of the command line client.
+///
+/// `--output` selects how `status`, `history`, `show`, `submit`, `resubmit`,
+/// `update`, `remove`, `kill`, `hold`, `release`, `move`, `cleanup` and
+/// `queues` print their results:
+///
+/// - `table` (default): for humans, see `table.rs`. Subject to change.
+/// - `json`: a single JSON document.
+/// - `jsonl`: one JSON object per line, one line per job.
+/// - `csv`: a header line with the column names, then one line per job.
+///
+/// Jobs are printed as `JobRecord`s. Their schema is stable: fields are only
+/// ever added, and `CSV_COLUMNS` only ever grows at the end. Errors are
+/// reported on stderr with a non-zero exit code in all formats.
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use job_queue::{Job, JobChange, JobPage, JobState};
+use notifier::format_time;
+use queues::QueueInfo;
+use resources::{ResourceUsage, Resources};
+
+/// Output format of the client
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Jsonl,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::Jsonl),
+            "csv" => Ok(OutputFormat::Csv),
+            _ => Err(format!(
+                "Unknown output format '{}', use table, json, jsonl or csv",
+                s
+            )),
+        }
+    }
+}
+
+/// Columns of CSV output, in this order
+pub const CSV_COLUMNS: &[&str] = &[
+    "id",
+    "appkey",
+    "owner",
+    "state",
+    "exit_code",
+    "signal",
+    "error",
+    "submitted",
+    "started",
+    "finished",
+    "wait_seconds",
+    "run_seconds",
+    "pid",
+    "cmdline",
+    "resubmitted_from",
+    "queue",
+    "cpus",
+    "mem",
+];
+
+/// A job in machine-readable output
+#[derive(Debug, Serialize)]
+pub struct JobRecord {
+    pub id: u64,
+    pub appkey: String,
+
+    /// Command line including the appkey
+    pub cmdline: String,
+
+    /// Submitting user, null if unknown
+    pub owner: Option<String>,
+
+    /// Queue that runs the job
+    pub queue: String,
+
+    /// queued, held, running, terminated, killed or failed
+    pub state: &'static str,
+
+    /// Exit code of a terminated job
+    pub exit_code: Option<i32>,
+
+    /// Signal that killed the job
+    pub signal: Option<i32>,
+
+    /// Why the job could not be run
+    pub error: Option<String>,
+
+    /// RFC 3339 time stamps, i.e. `2020-03-01T12:00:00Z`
+    pub submitted: String,
+    pub started: Option<String>,
+    pub finished: Option<String>,
+
+    /// Seconds between submission and start, up to now for queued jobs
+    pub wait_seconds: u64,
+
+    /// Seconds between start and end, up to now for running jobs
+    pub run_seconds: Option<u64>,
+
+    pub pid: Option<u32>,
+
+    /// ID of the job that this one is a copy of
+    pub resubmitted_from: Option<u64>,
+
+    /// Declared CPUs and bytes of memory, null if not declared
+    pub cpus: Option<u32>,
+    pub mem: Option<u64>,
+
+    /// Output of the job, only printed by `show`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+
+    /// Changes made while the job was queued, only printed by `show`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<ChangeRecord>>,
+}
+
+/// A change of a queued job in machine-readable output
+#[derive(Debug, Serialize)]
+pub struct ChangeRecord {
+    /// RFC 3339 time stamp
+    pub time: String,
+
+    /// `cmdline`, `notify` or `mail_to`
+    pub field: String,
+    pub old: String,
+    pub new: String,
+}
+
+impl ChangeRecord {
+    fn new(change: &JobChange) -> Self {
+        ChangeRecord {
+            time: format_time(change.time),
+            field: change.field.clone(),
+            old: change.old.clone(),
+            new: change.new.clone(),
+        }
+    }
+}
+
+/// Seconds from one point in time to another, or to now
+fn seconds(from: SystemTime, to: Option<SystemTime>) -> u64 {
+    to.unwrap_or_else(SystemTime::now)
+        .duration_since(from)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl JobRecord {
+    pub fn new(job: &Job) -> Self {
+        let (exit_code, signal, error) = match job.state {
+            JobState::Terminated(code) => (Some(code), None, None),
+            JobState::Killed(signum) => (None, Some(signum), None),
+            JobState::Failed(ref reason) => (None, None, Some(reason.clone())),
+            JobState::Queued | JobState::Held | JobState::Running => (None, None, None),
+        };
+        JobRecord {
+            id: job.id,
+            appkey: job.appkey().to_string(),
+            cmdline: job.cmdline.clone(),
+            owner: job.owner.clone(),
+            queue: job.queue.clone(),
+            state: job.state.name(),
+            exit_code,
+            signal,
+            error,
+            submitted: format_time(job.scheduled),
+            started: job.started.map(format_time),
+            finished: job.finished.map(format_time),
+            wait_seconds: seconds(job.scheduled, job.started),
+            run_seconds: job.started.map(|started| seconds(started, job.finished)),
+            pid: job.pid,
+            resubmitted_from: job.resubmitted_from,
+            cpus: job.cpus,
+            mem: job.mem,
+            stdout: None,
+            stderr: None,
+            changes: None,
+        }
+    }
+
+    /// Like `new`, including the job's stdout, stderr and changes
+    pub fn with_output(job: &Job) -> Self {
+        JobRecord {
+            stdout: Some(job.stdout.clone()),
+            stderr: Some(job.stderr.clone()),
+            changes: Some(job.changes.iter().map(ChangeRecord::new).collect()),
+            ..JobRecord::new(job)
+        }
+    }
+}
+
+/// Quotes a CSV field if necessary
+fn csv_field(value: &Value) -> String {
+    let s = match value {
+        Value::Null => return String::new(),
+        Value::String(s) => s.clone(),
+        v => v.to_string(),
+    };
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s
+    }
+}
+
+/// Prints objects as CSV with the given columns
+fn print_csv(columns: &[&str], rows: &[Value]) {
+    println!("{}", columns.join(","));
+    for row in rows {
+        let fields: Vec<String> = columns
+            .iter()
+            .map(|c| csv_field(row.get(c).unwrap_or(&Value::Null)))
+            .collect();
+        println!("{}", fields.join(","));
+    }
+}
+
+/// Prints jobs in the given machine-readable format. `json` gets `document`,
+/// the line-based formats get one line per job.
+fn print_jobs_with<T: Serialize, R: Serialize>(format: OutputFormat, document: &T, jobs: &[R]) {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(document).unwrap()),
+        OutputFormat::Jsonl => {
+            for job in jobs {
+                println!("{}", serde_json::to_string(job).unwrap());
+            }
+        }
+        OutputFormat::Csv => {
+            let rows: Vec<Value> = jobs
+                .iter()
+                .map(|j| serde_json::to_value(j).unwrap())
+                .collect();
+            print_csv(CSV_COLUMNS, &rows);
+        }
+        OutputFormat::Table => unreachable!("tables are printed by table.rs"),
+    }
+}
+
+/// Prints a single job: an object in `json`
+pub fn print_job(format: OutputFormat, record: JobRecord) {
+    print_jobs_with(format, &record, std::slice::from_ref(&record));
+}
+
+/// Prints a list of jobs: an array in `json`
+pub fn print_jobs(format: OutputFormat, jobs: &[Job]) {
+    let records: Vec<JobRecord> = jobs.iter().map(JobRecord::new).collect();
+    print_jobs_with(format, &records, &records);
+}
+
+/// A page of a listing in `json`
+#[derive(Serialize)]
+struct PageRecord<'a> {
+    jobs: &'a [JobRecord],
+
+    /// Number of matching jobs on all pages
+    total: usize,
+
+    /// Position of the first job among all matching jobs
+    offset: usize,
+}
+
+/// Prints a page of a job listing: `{"jobs": [...], "total": 42, "offset": 0}`
+/// in `json`
+pub fn print_page(format: OutputFormat, page: &JobPage) {
+    let records: Vec<JobRecord> = page.jobs.iter().map(JobRecord::new).collect();
+    let document = PageRecord {
+        jobs: &records,
+        total: page.total,
+        offset: page.offset,
+    };
+    print_jobs_with(format, &document, &records);
+}
+
+/// A queue in machine-readable output
+#[derive(Debug, Serialize)]
+pub struct QueueRecord {
+    pub name: String,
+
+    /// running, stopping or stopped
+    pub state: String,
+
+    /// Jobs run at the same time, 0 if the queue is no longer configured
+    pub slots: usize,
+
+    /// Accepted appkeys, null for all
+    pub appkeys: Option<Vec<String>>,
+
+    /// Number of running jobs and of queued and held jobs
+    pub running: usize,
+    pub waiting: usize,
+}
+
+impl QueueRecord {
+    pub fn new(queue: &QueueInfo) -> Self {
+        QueueRecord {
+            name: queue.name.clone(),
+            state: format!("{:?}", queue.state).to_lowercase(),
+            slots: queue.slots,
+            appkeys: queue.appkeys.clone(),
+            running: queue.running,
+            waiting: queue.waiting,
+        }
+    }
+}
+
+/// Prints the queues: an array in `json`, one line per queue otherwise.
+/// `csv` leaves out the appkeys.
+pub fn print_queues(format: OutputFormat, queues: &[QueueInfo]) {
+    let records: Vec<QueueRecord> = queues.iter().map(QueueRecord::new).collect();
+    match format {
+        OutputFormat::Csv => {
+            let rows: Vec<Value> = records
+                .iter()
+                .map(|r| serde_json::to_value(r).unwrap())
+                .collect();
+            print_csv(&["name", "state", "slots", "running", "waiting"], &rows);
+        }
+        _ => print_jobs_with(format, &records, &records),
+    }
+}
+
+/// The host's resources in `json`, with memory in bytes
+#[derive(Serialize)]
+struct ResourceRecord {
+    total: Resources,
+    allocated: Resources,
+    free: Resources,
+}
+
+/// Output of `status` in `json`
+#[derive(Serialize)]
+struct StatusRecord<'a> {
+    /// State of the default queue, or of the queue given by `--queue`:
+    /// running, stopping or stopped
+    queue_state: &'a str,
+    queues: Vec<QueueRecord>,
+
+    /// null if the host's resources are not limited
+    resources: Option<ResourceRecord>,
+    queued: PageRecord<'a>,
+    finished: PageRecord<'a>,
+}
+
+/// Prints the output of `status`: in `json`, `{"queue_state": "running",
+/// "queues": [...], "resources": {...}, "queued": <page>, "finished":
+/// <page>}`; in the other formats the queued jobs followed by the finished
+/// jobs
+pub fn print_status(
+    format: OutputFormat,
+    queue_state: &str,
+    queues: &[QueueInfo],
+    resources: Option<ResourceUsage>,
+    queued: &JobPage,
+    finished: &JobPage,
+) {
+    let queued_records: Vec<JobRecord> = queued.jobs.iter().map(JobRecord::new).collect();
+    let finished_records: Vec<JobRecord> = finished.jobs.iter().map(JobRecord::new).collect();
+    let document = StatusRecord {
+        queue_state,
+        queues: queues.iter().map(QueueRecord::new).collect(),
+        resources: resources.map(|usage| ResourceRecord {
+            total: usage.total,
+            allocated: usage.allocated,
+            free: usage.total - usage.allocated,
+        }),
+        queued: PageRecord {
+            jobs: &queued_records,
+            total: queued.total,
+            offset: queued.offset,
+        },
+        finished: PageRecord {
+            jobs: &finished_records,
+            total: finished.total,
+            offset: finished.offset,
+        },
+    };
+    let all: Vec<&JobRecord> = queued_records.iter().chain(&finished_records).collect();
+    print_jobs_with(format, &document, &all);
+}
+
+/// Prints a result that is not a job, i.e. `{"id": 42}` for `submit`. `csv`
+/// gets the keys as header line.
+pub fn print_value(format: OutputFormat, value: &Value) {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string_pretty(value).unwrap()),
+        OutputFormat::Jsonl => println!("{}", value),
+        OutputFormat::Csv => {
+            let columns: Vec<&str> = value
+                .as_object()
+                .map(|o| o.keys().map(|k| k.as_str()).collect())
+                .unwrap_or_default();
+            print_csv(&columns, std::slice::from_ref(value));
+        }
+        OutputFormat::Table => unreachable!("tables are printed by clicommands.rs"),
+    }
+}
@@ -1,4 +1,11 @@
-use job_queue::{Job, QueueState};
+use std::time::SystemTime;
+
+use job_queue::{
+    ImportReport, Job, JobChanges, JobFilter, JobList, JobPage, JobQuery, QueuePosition,
+    QueueState, SubmitOptions,
+};
+use queues::QueueInfo;
+use resources::ResourceUsage;
 
 /// A request by the client for the server. May be answered by
 #[derive(Serialize, Deserialize, Debug)]
@@ -7,6 +14,25 @@ pub enum Request {
     /// Triggers a SubmitJob or Error response
     SubmitJob(String),
 
+    /// Submit a job like `SubmitJob`, with options. The notify URL must be
+    /// allowed by the daemon's `notify-allowed-urls`, and the queue, if
+    /// given, must accept the appkey.
+    /// Triggers a SubmitJob or Error response
+    SubmitJobWith {
+        cmdline: String,
+        #[serde(flatten)]
+        options: SubmitOptions,
+    },
+
+    /// Submit a copy of the job with the given ID, optionally with another
+    /// command line or to another queue, see `JobQueue::resubmit`
+    /// Triggers a SubmitJob or Error response
+    ResubmitJob {
+        id: u64,
+        cmdline: Option<String>,
+        queue: Option<String>,
+    },
+
     /// Remove the job with the given ID with `Queued` or `Finished` job.
     /// Triggers a GetJob or an Error response
     RemoveJob(u64),
@@ -15,25 +41,104 @@ pub enum Request {
     /// Triggers an Ok or Error response
     KillJob(u64),
 
-    /// Request a list of queued jobs, including the currently running
+    /// Hold the queued job with the given ID, so that it is not started
+    /// Triggers a GetJob or Error response
+    HoldJob(u64),
+
+    /// Release the held job with the given ID
+    /// Triggers a GetJob or Error response
+    ReleaseJob(u64),
+
+    /// Change the queued or held job with the given ID, see `JobQueue::update`
+    /// Triggers a GetJob or Error response
+    UpdateJob { id: u64, changes: JobChanges },
+
+    /// Move the queued or held job with the given ID within the queue
+    /// Triggers a GetJobs response with the reordered queue, or an Error
+    MoveJob { id: u64, to: QueuePosition },
+
+    /// Request a list of queued jobs of the default queue, including the
+    /// running ones
     /// Triggers a GetJobs response
     GetQueuedJobs,
 
-    /// Request a list of terminated jobs
+    /// Request a list of queued jobs, including the running ones, of the
+    /// given queue or of all queues
+    /// Triggers a GetJobs response
+    GetQueuedJobsIn { queue: Option<String> },
+
+    /// Request a single queued, running or finished job
+    /// Triggers a GetJob or Error response
+    GetJob(u64),
+
+    /// Request a list of terminated jobs of the default queue
     /// Triggers a GetJobs response
     GetFinishedJobs,
 
-    /// Set the queue state
-    /// Triggers a QueueState or Error response
+    /// Request a list of terminated jobs of the given queue or of all queues
+    /// Triggers a GetJobs response
+    GetFinishedJobsIn { queue: Option<String> },
+
+    /// Set the state of the default queue
+    /// Triggers a QueueState response
     SetQueueState(QueueState),
 
-    /// Request the current queue state
+    /// Set the state of the given queue, or of the default queue
+    /// Triggers a QueueState or Error response
+    SetQueueStateIn {
+        queue: Option<String>,
+        state: QueueState,
+    },
+
+    /// Request the current state of the default queue
     /// Triggers a QueueState response
     GetQueueState,
+
+    /// Request the current state of the given queue, or of the default queue
+    /// Triggers a QueueState or Error response
+    GetQueueStateIn { queue: Option<String> },
+
+    /// Request the configured queues with their states and number of jobs
+    /// Triggers a Queues response
+    GetQueues,
+
+    /// Request the host's resources and the ones allocated to running jobs
+    /// Triggers a Resources response
+    GetResources,
+
+    /// Request all queued and finished jobs matching the given filter
+    /// Triggers a GetJobs or Error response
+    FindJobs(JobFilter),
+
+    /// Request a filtered, sorted page of the given list
+    /// Triggers a JobPage or Error response
+    ListJobs { list: JobList, query: JobQuery },
+
+    /// Remove all finished jobs matching the given filter in one go
+    /// Triggers a GetJobs response listing the removed jobs
+    Cleanup { filter: JobFilter },
+
+    /// Request all queued and finished jobs at the same point in time, the
+    /// queued ones first
+    /// Triggers a GetJobs response
+    ExportJobs,
+
+    /// Import jobs exported from another daemon, see `JobQueue::import`
+    /// Triggers an ImportReport response
+    ImportJobs {
+        jobs: Vec<Job>,
+        preserve_ids: bool,
+        dry_run: bool,
+    },
+
+    /// Re-read the daemon's config file, like on SIGHUP
+    /// Triggers a ConfigReloaded or Error response
+    ReloadConfig,
 }
 
 /// A response from the server to the client
 #[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Response {
     /// The job has been submitted with the given ID
     SubmitJob(u64),
@@ -44,12 +149,49 @@ pub enum Response {
     /// A single job
     GetJob(Job),
 
+    /// A page of a job listing
+    JobPage(JobPage),
+
     /// The request could not be handled (error message given)
     Error(String),
 
     /// The current queue state
     QueueState(QueueState),
 
+    /// All queues, see `queues.rs`
+    Queues(Vec<QueueInfo>),
+
+    /// The host's resources and their use, None if they are not limited
+    Resources(Option<ResourceUsage>),
+
     /// The request was successfully handled and no return value is given
     Ok,
+
+    /// The outcome of an import
+    ImportReport(ImportReport),
+
+    /// The config file has been reloaded. Lists the changed settings that
+    /// only take effect after a restart.
+    ConfigReloaded(Vec<String>),
+}
+
+/// Name and version of the export format written by `qmanager export`
+pub const EXPORT_FORMAT: &str = "qmanager-export";
+pub const EXPORT_VERSION: u32 = 1;
+
+/// A single line of an export file. The first line is a `Header`, followed
+/// by one `Job` line per queued or finished job.
+#[derive(Serialize, Deserialize, Debug)]
+#[allow(clippy::large_enum_variant)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ExportRecord {
+    /// Describes the file format
+    Header {
+        format: String,
+        version: u32,
+        exported: SystemTime,
+    },
+
+    /// A queued, running or finished job
+    Job { job: Job },
 }
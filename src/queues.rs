@@ -0,0 +1,242 @@
+/// queues.rs
+///
+/// Named job queues.
+///
+/// Besides the `default` queue, further queues can be defined in the
+/// `[queues.<name>]` sections of the config file. Each queue has a state of
+/// its own (see `QueueState`), a number of slots, i.e. jobs that it runs at
+/// the same time, and the appkeys it accepts. If the host's resources are
+/// limited (see `resources.rs`), queues without a slot count run as many jobs
+/// as fit. Jobs are submitted to the queue
+/// given by the client, or else to the queue that lists their appkey in
+/// `default-for`, or else to `default`.
+///
+/// Queues are set up when the daemon starts. Changes require a restart.
+use std::collections::{BTreeMap, HashMap};
+
+use config::{Config, Value};
+
+use job_queue::{JobQueue, JobState, QueueState, DEFAULT_QUEUE};
+
+/// Settings of a single queue, read from `[queues.<name>]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueueConfig {
+    /// Number of jobs run at the same time
+    pub slots: usize,
+
+    /// Appkeys that may be submitted to the queue, None for all
+    pub appkeys: Option<Vec<String>>,
+
+    /// Appkeys that are submitted to this queue unless another one is given
+    pub default_for: Vec<String>,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            slots: 1,
+            appkeys: None,
+            default_for: Vec::new(),
+        }
+    }
+}
+
+/// Reads a list of strings from a config value
+fn string_list(key: &str, value: Value) -> Result<Vec<String>, String> {
+    value
+        .into_array()
+        .and_then(|values| values.into_iter().map(Value::into_str).collect())
+        .map_err(|e| format!("{}: {}", key, e))
+}
+
+impl QueueConfig {
+    /// Reads the settings of the queue with the given name from its table
+    fn from_table(
+        name: &str,
+        mut table: HashMap<String, Value>,
+        default_slots: usize,
+    ) -> Result<Self, String> {
+        let mut queue = QueueConfig {
+            slots: default_slots,
+            ..QueueConfig::default()
+        };
+
+        if let Some(slots) = table.remove("slots") {
+            queue.slots = match slots.into_int() {
+                Ok(n) if n >= 1 => n as usize,
+                _ => return Err(format!("queues.{}.slots: must be at least 1", name)),
+            };
+        }
+        if let Some(appkeys) = table.remove("appkeys") {
+            queue.appkeys = Some(string_list(&format!("queues.{}.appkeys", name), appkeys)?);
+        }
+        if let Some(default_for) = table.remove("default-for") {
+            queue.default_for = string_list(&format!("queues.{}.default-for", name), default_for)?;
+        }
+
+        for appkey in &queue.default_for {
+            if !queue.accepts(appkey) {
+                return Err(format!(
+                    "queues.{}.default-for: appkey '{}' is not in appkeys",
+                    name, appkey
+                ));
+            }
+        }
+
+        Ok(queue)
+    }
+
+    /// Checks whether jobs with the given appkey may be submitted to the queue
+    pub fn accepts(&self, appkey: &str) -> bool {
+        self.appkeys
+            .as_ref()
+            .is_none_or(|appkeys| appkeys.iter().any(|a| a == appkey))
+    }
+}
+
+/// A queue as reported to clients
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueueInfo {
+    pub name: String,
+    pub state: QueueState,
+
+    /// Number of jobs run at the same time, 0 if the queue is no longer
+    /// configured
+    pub slots: usize,
+
+    /// Appkeys that may be submitted to the queue, None for all
+    pub appkeys: Option<Vec<String>>,
+
+    /// Number of running jobs
+    pub running: usize,
+
+    /// Number of queued and held jobs
+    pub waiting: usize,
+}
+
+/// All configured queues, by name
+#[derive(Debug, Clone, PartialEq)]
+pub struct Queues {
+    pub queues: BTreeMap<String, QueueConfig>,
+}
+
+impl Default for Queues {
+    fn default() -> Self {
+        let mut queues = BTreeMap::new();
+        queues.insert(DEFAULT_QUEUE.to_string(), QueueConfig::default());
+        Queues { queues }
+    }
+}
+
+impl Queues {
+    /// Reads the `[queues.<name>]` sections of the config file. The
+    /// `default` queue always exists. Queues get `default_slots` unless
+    /// configured otherwise.
+    pub fn from_config(conf: &Config, default_slots: usize) -> Result<Self, String> {
+        let mut queues = Queues::default();
+        for queue in queues.queues.values_mut() {
+            queue.slots = default_slots;
+        }
+
+        if let Ok(table) = conf.get_table("queues") {
+            for (name, value) in table {
+                if !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                {
+                    return Err(format!(
+                        "Invalid queue name '{}', use letters, digits, '-' and '_'",
+                        name
+                    ));
+                }
+                let table = value
+                    .into_table()
+                    .map_err(|e| format!("queues.{}: {}", name, e))?;
+                let queue = QueueConfig::from_table(&name, table, default_slots)?;
+                queues.queues.insert(name, queue);
+            }
+        }
+
+        // each appkey has at most one default queue
+        let mut defaults: HashMap<&str, &str> = HashMap::new();
+        for (name, queue) in &queues.queues {
+            for appkey in &queue.default_for {
+                if let Some(other) = defaults.insert(appkey, name) {
+                    return Err(format!(
+                        "Appkey '{}' is in default-for of both queue '{}' and '{}'",
+                        appkey, other, name
+                    ));
+                }
+            }
+        }
+
+        Ok(queues)
+    }
+
+    /// Picks the queue for a new job with the given appkey: the given queue,
+    /// or else the appkey's default queue. Fails if the queue does not exist
+    /// or does not accept the appkey.
+    pub fn resolve(&self, queue: Option<&str>, appkey: &str) -> Result<String, String> {
+        let name = match queue {
+            Some(name) => name,
+            None => self
+                .queues
+                .iter()
+                .find(|(_, q)| q.default_for.iter().any(|a| a == appkey))
+                .map(|(name, _)| name.as_str())
+                .unwrap_or(DEFAULT_QUEUE),
+        };
+        match self.queues.get(name) {
+            Some(q) if q.accepts(appkey) => Ok(name.to_string()),
+            Some(_) => Err(format!(
+                "Queue '{}' does not accept appkey '{}'",
+                name, appkey
+            )),
+            None => Err(format!("Unknown queue '{}'", name)),
+        }
+    }
+
+    /// Checks whether a queue with the given name is configured
+    pub fn contains(&self, name: &str) -> bool {
+        self.queues.contains_key(name)
+    }
+
+    /// Number of slots by queue name
+    pub fn slots(&self) -> BTreeMap<String, usize> {
+        self.queues
+            .iter()
+            .map(|(name, q)| (name.clone(), q.slots))
+            .collect()
+    }
+
+    /// Describes all configured queues, and queues that still have jobs
+    /// but are no longer configured
+    pub fn info(&self, q: &JobQueue) -> Vec<QueueInfo> {
+        let mut names: Vec<&str> = self.queues.keys().map(|n| n.as_str()).collect();
+        for job in q.iter_queued() {
+            if !names.contains(&job.queue.as_str()) {
+                names.push(&job.queue);
+            }
+        }
+        names.sort();
+
+        names
+            .into_iter()
+            .map(|name| {
+                let config = self.queues.get(name);
+                let waiting = q
+                    .iter_queued()
+                    .filter(|j| j.queue == name && j.state != JobState::Running)
+                    .count();
+                QueueInfo {
+                    name: name.to_string(),
+                    state: q.get_state(name),
+                    slots: config.map(|c| c.slots).unwrap_or(0),
+                    appkeys: config.map_or(Some(Vec::new()), |c| c.appkeys.clone()),
+                    running: q.running_jobs(name).count(),
+                    waiting,
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,158 @@
+/// resources.rs
+///
+/// CPUs and memory of the host and of the jobs.
+///
+/// If the `[resources]` section of the config file gives the CPUs and memory
+/// of the host, jobs are only started while their declared resources fit
+/// into what the running jobs leave free (see `JobQueue::schedule`). Jobs
+/// declare their needs on submission (`submit --cpus 8 --mem 64G`), or get
+/// the defaults of their appkey (see `limits.rs`). A job that declares
+/// nothing needs a single CPU and no memory.
+///
+/// The host's resources are set up when the daemon starts. Changes require a
+/// restart.
+use std::ops::{Add, Sub};
+
+use config::Config;
+
+/// CPUs and memory, of the host or needed by a job
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resources {
+    pub cpus: u32,
+
+    /// Bytes
+    pub mem: u64,
+}
+
+impl Resources {
+    /// Reads the `[resources]` section of the config file, None if it is
+    /// missing
+    pub fn from_config(conf: &Config) -> Result<Option<Self>, String> {
+        if conf.get_table("resources").is_err() {
+            return Ok(None);
+        }
+
+        let cpus = match conf.get_int("resources.cpus") {
+            Ok(n) if n >= 1 => n as u32,
+            Ok(_) => return Err("resources.cpus: must be at least 1".to_string()),
+            Err(e) => return Err(format!("resources.cpus: {}", e)),
+        };
+        let mem = conf
+            .get_str("resources.mem")
+            .map_err(|e| e.to_string())
+            .and_then(|s| parse_mem(&s))
+            .map_err(|e| format!("resources.mem: {}", e))?;
+
+        Ok(Some(Resources { cpus, mem }))
+    }
+
+    /// Returns the resources of a job that declares the given CPUs and bytes
+    /// of memory: a single CPU and no memory unless declared otherwise
+    pub fn declared(cpus: Option<u32>, mem: Option<u64>) -> Resources {
+        Resources {
+            cpus: cpus.unwrap_or(1),
+            mem: mem.unwrap_or(0),
+        }
+    }
+
+    /// Checks whether `other` is at most as large as `self` in both CPUs
+    /// and memory
+    pub fn holds(&self, other: &Resources) -> bool {
+        other.cpus <= self.cpus && other.mem <= self.mem
+    }
+}
+
+impl Add for Resources {
+    type Output = Resources;
+
+    fn add(self, other: Resources) -> Resources {
+        Resources {
+            cpus: self.cpus.saturating_add(other.cpus),
+            mem: self.mem.saturating_add(other.mem),
+        }
+    }
+}
+
+/// Never goes below zero, i.e. if jobs of a previous daemon use more than the
+/// host has now
+impl Sub for Resources {
+    type Output = Resources;
+
+    fn sub(self, other: Resources) -> Resources {
+        Resources {
+            cpus: self.cpus.saturating_sub(other.cpus),
+            mem: self.mem.saturating_sub(other.mem),
+        }
+    }
+}
+
+/// The host's resources and the ones allocated to running jobs
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    pub total: Resources,
+    pub allocated: Resources,
+}
+
+/// Parses an amount of memory, i.e. `512M` or `64G`. The suffixes K, M, G
+/// and T stand for powers of 1024; a plain number is bytes.
+pub fn parse_mem(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, factor) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        Some('T') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    number
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .ok_or_else(|| format!("Invalid amount of memory '{}', i.e. use 512M or 64G", s))
+}
+
+/// Formats an amount of memory with the largest suffix that keeps it whole,
+/// i.e. `64G` or `1536M`
+pub fn format_mem(bytes: u64) -> String {
+    for (suffix, factor) in &[
+        ("T", 1u64 << 40),
+        ("G", 1 << 30),
+        ("M", 1 << 20),
+        ("K", 1 << 10),
+    ] {
+        if bytes >= *factor && bytes.is_multiple_of(*factor) {
+            return format!("{}{}", bytes / factor, suffix);
+        }
+    }
+    bytes.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsing_memory() {
+        assert_eq!(parse_mem("123"), Ok(123));
+        assert_eq!(parse_mem("4K"), Ok(4096));
+        assert_eq!(parse_mem("512M"), Ok(512 << 20));
+        assert_eq!(parse_mem("64g"), Ok(64 << 30));
+        assert_eq!(parse_mem(" 2 T "), Ok(2 << 40));
+        for invalid in &["", "G", "1.5G", "-1", "12X", "20000000T"] {
+            assert!(parse_mem(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn formatting_memory() {
+        assert_eq!(format_mem(0), "0");
+        assert_eq!(format_mem(1023), "1023");
+        assert_eq!(format_mem(1024), "1K");
+        assert_eq!(format_mem(1536 << 20), "1536M");
+        assert_eq!(format_mem(64 << 30), "64G");
+        for &bytes in &[1u64, 1000, 3 << 10, 5 << 20, 7 << 40] {
+            assert_eq!(parse_mem(&format_mem(bytes)), Ok(bytes));
+        }
+    }
+}
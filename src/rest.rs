@@ -0,0 +1,283 @@
+/// rest.rs
+///
+/// Resource-style HTTP API.
+///
+/// Besides the protocol of `protocol.rs`, where every request is POSTed to
+/// `/` as a serialized `Request`, the daemon answers these routes:
+///
+/// | Route                      | Action                                  |
+/// |----------------------------|-----------------------------------------|
+/// | `GET /jobs`                | a page of queued and finished jobs      |
+/// | `POST /jobs`               | submit a job, `{"cmdline": "...", ...}` |
+/// | `GET /jobs/{id}`           | a single job                            |
+/// | `PATCH /jobs/{id}`         | change a queued job, `{"args": "..."}`  |
+/// | `DELETE /jobs/{id}`        | remove a queued or finished job         |
+/// | `POST /jobs/{id}/kill`     | send SIGTERM to a running job           |
+/// | `POST /jobs/{id}/hold`     | keep a queued job from being started    |
+/// | `POST /jobs/{id}/release`  | release a held job                      |
+/// | `POST /jobs/{id}/move`     | reorder the queue, `{"before": 12}`     |
+/// | `POST /jobs/{id}/resubmit` | submit a copy, `{"cmdline": "..."}`     |
+/// | `GET /queue/state`         | the queue state, `{"state": "Running"}` |
+/// | `PUT /queue/state`         | set the queue state, same body          |
+/// | `GET /queues`              | all queues with their state             |
+/// | `GET /resources`           | the host's resources and their use      |
+///
+/// `GET /jobs` takes the list, the criteria of `JobFilter`, the sort order and
+/// the page as query parameters, i.e. `/jobs?state=failed&sort=finished&limit=20`
+/// (see `parse_listing`). The queue routes refer to the default queue unless
+/// another one is given, i.e. `/queue/state?queue=imputation`. Each route is
+/// translated to a `Request` and handled like one. Responses carry the bare resource as JSON and errors
+/// are answered with `{"error": "..."}` and a matching status code.
+use reqwest::Url;
+use serde_json::{self, json, Value};
+use tiny_http::{Header, Method};
+
+use job_queue::{JobChanges, JobList, JobQuery, QueuePosition, QueueState, SubmitOptions};
+use protocol::{Request, Response};
+
+/// An HTTP response to a REST request
+pub struct Reply {
+    pub status: u16,
+    pub body: String,
+    pub headers: Vec<Header>,
+}
+
+impl Reply {
+    fn json(status: u16, body: &Value) -> Reply {
+        Reply {
+            status,
+            body: serde_json::to_string_pretty(body).unwrap(),
+            headers: vec![content_type("application/json")],
+        }
+    }
+
+    /// An error response with the given status code
+    pub fn error(status: u16, message: &str) -> Reply {
+        Reply::json(status, &json!({ "error": message }))
+    }
+
+    /// Translates the response to a successfully handled request
+    pub fn from_response(response: Response) -> Reply {
+        match response {
+            Response::SubmitJob(id) => {
+                let mut reply = Reply::json(201, &json!({ "id": id }));
+                reply.headers.push(
+                    Header::from_bytes(&b"Location"[..], format!("/jobs/{}", id).as_bytes())
+                        .unwrap(),
+                );
+                reply
+            }
+            Response::GetJobs(jobs) => Reply::json(200, &json!(jobs)),
+            Response::GetJob(job) => Reply::json(200, &json!(job)),
+            Response::JobPage(page) => Reply::json(200, &json!(page)),
+            Response::QueueState(state) => Reply::json(200, &json!({ "state": state })),
+            Response::Queues(queues) => Reply::json(200, &json!(queues)),
+            Response::Resources(usage) => Reply::json(200, &json!(usage)),
+            // the job has been signalled, but may not have terminated yet
+            Response::Ok => Reply::json(202, &json!({})),
+            Response::ImportReport(report) => Reply::json(200, &json!(report)),
+            Response::ConfigReloaded(restart_required) => {
+                Reply::json(200, &json!({ "restart_required": restart_required }))
+            }
+            Response::Error(message) => Reply::error(500, &message),
+        }
+    }
+}
+
+fn content_type(value: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], value.as_bytes()).unwrap()
+}
+
+/// Body of `POST /jobs`
+#[derive(Deserialize)]
+struct SubmitBody {
+    cmdline: String,
+    #[serde(flatten)]
+    options: SubmitOptions,
+}
+
+/// Body of `POST /jobs/{id}/resubmit`, which may be empty
+#[derive(Default, Deserialize)]
+struct ResubmitBody {
+    #[serde(default)]
+    cmdline: Option<String>,
+    #[serde(default)]
+    queue: Option<String>,
+}
+
+/// Body of `PUT /queue/state`
+#[derive(Deserialize)]
+struct QueueStateBody {
+    state: QueueState,
+    #[serde(default)]
+    queue: Option<String>,
+}
+
+/// Parses a JSON request body
+fn parse_body<'a, T: serde::Deserialize<'a>>(body: &'a str) -> Result<T, Reply> {
+    serde_json::from_str(body).map_err(|e| Reply::error(400, &format!("Invalid body: {}", e)))
+}
+
+/// Reads the listing from the query parameters of `GET /jobs`
+fn parse_listing(url: &Url) -> Result<Request, Reply> {
+    let mut list = JobList::All;
+    let mut query = JobQuery::default();
+    for (key, value) in url.query_pairs() {
+        let invalid = |e: &dyn ToString| {
+            Reply::error(
+                400,
+                &format!("Invalid {} '{}': {}", key, value, e.to_string()),
+            )
+        };
+        let time = || humantime::parse_rfc3339_weak(&value).map_err(|e| invalid(&e));
+        let number = || value.parse::<usize>().map_err(|e| invalid(&e));
+        match key.as_ref() {
+            "list" => {
+                list = match value.as_ref() {
+                    "queued" => JobList::Queued,
+                    "finished" => JobList::Finished,
+                    "all" => JobList::All,
+                    _ => return Err(invalid(&"use queued, finished or all")),
+                }
+            }
+            "state" => query.filter.state = Some(value.to_string()),
+            "appkey" => query.filter.appkey = Some(value.to_string()),
+            "owner" => query.filter.owner = Some(value.to_string()),
+            "queue" => query.filter.queue = Some(value.to_string()),
+            "cmdline" => query.filter.cmdline = Some(value.to_string()),
+            "resubmitted_from" => {
+                query.filter.resubmitted_from = Some(value.parse().map_err(|e| invalid(&e))?)
+            }
+            "since" => query.filter.since = Some(time()?),
+            "until" => query.filter.until = Some(time()?),
+            "sort" => query.sort = Some(value.parse().map_err(|e| invalid(&e))?),
+            "order" => {
+                query.descending = match value.as_ref() {
+                    "asc" => false,
+                    "desc" => true,
+                    _ => return Err(invalid(&"use asc or desc")),
+                }
+            }
+            "offset" => query.offset = number()?,
+            "limit" => query.limit = Some(number()?),
+            _ => return Err(Reply::error(400, &format!("Unknown parameter '{}'", key))),
+        }
+    }
+    Ok(Request::ListJobs { list, query })
+}
+
+/// Answers requests with a method the route does not support
+fn method_not_allowed(allowed: &str) -> Reply {
+    let mut reply = Reply::error(405, "Method not allowed");
+    reply
+        .headers
+        .push(Header::from_bytes(&b"Allow"[..], allowed.as_bytes()).unwrap());
+    reply
+}
+
+/// Translates a REST request to the protocol request that implements it.
+/// Returns the error reply if there is no such route or the request is
+/// malformed.
+pub fn route(method: &Method, url: &str, body: &str) -> Result<Request, Reply> {
+    let url = Url::parse("http://localhost")
+        .and_then(|base| base.join(url))
+        .map_err(|e| Reply::error(400, &e.to_string()))?;
+    let segments: Vec<&str> = url
+        .path_segments()
+        .map(|s| s.filter(|s| !s.is_empty()).collect())
+        .unwrap_or_default();
+
+    let job_id = |s: &str| {
+        s.parse::<u64>()
+            .map_err(|_| Reply::error(404, &format!("Invalid job ID '{}'", s)))
+    };
+
+    match segments.as_slice() {
+        ["jobs"] => match method {
+            Method::Get => parse_listing(&url),
+            Method::Post => {
+                let submit: SubmitBody = parse_body(body)?;
+                Ok(Request::SubmitJobWith {
+                    cmdline: submit.cmdline,
+                    options: submit.options,
+                })
+            }
+            _ => Err(method_not_allowed("GET, POST")),
+        },
+        ["jobs", id] => {
+            let id = job_id(id)?;
+            match method {
+                Method::Get => Ok(Request::GetJob(id)),
+                Method::Patch => {
+                    let changes: JobChanges = parse_body(body)?;
+                    Ok(Request::UpdateJob { id, changes })
+                }
+                Method::Delete => Ok(Request::RemoveJob(id)),
+                _ => Err(method_not_allowed("GET, PATCH, DELETE")),
+            }
+        }
+        ["jobs", id, action @ ("kill" | "hold" | "release")] => {
+            let id = job_id(id)?;
+            match (method, *action) {
+                (Method::Post, "kill") => Ok(Request::KillJob(id)),
+                (Method::Post, "hold") => Ok(Request::HoldJob(id)),
+                (Method::Post, _) => Ok(Request::ReleaseJob(id)),
+                _ => Err(method_not_allowed("POST")),
+            }
+        }
+        ["jobs", id, "move"] => {
+            let id = job_id(id)?;
+            match method {
+                Method::Post => {
+                    let to: QueuePosition = parse_body(body)?;
+                    Ok(Request::MoveJob { id, to })
+                }
+                _ => Err(method_not_allowed("POST")),
+            }
+        }
+        ["jobs", id, "resubmit"] => {
+            let id = job_id(id)?;
+            match method {
+                Method::Post => {
+                    let body: ResubmitBody = if body.trim().is_empty() {
+                        ResubmitBody::default()
+                    } else {
+                        parse_body(body)?
+                    };
+                    Ok(Request::ResubmitJob {
+                        id,
+                        cmdline: body.cmdline,
+                        queue: body.queue,
+                    })
+                }
+                _ => Err(method_not_allowed("POST")),
+            }
+        }
+        ["queue", "state"] => match method {
+            Method::Get => {
+                let queue = url
+                    .query_pairs()
+                    .find(|(key, _)| key == "queue")
+                    .map(|(_, value)| value.into_owned());
+                Ok(Request::GetQueueStateIn { queue })
+            }
+            Method::Put => {
+                let body: QueueStateBody = parse_body(body)?;
+                Ok(Request::SetQueueStateIn {
+                    queue: body.queue,
+                    state: body.state,
+                })
+            }
+            _ => Err(method_not_allowed("GET, PUT")),
+        },
+        ["queues"] => match method {
+            Method::Get => Ok(Request::GetQueues),
+            _ => Err(method_not_allowed("GET")),
+        },
+        ["resources"] => match method {
+            Method::Get => Ok(Request::GetResources),
+            _ => Err(method_not_allowed("GET")),
+        },
+        _ => Err(Reply::error(404, "No such resource")),
+    }
+}
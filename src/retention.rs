@@ -0,0 +1,257 @@
+/// retention.rs
+///
+/// Server-side retention rules for finished jobs.
+///
+/// The daemon periodically removes finished jobs that are older than the
+/// configured maximum age (which may differ per job state) and keeps at most
+/// a configured number of finished jobs. Removed jobs can be appended to a
+/// gzip-compressed JSON-lines archive.
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use config::{Config, ConfigError};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use job_queue::{Job, JobQueue, JobState};
+
+/// Default time between two retention runs
+pub const DEFAULT_RETENTION_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Keys of `[retention.max-age-per-state]`: the names of the states of
+/// finished jobs, and `exit-nonzero` for jobs that have terminated with a
+/// non-zero exit code
+const STATE_KEYS: [&str; 4] = ["terminated", "exit-nonzero", "killed", "failed"];
+
+/// Rules for removing finished jobs, read from the `[retention]` section
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Maximum age of a job's 'finished' timestamp
+    pub max_age: Option<Duration>,
+
+    /// Maximum age by job state name (i.e. `failed`) or `exit-nonzero`,
+    /// overrides `max_age`
+    pub max_age_per_state: HashMap<String, Duration>,
+
+    /// Maximum number of finished jobs to keep, oldest are removed first
+    pub max_count: Option<usize>,
+
+    /// gzip-compressed JSON-lines file that removed jobs are appended to
+    pub archive: Option<PathBuf>,
+
+    /// Time between two retention runs
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_age: None,
+            max_age_per_state: HashMap::new(),
+            max_count: None,
+            archive: None,
+            interval: DEFAULT_RETENTION_INTERVAL,
+        }
+    }
+}
+
+/// Parses a human-readable duration from the given config key, if present
+fn get_duration(conf: &Config, key: &str) -> std::result::Result<Option<Duration>, String> {
+    match conf.get_str(key) {
+        Ok(s) => humantime::parse_duration(&s)
+            .map(Some)
+            .map_err(|e| format!("{}: {}", key, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads a number that must not be negative from the given config key, if
+/// present
+fn get_count(conf: &Config, key: &str) -> std::result::Result<Option<usize>, String> {
+    match conf.get_int(key) {
+        Ok(n) if n < 0 => Err(format!("{}: must not be negative", key)),
+        Ok(n) => Ok(Some(n as usize)),
+        Err(ConfigError::NotFound(_)) => Ok(None),
+        Err(e) => Err(format!("{}: {}", key, e)),
+    }
+}
+
+impl RetentionPolicy {
+    /// Reads the retention rules from the `[retention]` section of the config
+    /// file. Without such a section, finished jobs are kept forever.
+    pub fn from_config(conf: &Config) -> std::result::Result<Self, String> {
+        let mut policy = RetentionPolicy {
+            max_age: get_duration(conf, "retention.max-age")?,
+            max_count: get_count(conf, "retention.max-count")?,
+            archive: conf.get_str("retention.archive").ok().map(PathBuf::from),
+            ..RetentionPolicy::default()
+        };
+
+        if let Some(interval) = get_duration(conf, "retention.interval")? {
+            policy.interval = interval;
+        }
+
+        if let Ok(states) = conf.get_table("retention.max-age-per-state") {
+            for (state, age) in states {
+                let state = state.to_lowercase();
+                if !STATE_KEYS.contains(&state.as_str()) {
+                    return Err(format!(
+                        "retention.max-age-per-state.{}: unknown state, use one of {}",
+                        state,
+                        STATE_KEYS.join(", ")
+                    ));
+                }
+                let age = age
+                    .into_str()
+                    .map_err(|e| e.to_string())
+                    .and_then(|s| humantime::parse_duration(&s).map_err(|e| e.to_string()))
+                    .map_err(|e| format!("retention.max-age-per-state.{}: {}", state, e))?;
+                policy.max_age_per_state.insert(state, age);
+            }
+        }
+
+        Ok(policy)
+    }
+
+    /// Checks whether any rules are configured at all
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || !self.max_age_per_state.is_empty() || self.max_count.is_some()
+    }
+
+    /// Checks whether the given finished job has exceeded its maximum age
+    fn is_expired(&self, job: &Job, now: SystemTime) -> bool {
+        let nonzero_exit = match job.state {
+            JobState::Terminated(code) if code != 0 => self.max_age_per_state.get("exit-nonzero"),
+            _ => None,
+        };
+        let max_age = nonzero_exit
+            .or_else(|| self.max_age_per_state.get(job.state.name()))
+            .or(self.max_age.as_ref());
+
+        match (max_age, job.finished) {
+            (Some(max_age), Some(finished)) => now
+                .duration_since(finished)
+                .map(|age| age > *max_age)
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+    /// Returns all finished jobs that violate the rules, oldest first
+    pub fn expired_jobs(&self, q: &JobQueue, now: SystemTime) -> Vec<Job> {
+        let mut expired: Vec<Job> = q
+            .iter_finished()
+            .filter(|j| self.is_expired(j, now))
+            .cloned()
+            .collect();
+
+        if let Some(max_count) = self.max_count {
+            let ids: HashSet<u64> = expired.iter().map(|j| j.id).collect();
+            let remaining: Vec<&Job> = q.iter_finished().filter(|j| !ids.contains(&j.id)).collect();
+            let excess = remaining.len().saturating_sub(max_count);
+            expired.extend(remaining.into_iter().take(excess).cloned());
+        }
+
+        expired
+    }
+}
+
+/// Removes the given finished jobs from the queue
+pub fn take_jobs(q: &mut JobQueue, jobs: &[Job]) {
+    let ids: HashSet<u64> = jobs.iter().map(|j| j.id).collect();
+    q.remove_finished_where(|j| ids.contains(&j.id));
+}
+
+/// Appends the given jobs to a gzip-compressed JSON-lines archive, one job
+/// per line. Each call adds a new gzip member, which `zcat` handles fine.
+pub fn archive_jobs(archive: &Path, jobs: &[Job]) -> Result<()> {
+    if jobs.is_empty() {
+        return Ok(());
+    }
+
+    let f = OpenOptions::new().create(true).append(true).open(archive)?;
+    let mut gz = GzEncoder::new(f, Compression::default());
+    for job in jobs {
+        serde_json::to_writer(&mut gz, job)?;
+        gz.write_all(b"\n")?;
+    }
+    gz.finish()?.sync_data()?;
+
+    debug!(
+        "Archived {} jobs to {}",
+        jobs.len(),
+        archive.to_str().unwrap()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use job_queue::SubmitOptions;
+    use limits::AppkeyLimits;
+
+    fn config(entries: &[(&str, &str)]) -> Config {
+        let mut conf = Config::default();
+        for (key, value) in entries {
+            conf.set(key, *value).unwrap();
+        }
+        conf
+    }
+
+    /// Returns a queue with one finished job per given state
+    fn finished_queue(states: &[JobState]) -> JobQueue {
+        let mut q = JobQueue::new(0);
+        for state in states {
+            q.submit("a".to_string(), SubmitOptions::default());
+            let id = q.schedule(&AppkeyLimits::default()).unwrap().id;
+            q.finish(id, state.clone(), "".into(), "".into());
+        }
+        q
+    }
+
+    #[test]
+    fn negative_counts_and_unknown_states_are_rejected() {
+        let conf = config(&[("retention.max-count", "-1")]);
+        assert!(RetentionPolicy::from_config(&conf).is_err());
+        let conf = config(&[("retention.max-count", "many")]);
+        assert!(RetentionPolicy::from_config(&conf).is_err());
+        let conf = config(&[("retention.max-age-per-state.faild", "90 days")]);
+        assert!(RetentionPolicy::from_config(&conf).is_err());
+
+        let conf = config(&[
+            ("retention.max-count", "0"),
+            ("retention.max-age-per-state.exit-nonzero", "90 days"),
+        ]);
+        let policy = RetentionPolicy::from_config(&conf).unwrap();
+        assert_eq!(policy.max_count, Some(0));
+        assert_eq!(policy.max_age_per_state.len(), 1);
+    }
+
+    #[test]
+    fn nonzero_exits_can_be_kept_longer() {
+        let conf = config(&[
+            ("retention.max-age", "1 day"),
+            ("retention.max-age-per-state.exit-nonzero", "90 days"),
+        ]);
+        let policy = RetentionPolicy::from_config(&conf).unwrap();
+        let q = finished_queue(&[
+            JobState::Terminated(0),
+            JobState::Terminated(1),
+            JobState::Failed("no such file".to_string()),
+        ]);
+
+        let later = SystemTime::now() + Duration::from_secs(7 * 86400);
+        let expired: Vec<u64> = policy
+            .expired_jobs(&q, later)
+            .iter()
+            .map(|j| j.id)
+            .collect();
+        assert_eq!(expired, vec![1, 3]);
+    }
+}
@@ -0,0 +1,179 @@
+/// settings.rs
+///
+/// Daemon settings that can be reloaded at runtime.
+///
+/// On SIGHUP or a `ReloadConfig` request, the daemon re-reads the config
+/// file and merges it with its original command line, just like on startup.
+/// If the result is valid, appkeys and their limits, notify, mail and hook
+/// settings, log level, retention and shutdown settings are swapped in one
+/// go. Everything else (i.e. the port, the state file, the queues or the
+/// host's resources) is only reported as requiring a restart.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use config::{Config, FileFormat};
+use log::LevelFilter;
+use reqwest::Url;
+use structopt::StructOpt;
+
+use cliopts::{Opt, OptCommand};
+use hooks::Hooks;
+use limits::AppkeyLimits;
+use mailer::Mailer;
+use notifier::NotifyPolicy;
+use queues::Queues;
+use resources::Resources;
+use retention::RetentionPolicy;
+use shutdown::ShutdownPolicy;
+
+/// The settings of a running daemon
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// The config file that is read on reload, with its absolute path
+    pub config_file: PathBuf,
+
+    /// Application keys
+    pub appkeys: HashMap<String, PathBuf>,
+
+    /// Limits on running jobs per appkey
+    pub appkey_limits: AppkeyLimits,
+
+    /// URL called when a job has finished
+    pub notify_url: Option<Url>,
+
+    /// How job reports are delivered to the notify URL
+    pub notify: NotifyPolicy,
+
+    /// Mails sent when jobs have ended
+    pub mail: Option<Mailer>,
+
+    /// Local commands run on job events
+    pub hooks: Hooks,
+
+    /// Maximum log level
+    pub loglevel: LevelFilter,
+
+    /// Rules for removing finished jobs
+    pub retention: RetentionPolicy,
+
+    /// What to do with a running job on SIGTERM
+    pub shutdown: ShutdownPolicy,
+
+    /// Named queues, as set up on startup
+    pub queues: Queues,
+
+    /// CPUs and memory of the host, as set up on startup
+    pub resources: Option<Resources>,
+
+    /// Settings that cannot be changed at runtime, by config key
+    fixed: Vec<(&'static str, String)>,
+}
+
+/// Reads the given config file and merges it with the command line, the
+/// same way as on startup. The path of the config file on the command line
+/// may be relative to the directory that the daemon has left on startup,
+/// so its absolute path is passed instead.
+pub fn read_opt(config_file: &Path) -> Result<Opt, String> {
+    let mut opt = Opt::from_args();
+    opt.config = config_file.to_path_buf();
+
+    let mut conf = Config::default();
+    conf.merge(config::File::new(
+        opt.config.to_str().unwrap(),
+        FileFormat::Toml,
+    ))
+    .map_err(|e| format!("Failed to read configuration file: {}", e))?;
+    opt.merge_config(conf)?;
+
+    Ok(opt)
+}
+
+impl Settings {
+    /// Extracts and validates the daemon settings from the merged options
+    pub fn from_opt(opt: &Opt) -> Result<Self, String> {
+        let notify_url = match opt.cmd {
+            OptCommand::Daemon {
+                notify_url: Some(ref url),
+                ..
+            } => Some(Url::parse(url).map_err(|e| format!("notify-url: {}", e))?),
+            _ => None,
+        };
+
+        let loglevel = LevelFilter::from_str(&opt.loglevel)
+            .map_err(|_| format!("Invalid log level '{}'", opt.loglevel))?;
+
+        let mut fixed = vec![
+            ("port", opt.port.to_string()),
+            ("insecure", opt.insecure.to_string()),
+            ("state-file", format!("{:?}", opt.state_file)),
+            ("storage", opt.storage.clone()),
+            (
+                "journal-compact-after",
+                opt.journal_compact_after.to_string(),
+            ),
+            ("spool-dir", format!("{:?}", opt.spool_dir)),
+            ("queues", format!("{:?}", opt.queues)),
+            ("resources", format!("{:?}", opt.resources)),
+        ];
+        if let OptCommand::Daemon {
+            ref cert,
+            ref key,
+            ref pidfile,
+            ..
+        } = opt.cmd
+        {
+            fixed.push(("cert", format!("{:?}", cert)));
+            fixed.push(("key", format!("{:?}", key)));
+            fixed.push(("pidfile", format!("{:?}", pidfile)));
+        }
+
+        Ok(Settings {
+            config_file: opt.config.clone(),
+            appkeys: opt.appkeys.clone(),
+            appkey_limits: opt.appkey_limits.clone(),
+            notify_url,
+            notify: opt.notify.clone(),
+            mail: opt.mail.clone(),
+            hooks: opt.hooks.clone(),
+            loglevel,
+            retention: opt.retention.clone(),
+            shutdown: opt.shutdown.clone(),
+            queues: opt.queues.clone(),
+            resources: opt.resources,
+            fixed,
+        })
+    }
+
+    /// Logs an error for each appkey whose executable does not exist
+    pub fn check_appkeys(&self) {
+        for (k, v) in &self.appkeys {
+            if !v.exists() {
+                error!("Appkey '{}' points to non-existent file '{:#?}'", k, v);
+            }
+
+            debug!("Registered appkey '{}' => '{:#?}'", k, v);
+        }
+    }
+
+    /// Replaces all settings that can be changed at runtime with the ones
+    /// from `new`. Returns the names of the settings that differ, but only
+    /// take effect after a restart.
+    pub fn update(&mut self, new: Settings) -> Vec<String> {
+        let restart_required = self
+            .fixed
+            .iter()
+            .zip(new.fixed.iter())
+            .filter(|(old, new)| old != new)
+            .map(|(old, _)| old.0.to_owned())
+            .collect();
+
+        *self = Settings {
+            fixed: std::mem::take(&mut self.fixed),
+            queues: std::mem::take(&mut self.queues),
+            resources: self.resources,
+            ..new
+        };
+        restart_required
+    }
+}
@@ -0,0 +1,382 @@
+/// shutdown.rs
+///
+/// Graceful shutdown of the daemon on SIGTERM.
+///
+/// Once asked to terminate, the daemon stops starting new jobs and tells
+/// systemd that it is stopping. What happens to running jobs depends on the
+/// configured mode:
+///
+/// - `detach`: the jobs keep running and the next daemon reattaches to them
+/// - `wait`: the daemon waits for the jobs to terminate, at most for the
+///   configured timeout, after which they are left running as in `detach`
+/// - `requeue`: the jobs are sent SIGTERM, and SIGKILL if they are still
+///   running after the configured timeout. Once they have terminated, they
+///   are put back into the queue, so that they are started again from
+///   scratch by the next daemon.
+///
+/// Finally, the program state is saved and the process exits. A second
+/// SIGTERM skips the waiting and exits right away.
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::Config;
+use systemd::daemon;
+
+use job_queue::JobQueue;
+use state::State;
+
+/// Default time to wait for running jobs in `wait` and `requeue` mode
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time between two checks whether the running jobs have terminated
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Time to wait for killed jobs to be reaped and requeued
+const KILL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do with running jobs when the daemon is shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    Wait,
+    Requeue,
+    Detach,
+}
+
+impl FromStr for ShutdownMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "wait" => Ok(ShutdownMode::Wait),
+            "requeue" => Ok(ShutdownMode::Requeue),
+            "detach" => Ok(ShutdownMode::Detach),
+            _ => Err(format!(
+                "Unknown shutdown mode '{}', use wait, requeue or detach",
+                s
+            )),
+        }
+    }
+}
+
+/// Shutdown settings, read from `shutdown-mode` and `shutdown-timeout`
+#[derive(Debug, Clone)]
+pub struct ShutdownPolicy {
+    pub mode: ShutdownMode,
+    pub timeout: Duration,
+}
+
+impl Default for ShutdownPolicy {
+    fn default() -> Self {
+        ShutdownPolicy {
+            mode: ShutdownMode::Detach,
+            timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+}
+
+impl ShutdownPolicy {
+    /// Reads the shutdown settings from the config file
+    pub fn from_config(conf: &Config) -> Result<Self, String> {
+        let mut policy = ShutdownPolicy::default();
+
+        if let Ok(mode) = conf.get_str("shutdown-mode") {
+            policy.mode = mode.parse()?;
+        }
+        if let Ok(timeout) = conf.get_str("shutdown-timeout") {
+            policy.timeout = humantime::parse_duration(&timeout)
+                .map_err(|e| format!("shutdown-timeout: {}", e))?;
+        }
+
+        Ok(policy)
+    }
+}
+
+/// What a SIGTERM asks the daemon to do
+#[derive(Debug, PartialEq, Eq)]
+pub enum Termination {
+    /// Drain the queue according to the shutdown policy, then exit
+    Graceful,
+
+    /// Save the program state and exit right away
+    Immediate,
+}
+
+/// Tells the first SIGTERM, which starts a graceful shutdown, from later
+/// ones, which cut it short
+#[derive(Default)]
+pub struct Terminations {
+    received: bool,
+}
+
+impl Terminations {
+    /// Returns what the SIGTERM that has just been caught asks for
+    pub fn next(&mut self) -> Termination {
+        if std::mem::replace(&mut self.received, true) {
+            Termination::Immediate
+        } else {
+            Termination::Graceful
+        }
+    }
+}
+
+/// Sends a status line to systemd, ignoring errors
+fn notify_status(status: &str) {
+    let _ = daemon::notify(false, [(daemon::STATE_STATUS, status)].iter());
+}
+
+/// Saves the program state and terminates the process. Locks the queue
+/// before the program state, like the rest of the daemon.
+pub fn exit(job_queue: &Arc<(Mutex<JobQueue>, Condvar)>, state: &Arc<Mutex<State>>) -> ! {
+    let mut q = job_queue.0.lock().unwrap();
+    let mut state = state.lock().unwrap();
+    state.save(&mut q).expect("Could not write program state");
+    state.compact(&q).expect("Could not write program state");
+    info!("Program state saved, exiting");
+    std::process::exit(0);
+}
+
+/// Waits until no job is running anymore or the deadline has passed.
+/// Returns whether the jobs have terminated.
+fn wait_for_running_jobs(job_queue: &Arc<(Mutex<JobQueue>, Condvar)>, deadline: Instant) -> bool {
+    loop {
+        if job_queue
+            .0
+            .lock()
+            .unwrap()
+            .all_running_jobs()
+            .next()
+            .is_none()
+        {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Drains the queue according to the given policy, saves the program state
+/// and terminates the process
+pub fn shutdown(
+    job_queue: Arc<(Mutex<JobQueue>, Condvar)>,
+    state: Arc<Mutex<State>>,
+    policy: ShutdownPolicy,
+) -> ! {
+    let _ = daemon::notify(
+        false,
+        [
+            (daemon::STATE_STOPPING, "1"),
+            (daemon::STATE_STATUS, "Shutting down"),
+        ]
+        .iter(),
+    );
+
+    drain(&job_queue, &policy);
+
+    notify_status("Saving program state");
+    exit(&job_queue, &state)
+}
+
+/// Stops starting new jobs and deals with the running ones according to the
+/// given policy. Returns once the jobs have terminated or have been left
+/// running.
+fn drain(job_queue: &Arc<(Mutex<JobQueue>, Condvar)>, policy: &ShutdownPolicy) {
+    let running: Vec<u64> = {
+        let mut q = job_queue.0.lock().unwrap();
+        q.drain(policy.mode == ShutdownMode::Requeue);
+        q.all_running_jobs().map(|j| j.id).collect()
+    };
+
+    if !running.is_empty() {
+        let ids: Vec<String> = running.iter().map(|id| id.to_string()).collect();
+        let ids = ids.join(", ");
+        let deadline = Instant::now() + policy.timeout;
+        match policy.mode {
+            ShutdownMode::Detach => {
+                info!("[shutdown] Leaving jobs {} running", ids);
+            }
+            ShutdownMode::Wait => {
+                info!("[shutdown] Waiting for jobs {} to terminate", ids);
+                notify_status(&format!("Waiting for jobs {} to terminate", ids));
+                if !wait_for_running_jobs(job_queue, deadline) {
+                    warn!(
+                        "[shutdown] Jobs did not terminate within {}, leaving them running",
+                        humantime::format_duration(policy.timeout)
+                    );
+                }
+            }
+            ShutdownMode::Requeue => {
+                info!("[shutdown] Terminating jobs {} to requeue them", ids);
+                notify_status(&format!("Terminating jobs {} to requeue them", ids));
+                for id in &running {
+                    if let Err(e) = job_queue.0.lock().unwrap().send_sigterm(*id) {
+                        error!("[shutdown] Could not terminate job {}: {}", id, e);
+                    }
+                }
+                if !wait_for_running_jobs(job_queue, deadline) {
+                    warn!(
+                        "[shutdown] Jobs did not terminate within {}, killing them",
+                        humantime::format_duration(policy.timeout)
+                    );
+                    notify_status("Killing jobs to requeue them");
+                    let running: Vec<u64> = {
+                        let q = job_queue.0.lock().unwrap();
+                        q.all_running_jobs().map(|j| j.id).collect()
+                    };
+                    for id in &running {
+                        if let Err(e) = job_queue.0.lock().unwrap().send_sigkill(*id) {
+                            error!("[shutdown] Could not kill job {}: {}", id, e);
+                        }
+                    }
+                    // the queue runners requeue the jobs once they are reaped
+                    if !wait_for_running_jobs(job_queue, Instant::now() + KILL_TIMEOUT) {
+                        error!("[shutdown] Killed jobs have not terminated, leaving them running");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    use job_queue::{JobState, SubmitOptions};
+    use limits::AppkeyLimits;
+
+    /// Returns a queue whose first job is running
+    fn queue_with_running_job() -> Arc<(Mutex<JobQueue>, Condvar)> {
+        let mut q = JobQueue::new(0);
+        q.submit("a".to_string(), SubmitOptions::default());
+        q.submit("a".to_string(), SubmitOptions::default());
+        assert_eq!(q.schedule(&AppkeyLimits::default()).unwrap().id, 1);
+        Arc::new((Mutex::new(q), Condvar::new()))
+    }
+
+    fn policy(mode: ShutdownMode, timeout_ms: u64) -> ShutdownPolicy {
+        ShutdownPolicy {
+            mode,
+            timeout: Duration::from_millis(timeout_ms),
+        }
+    }
+
+    fn state_of_job_1(job_queue: &Arc<(Mutex<JobQueue>, Condvar)>) -> JobState {
+        job_queue
+            .0
+            .lock()
+            .unwrap()
+            .get_job(1)
+            .unwrap()
+            .state
+            .clone()
+    }
+
+    /// Starts the given shell command in its own process group as job 1 and
+    /// reaps it like a queue runner would
+    fn run_as_job_1(
+        job_queue: &Arc<(Mutex<JobQueue>, Condvar)>,
+        script: &str,
+    ) -> thread::JoinHandle<()> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        job_queue.0.lock().unwrap().assign_pid(1, child.id());
+
+        let job_queue = Arc::clone(job_queue);
+        thread::spawn(move || {
+            child.wait().unwrap();
+            let mut q = job_queue.0.lock().unwrap();
+            if q.requeue_on_exit() {
+                q.requeue(1);
+            } else {
+                q.finish(1, JobState::Terminated(0), "".into(), "".into());
+            }
+        })
+    }
+
+    #[test]
+    fn parsing_shutdown_settings() {
+        let conf = Config::default();
+        assert_eq!(
+            ShutdownPolicy::from_config(&conf).unwrap().mode,
+            ShutdownMode::Detach
+        );
+
+        let mut conf = Config::default();
+        conf.set("shutdown-mode", "Requeue").unwrap();
+        conf.set("shutdown-timeout", "2s").unwrap();
+        let policy = ShutdownPolicy::from_config(&conf).unwrap();
+        assert_eq!(policy.mode, ShutdownMode::Requeue);
+        assert_eq!(policy.timeout, Duration::from_secs(2));
+
+        conf.set("shutdown-mode", "later").unwrap();
+        assert!(ShutdownPolicy::from_config(&conf).is_err());
+    }
+
+    #[test]
+    fn second_sigterm_exits_immediately() {
+        let mut terminations = Terminations::default();
+        assert_eq!(terminations.next(), Termination::Graceful);
+        assert_eq!(terminations.next(), Termination::Immediate);
+        assert_eq!(terminations.next(), Termination::Immediate);
+    }
+
+    #[test]
+    fn detach_leaves_jobs_running() {
+        let job_queue = queue_with_running_job();
+        drain(&job_queue, &policy(ShutdownMode::Detach, 10_000));
+
+        let mut q = job_queue.0.lock().unwrap();
+        assert_eq!(q.get_job(1).unwrap().state, JobState::Running);
+        assert!(!q.requeue_on_exit());
+        // no more jobs are started
+        assert!(q.schedule(&AppkeyLimits::default()).is_none());
+    }
+
+    #[test]
+    fn wait_ends_with_the_jobs_or_the_timeout() {
+        let job_queue = queue_with_running_job();
+        let runner = run_as_job_1(&job_queue, "sleep 0.2");
+        drain(&job_queue, &policy(ShutdownMode::Wait, 10_000));
+        runner.join().unwrap();
+        assert_eq!(state_of_job_1(&job_queue), JobState::Terminated(0));
+
+        let job_queue = queue_with_running_job();
+        let start = Instant::now();
+        drain(&job_queue, &policy(ShutdownMode::Wait, 200));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert_eq!(state_of_job_1(&job_queue), JobState::Running);
+    }
+
+    #[test]
+    fn requeue_terminates_or_kills_jobs() {
+        let job_queue = queue_with_running_job();
+        let runner = run_as_job_1(&job_queue, "sleep 30");
+        let start = Instant::now();
+        drain(&job_queue, &policy(ShutdownMode::Requeue, 10_000));
+        runner.join().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(state_of_job_1(&job_queue), JobState::Queued);
+
+        // jobs that ignore SIGTERM are killed after the timeout
+        let job_queue = queue_with_running_job();
+        let runner = run_as_job_1(&job_queue, "trap '' TERM; sleep 30");
+        // give the shell time to install the trap
+        thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        drain(&job_queue, &policy(ShutdownMode::Requeue, 300));
+        runner.join().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(state_of_job_1(&job_queue), JobState::Queued);
+    }
+}
@@ -1,66 +1,111 @@
-use std::fs::{self, File};
-use std::io::Result;
+use std::io::{Error, ErrorKind, Result};
 use std::path::PathBuf;
 
 use job_queue::*;
+use storage::{JournalStorage, JsonStorage, SqliteStorage, Storage};
 
 /// Job IDs are incremented before they are assigned to jobs. Setting the
 /// default last job id to zero makes the first submitted job to get
 /// job id 1 assigned.
 const DEFAULT_STATE_LAST_ID: u64 = 0;
 
+/// Default storage backend
+pub const DEFAULT_STORAGE: &str = "json";
+
+/// Default number of journal events after which a snapshot is written
+pub const DEFAULT_JOURNAL_COMPACT_AFTER: u64 = 1000;
+
 /// Configuration of the program state object
 pub struct State {
-    state_file: PathBuf,
+    backend: Box<dyn Storage>,
 }
 
 impl State {
-    /// Configure the program state object
-    fn load(p: PathBuf) -> State {
-        State { state_file: p }
-    }
-
-    /// Configures the program state or uses defaults if the state file is not available
-    pub fn from(p: PathBuf) -> State {
+    /// Configures the program state to be stored in the given location using
+    /// the named storage backend (`json`, `sqlite` or `journal`).
+    /// `compact_after` only applies to the journal.
+    pub fn open(backend: &str, p: PathBuf, compact_after: u64) -> Result<State> {
         if !p.exists() {
             warn!(
                 "Cannot open state file {}. Using defaults.",
                 p.to_str().unwrap()
             );
-            State { state_file: p }
         } else {
             debug!("Loading program state from {}", p.to_str().unwrap());
-            State::load(p)
         }
+
+        let backend: Box<dyn Storage> = match backend {
+            "json" => Box::new(JsonStorage::new(p)),
+            "sqlite" => Box::new(SqliteStorage::open(p)?),
+            "journal" => Box::new(JournalStorage::open(p, compact_after)?),
+            _ => {
+                error!("Unknown storage backend '{}'", backend);
+                return Err(Error::from(ErrorKind::InvalidInput));
+            }
+        };
+
+        Ok(State { backend })
     }
 
     /// Loads the job queue from the configured program state
     pub fn load_queue(&self) -> JobQueue {
-        let s = fs::read_to_string(&self.state_file).unwrap_or("".to_owned());
-        let o = serde_json::from_str(&s);
-        if let Ok(q) = o {
-            q
-        } else {
-            warn!("Could not parse JobQueue from state file, returning default queue");
-            JobQueue::new(DEFAULT_STATE_LAST_ID)
+        match self.backend.load_queue() {
+            Ok(Some(q)) => q,
+            Ok(None) => JobQueue::new(DEFAULT_STATE_LAST_ID),
+            Err(e) => {
+                warn!(
+                    "Could not load JobQueue from program state ({}), returning default queue",
+                    e
+                );
+                JobQueue::new(DEFAULT_STATE_LAST_ID)
+            }
         }
     }
 
-    /// Stores the given job queue into the configured program state
-    pub fn save(&self, q: &JobQueue) -> Result<()> {
-        let f = File::create(&self.state_file);
-        if let Err(e) = f {
-            error!(
-                "Cannot create or open state file {}: {:?}",
-                self.state_file.to_str().unwrap(),
-                e
-            );
-            Err(e)
-        } else {
-            let mut f = f.unwrap();
-            serde_json::to_writer_pretty(&mut f, q)?;
-            debug!("State file {} updated.", self.state_file.to_str().unwrap());
-            Ok(())
+    /// Stores the given job queue into the configured program state,
+    /// passing on the changes recorded by the queue since the last save.
+    /// If storing fails, the changes are kept for the next attempt.
+    pub fn save(&mut self, q: &mut JobQueue) -> Result<()> {
+        let events = q.take_events();
+        let result = self.backend.save(q, &events);
+        if result.is_err() {
+            q.return_events(events);
         }
+        result
+    }
+
+    /// Writes a full copy of the job queue, i.e. before shutting down
+    pub fn compact(&mut self, q: &JobQueue) -> Result<()> {
+        self.backend.compact(q)
+    }
+
+    /// Looks up queued and finished jobs of the given job queue, using the
+    /// configured program state where it can be searched more efficiently
+    pub fn find_jobs(&self, q: &JobQueue, filter: &JobFilter) -> Result<Vec<Job>> {
+        self.backend.find_jobs(q, filter)
+    }
+
+    /// Returns a page of the queued and finished jobs of the given job queue,
+    /// using the configured program state where it can be searched more
+    /// efficiently
+    pub fn query_jobs(&self, q: &JobQueue, query: &JobQuery) -> Result<JobPage> {
+        self.backend.query_jobs(q, query)
+    }
+
+    /// Copies the job queue stored in another program state, i.e. when
+    /// migrating from the JSON state file to an SQLite database.
+    /// Returns the number of jobs copied.
+    pub fn import_from(&mut self, other: &State) -> Result<usize> {
+        let mut q = match other.backend.load_queue()? {
+            Some(q) => q,
+            None => {
+                error!("Source program state is empty or does not exist");
+                return Err(Error::from(ErrorKind::NotFound));
+            }
+        };
+
+        self.save(&mut q)?;
+        self.compact(&q)?;
+        Ok(q.iter_queued().count() + q.iter_finished().count())
     }
 }
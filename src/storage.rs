@@ -0,0 +1,1020 @@
+/// storage.rs
+///
+/// Persistence backends for the job queue.
+///
+/// The daemon keeps the authoritative `JobQueue` in memory and hands it to
+/// the configured backend after every modification. Backends are free to
+/// decide how much of the queue they actually have to write:
+///
+/// * `JsonStorage` rewrites a single pretty-printed JSON file, which is easy
+///   to inspect but gets slow with a long job history.
+///
+/// * `SqliteStorage` keeps one row per job in an embedded SQLite database and
+///   only touches rows that have changed since the last save. The state,
+///   appkey, owner and submission time of each job are stored in indexed
+///   columns so that the job history can be queried efficiently. Listings
+///   are filtered, sorted and paged by SQLite.
+///
+/// * `JournalStorage` appends the events recorded by the job queue to a
+///   journal file and only writes a full snapshot every now and then. On
+///   startup, the snapshot is loaded and the journal is replayed on top.
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Connection, OptionalExtension, ToSql};
+
+use job_queue::{
+    Job, JobFilter, JobPage, JobQuery, JobQueue, QueueEvent, QueueState, SortKey, DEFAULT_QUEUE,
+};
+
+/// A place where the job queue can be stored and restored from
+pub trait Storage: Send {
+    /// Loads the job queue. Returns `None` if nothing has been stored yet.
+    fn load_queue(&self) -> Result<Option<JobQueue>>;
+
+    /// Stores the given job queue. `events` are the changes made to the
+    /// queue since the last call.
+    fn save(&mut self, q: &JobQueue, events: &[QueueEvent]) -> Result<()>;
+
+    /// Writes the full job queue so that nothing has to be replayed on the
+    /// next start. Only meaningful for backends that store changes.
+    fn compact(&mut self, _q: &JobQueue) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns all jobs, queued and finished, that match the given filter.
+    /// `q` is the job queue last saved. Backends that cannot query their
+    /// contents any faster than reading them all search `q` instead.
+    fn find_jobs(&self, q: &JobQueue, filter: &JobFilter) -> Result<Vec<Job>> {
+        let mut jobs: Vec<Job> = q
+            .iter_queued()
+            .chain(q.iter_finished())
+            .filter(|j| filter.matches(j))
+            .cloned()
+            .collect();
+        jobs.sort_by_key(|j| j.id);
+        Ok(jobs)
+    }
+
+    /// Returns a page of the jobs, ordered by ID unless the query gives a
+    /// sort key
+    fn query_jobs(&self, q: &JobQueue, query: &JobQuery) -> Result<JobPage> {
+        Ok(query.apply(self.find_jobs(q, &query.filter)?.iter()))
+    }
+}
+
+/// Converts an SQLite error into an I/O error, which is what the rest of the
+/// program deals with
+fn sql_error(e: rusqlite::Error) -> Error {
+    Error::other(e)
+}
+
+/// Converts a time stamp to seconds since the epoch for the indexed columns
+fn to_epoch(t: SystemTime) -> i64 {
+    t.duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+        .as_secs() as i64
+}
+
+/// Stores the whole job queue as a single JSON file
+pub struct JsonStorage {
+    state_file: PathBuf,
+}
+
+impl JsonStorage {
+    /// Uses the given file for storage. The file does not need to exist yet.
+    pub fn new(state_file: PathBuf) -> Self {
+        JsonStorage { state_file }
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load_queue(&self) -> Result<Option<JobQueue>> {
+        if !self.state_file.exists() {
+            return Ok(None);
+        }
+        let s = fs::read_to_string(&self.state_file)?;
+        serde_json::from_str(&s)
+            .map(Some)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn save(&mut self, q: &JobQueue, _events: &[QueueEvent]) -> Result<()> {
+        let mut f = File::create(&self.state_file).map_err(|e| {
+            error!(
+                "Cannot create or open state file {}: {:?}",
+                self.state_file.to_str().unwrap(),
+                e
+            );
+            e
+        })?;
+        serde_json::to_writer_pretty(&mut f, q)?;
+        debug!("State file {} updated.", self.state_file.to_str().unwrap());
+        Ok(())
+    }
+}
+
+/// Name of the list a job is stored in, as used in the `list` column
+const LIST_QUEUED: &str = "queued";
+const LIST_FINISHED: &str = "finished";
+
+/// Database schema. Jobs are stored as JSON in the `data` column, the other
+/// columns are copies of job properties for ordering and querying.
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS jobs (
+        id INTEGER PRIMARY KEY,
+        list TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        state TEXT NOT NULL,
+        appkey TEXT NOT NULL,
+        owner TEXT,
+        scheduled INTEGER NOT NULL,
+        finished INTEGER,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS jobs_list ON jobs (list, position);
+    CREATE INDEX IF NOT EXISTS jobs_state ON jobs (state);
+    CREATE INDEX IF NOT EXISTS jobs_appkey ON jobs (appkey);
+    CREATE INDEX IF NOT EXISTS jobs_owner ON jobs (owner);
+    CREATE INDEX IF NOT EXISTS jobs_scheduled ON jobs (scheduled);
+";
+
+/// Where a job is stored in the `jobs` table. Finished jobs are ordered by
+/// their time stamps and always have position 0, so that removing old jobs
+/// from the history does not touch all others.
+#[derive(PartialEq, Clone, Copy)]
+struct JobRow {
+    list: &'static str,
+    position: usize,
+}
+
+/// `last_id`, the state of the default queue and the states of the others
+type Meta = (u64, QueueState, BTreeMap<String, QueueState>);
+
+/// Stores jobs in an SQLite database, one row per job
+pub struct SqliteStorage {
+    conn: Connection,
+
+    /// Where the jobs in the database are stored, by job ID. Used to write
+    /// only jobs that are new or have changed, and to move the others.
+    written: HashMap<u64, JobRow>,
+
+    /// Last written `last_id` and queue states
+    written_meta: Option<Meta>,
+}
+
+impl SqliteStorage {
+    /// Opens or creates the database at the given location
+    pub fn open(db_file: PathBuf) -> Result<Self> {
+        let conn = Connection::open(&db_file).map_err(sql_error)?;
+        conn.execute_batch(SQLITE_SCHEMA).map_err(sql_error)?;
+        debug!("Opened SQLite database {}", db_file.to_str().unwrap());
+
+        Ok(SqliteStorage {
+            conn,
+            written: HashMap::new(),
+            written_meta: None,
+        })
+    }
+
+    /// Reads a value from the `meta` table
+    fn get_meta(&self, key: &str) -> Result<Option<String>> {
+        self.conn
+            .query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(sql_error)
+    }
+
+    /// Reads all jobs of the given list in their queue order
+    fn load_list(&self, list: &str) -> Result<Vec<Job>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT data FROM jobs WHERE list = ?1 ORDER BY position, finished, id")
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map([list], |row| row.get::<_, String>(0))
+            .map_err(sql_error)?;
+
+        let mut jobs = Vec::new();
+        for data in rows {
+            let data = data.map_err(sql_error)?;
+            jobs.push(
+                serde_json::from_str(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            );
+        }
+        Ok(jobs)
+    }
+
+    /// Reads where the jobs in the database are stored
+    fn load_rows(&self) -> Result<HashMap<u64, JobRow>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT id, list, position FROM jobs")
+            .map_err(sql_error)?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })
+            .map_err(sql_error)?;
+
+        let mut written = HashMap::new();
+        for row in rows {
+            let (id, list, position) = row.map_err(sql_error)?;
+            let list = if list == LIST_QUEUED {
+                LIST_QUEUED
+            } else {
+                LIST_FINISHED
+            };
+            let position = position as usize;
+            written.insert(id as u64, JobRow { list, position });
+        }
+        Ok(written)
+    }
+
+    /// Reads `last_id` and the queue states, None if nothing has been stored yet
+    fn load_meta(&self) -> Result<Option<Meta>> {
+        let last_id = match self.get_meta("last_id")? {
+            Some(s) => s
+                .parse()
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            None => return Ok(None),
+        };
+        let state = match self.get_meta("state")? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => QueueState::Running,
+        };
+        let queue_states = match self.get_meta("queue_states")? {
+            Some(s) => serde_json::from_str(&s)?,
+            None => BTreeMap::new(),
+        };
+        Ok(Some((last_id, state, queue_states)))
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_queue(&self) -> Result<Option<JobQueue>> {
+        let (last_id, state, queue_states) = match self.load_meta()? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+
+        let queue = self.load_list(LIST_QUEUED)?;
+        let finished = self.load_list(LIST_FINISHED)?;
+        Ok(Some(JobQueue::from_parts(
+            last_id,
+            state,
+            queue_states,
+            queue,
+            finished,
+        )))
+    }
+
+    fn save(&mut self, q: &JobQueue, events: &[QueueEvent]) -> Result<()> {
+        // The cache is only valid for the database contents written by this
+        // instance, so fill it with what is stored before the first save.
+        if self.written_meta.is_none() {
+            self.written = self.load_rows()?;
+            self.written_meta = self.load_meta()?;
+        }
+
+        // Jobs that the events refer to are written in full. All others are
+        // only written if they are not in the database yet, i.e. when
+        // importing a whole queue, or moved if their position has changed.
+        let changed_ids: HashSet<u64> = events.iter().filter_map(QueueEvent::job_id).collect();
+        let rows = rows_of(q);
+        let meta = (
+            q.last_id(),
+            q.get_state(DEFAULT_QUEUE),
+            q.queue_states().clone(),
+        );
+
+        let tx = self.conn.transaction().map_err(sql_error)?;
+        let (mut written, mut moved) = (0, 0);
+
+        if self.written_meta.as_ref() != Some(&meta) {
+            tx.execute(
+                "INSERT OR REPLACE INTO meta (key, value)
+                    VALUES ('last_id', ?1), ('state', ?2), ('queue_states', ?3)",
+                [
+                    meta.0.to_string(),
+                    serde_json::to_string(&meta.1)?,
+                    serde_json::to_string(&meta.2)?,
+                ],
+            )
+            .map_err(sql_error)?;
+        }
+
+        for job in q.iter_queued().chain(q.iter_finished()) {
+            let row = rows[&job.id];
+            if !changed_ids.contains(&job.id) {
+                match self.written.get(&job.id) {
+                    Some(old) if *old == row => continue,
+                    Some(_) => {
+                        tx.execute(
+                            "UPDATE jobs SET list = ?1, position = ?2 WHERE id = ?3",
+                            rusqlite::params![row.list, row.position as i64, job.id as i64],
+                        )
+                        .map_err(sql_error)?;
+                        moved += 1;
+                        continue;
+                    }
+                    None => {}
+                }
+            }
+            tx.execute(
+                "INSERT OR REPLACE INTO jobs
+                    (id, list, position, state, appkey, owner, scheduled, finished, data)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                rusqlite::params![
+                    job.id as i64,
+                    row.list,
+                    row.position as i64,
+                    job.state.name(),
+                    job.appkey(),
+                    job.owner,
+                    to_epoch(job.scheduled),
+                    job.finished.map(to_epoch),
+                    serde_json::to_string(job)?,
+                ],
+            )
+            .map_err(sql_error)?;
+            written += 1;
+        }
+
+        for id in self.written.keys().filter(|id| !rows.contains_key(id)) {
+            tx.execute("DELETE FROM jobs WHERE id = ?1", [*id as i64])
+                .map_err(sql_error)?;
+            written += 1;
+        }
+
+        tx.commit().map_err(sql_error)?;
+        debug!(
+            "SQLite state updated, {} job rows written, {} moved.",
+            written, moved
+        );
+
+        self.written = rows;
+        self.written_meta = Some(meta);
+        Ok(())
+    }
+
+    fn find_jobs(&self, _q: &JobQueue, filter: &JobFilter) -> Result<Vec<Job>> {
+        let (conditions, params) = sql_conditions(filter);
+        self.select_jobs(
+            &format!("SELECT data FROM jobs{} ORDER BY id", conditions),
+            &params,
+        )
+    }
+
+    fn query_jobs(&self, _q: &JobQueue, query: &JobQuery) -> Result<JobPage> {
+        let (conditions, mut params) = sql_conditions(&query.filter);
+
+        let total: i64 = self
+            .conn
+            .query_row(
+                &format!("SELECT COUNT(*) FROM jobs{}", conditions),
+                rusqlite::params_from_iter(params.iter().map(|p| p.as_ref())),
+                |row| row.get(0),
+            )
+            .map_err(sql_error)?;
+
+        let direction = if query.descending { " DESC" } else { "" };
+        let order: Vec<String> = sql_order(query.sort)
+            .iter()
+            .chain(Some(&"id"))
+            .map(|column| format!("{}{}", column, direction))
+            .collect();
+        params.push(Box::new(query.limit.map_or(-1, |n| n as i64)));
+        params.push(Box::new(query.offset as i64));
+
+        let jobs = self.select_jobs(
+            &format!(
+                "SELECT data FROM jobs{} ORDER BY {} LIMIT ? OFFSET ?",
+                conditions,
+                order.join(", ")
+            ),
+            &params,
+        )?;
+        Ok(JobPage {
+            jobs,
+            total: total as usize,
+            offset: query.offset,
+        })
+    }
+}
+
+impl SqliteStorage {
+    /// Runs a query for the `data` column and parses the jobs
+    fn select_jobs(&self, sql: &str, params: &[Box<dyn ToSql>]) -> Result<Vec<Job>> {
+        let mut stmt = self.conn.prepare(sql).map_err(sql_error)?;
+        let rows = stmt
+            .query_map(
+                rusqlite::params_from_iter(params.iter().map(|p| p.as_ref())),
+                |row| row.get::<_, String>(0),
+            )
+            .map_err(sql_error)?;
+
+        let mut jobs = Vec::new();
+        for data in rows {
+            let data = data.map_err(sql_error)?;
+            jobs.push(
+                serde_json::from_str(&data).map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
+            );
+        }
+        Ok(jobs)
+    }
+}
+
+/// Returns the SQL expression for a time stamp of the job data, compared
+/// as a row value of seconds and nanoseconds
+fn sql_time(field: &str) -> String {
+    format!(
+        "(json_extract(data, '$.{0}.secs_since_epoch'), json_extract(data, '$.{0}.nanos_since_epoch'))",
+        field
+    )
+}
+
+/// Translates a filter into a `WHERE` clause that selects exactly the jobs
+/// that `JobFilter::matches` accepts. Time stamps are compared on the
+/// indexed columns first, which only have second precision, and then on the
+/// job data.
+fn sql_conditions(filter: &JobFilter) -> (String, Vec<Box<dyn ToSql>>) {
+    let mut conditions: Vec<String> = Vec::new();
+    let mut params: Vec<Box<dyn ToSql>> = Vec::new();
+
+    let times = [
+        ("scheduled", ">=", ">=", filter.since),
+        ("scheduled", "<=", "<", filter.until),
+        ("finished", "<=", "<", filter.finished_before),
+    ];
+    for &(column, rounded_op, op, t) in &times {
+        if let Some(t) = t {
+            let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+            conditions.push(format!("{} {} ?", column, rounded_op));
+            params.push(Box::new(to_epoch(t)));
+            conditions.push(format!("{} {} (?, ?)", sql_time(column), op));
+            params.push(Box::new(since_epoch.as_secs() as i64));
+            params.push(Box::new(since_epoch.subsec_nanos()));
+        }
+    }
+
+    if let Some(ref state) = filter.state {
+        conditions.push("state = ?".to_owned());
+        params.push(Box::new(state.clone()));
+    }
+    if let Some(ref appkey) = filter.appkey {
+        conditions.push("appkey = ?".to_owned());
+        params.push(Box::new(appkey.clone()));
+    }
+    if let Some(ref owner) = filter.owner {
+        conditions.push("owner = ?".to_owned());
+        params.push(Box::new(owner.clone()));
+    }
+    if let Some(ref queue) = filter.queue {
+        conditions.push("json_extract(data, '$.queue') = ?".to_owned());
+        params.push(Box::new(queue.clone()));
+    }
+    if let Some(ref text) = filter.cmdline {
+        conditions.push("instr(json_extract(data, '$.cmdline'), ?) > 0".to_owned());
+        params.push(Box::new(text.clone()));
+    }
+    if let Some(id) = filter.resubmitted_from {
+        conditions.push("json_extract(data, '$.resubmitted_from') = ?".to_owned());
+        params.push(Box::new(id as i64));
+    }
+
+    if conditions.is_empty() {
+        (String::new(), params)
+    } else {
+        (format!(" WHERE {}", conditions.join(" AND ")), params)
+    }
+}
+
+/// Returns the SQL expressions that order jobs like `SortKey::compare`
+fn sql_order(sort: Option<SortKey>) -> Vec<&'static str> {
+    match sort {
+        None | Some(SortKey::Id) => vec![],
+        Some(SortKey::Submitted) => vec![
+            "json_extract(data, '$.scheduled.secs_since_epoch')",
+            "json_extract(data, '$.scheduled.nanos_since_epoch')",
+        ],
+        Some(SortKey::Started) => vec![
+            "json_extract(data, '$.started.secs_since_epoch')",
+            "json_extract(data, '$.started.nanos_since_epoch')",
+        ],
+        Some(SortKey::Finished) => vec![
+            "json_extract(data, '$.finished.secs_since_epoch')",
+            "json_extract(data, '$.finished.nanos_since_epoch')",
+        ],
+        Some(SortKey::Appkey) => vec!["appkey"],
+        Some(SortKey::Owner) => vec!["owner"],
+        Some(SortKey::State) => vec!["state"],
+    }
+}
+
+/// Computes where the jobs of the given queue are stored in the database
+fn rows_of(q: &JobQueue) -> HashMap<u64, JobRow> {
+    let mut rows = HashMap::new();
+    for (position, job) in q.iter_queued().enumerate() {
+        let list = LIST_QUEUED;
+        rows.insert(job.id, JobRow { list, position });
+    }
+    for job in q.iter_finished() {
+        let list = LIST_FINISHED;
+        rows.insert(job.id, JobRow { list, position: 0 });
+    }
+    rows
+}
+
+/// First line of a journal
+#[derive(Serialize, Deserialize)]
+struct JournalHeader {
+    generation: u64,
+}
+
+/// Key of the snapshot's journal generation, stored next to the job queue
+const SNAPSHOT_GENERATION: &str = "journal_generation";
+
+/// Appends queue events to a journal file next to a JSON snapshot of the queue.
+/// The snapshot has the same format as the state file of `JsonStorage`, plus
+/// the generation of the journal that is to be replayed on top of it.
+///
+/// Every compaction starts a new generation: the snapshot is written with the
+/// new generation first, then the journal is replaced by an empty one of the
+/// same generation. If the daemon dies in between, the old journal is older
+/// than the snapshot and is not replayed.
+pub struct JournalStorage {
+    snapshot: JsonStorage,
+
+    /// Location of the journal, `<state file>.journal`
+    journal_file: PathBuf,
+
+    /// Generation of the snapshot and the current journal
+    generation: u64,
+
+    /// Number of events in the journal
+    journal_len: u64,
+
+    /// Number of events after which a new snapshot is written
+    compact_after: u64,
+}
+
+impl JournalStorage {
+    /// Uses the given snapshot file and a journal file next to it. A new
+    /// snapshot is written once the journal holds `compact_after` events.
+    pub fn open(state_file: PathBuf, compact_after: u64) -> Result<Self> {
+        let mut journal_file = state_file.clone().into_os_string();
+        journal_file.push(".journal");
+        let journal_file = PathBuf::from(journal_file);
+
+        let mut storage = JournalStorage {
+            snapshot: JsonStorage::new(state_file),
+            journal_file,
+            generation: 0,
+            journal_len: 0,
+            compact_after,
+        };
+        if let Some((_, generation)) = storage.load_snapshot()? {
+            storage.generation = generation;
+        }
+        let (journal_generation, events) = storage.read_journal()?;
+        if journal_generation < storage.generation {
+            warn!(
+                "Discarding journal {}, it is already part of the snapshot",
+                storage.journal_file.to_str().unwrap()
+            );
+            storage.new_journal()?;
+        } else {
+            storage.journal_len = events.len() as u64;
+        }
+        debug!(
+            "Journal {} holds {} events",
+            storage.journal_file.to_str().unwrap(),
+            storage.journal_len
+        );
+        Ok(storage)
+    }
+
+    /// Reads the snapshot and its generation
+    fn load_snapshot(&self) -> Result<Option<(JobQueue, u64)>> {
+        let path = &self.snapshot.state_file;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut value: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let generation = value
+            .as_object_mut()
+            .and_then(|o| o.remove(SNAPSHOT_GENERATION))
+            .and_then(|g| g.as_u64())
+            .unwrap_or(0);
+        let q = serde_json::from_value(value).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Some((q, generation)))
+    }
+
+    /// Reads the generation of the journal and all complete events from it.
+    /// A damaged last line, i.e. after a crash during writing, ends the
+    /// journal.
+    fn read_journal(&self) -> Result<(u64, Vec<QueueEvent>)> {
+        if !self.journal_file.exists() {
+            return Ok((self.generation, Vec::new()));
+        }
+
+        let mut generation = 0;
+        let mut events = Vec::new();
+        for (n, line) in BufReader::new(File::open(&self.journal_file)?)
+            .lines()
+            .enumerate()
+        {
+            let line = line?;
+            if n == 0 {
+                let header: JournalHeader = serde_json::from_str(&line)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                generation = header.generation;
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(event) => events.push(event),
+                Err(e) => {
+                    warn!(
+                        "Ignoring remainder of journal {} after {} events: {}",
+                        self.journal_file.to_str().unwrap(),
+                        events.len(),
+                        e
+                    );
+                    break;
+                }
+            }
+        }
+        Ok((generation, events))
+    }
+
+    /// Replaces the journal with an empty one of the current generation
+    fn new_journal(&mut self) -> Result<()> {
+        let mut tmp_file = self.journal_file.clone().into_os_string();
+        tmp_file.push(".tmp");
+        let tmp_file = PathBuf::from(tmp_file);
+
+        let mut f = File::create(&tmp_file)?;
+        serde_json::to_writer(
+            &mut f,
+            &JournalHeader {
+                generation: self.generation,
+            },
+        )?;
+        f.write_all(b"\n")?;
+        f.sync_all()?;
+        fs::rename(&tmp_file, &self.journal_file)?;
+        self.journal_len = 0;
+        Ok(())
+    }
+}
+
+impl Storage for JournalStorage {
+    fn load_queue(&self) -> Result<Option<JobQueue>> {
+        let (snapshot, generation) = match self.load_snapshot()? {
+            Some((q, generation)) => (Some(q), generation),
+            None => (None, 0),
+        };
+        let (journal_generation, events) = self.read_journal()?;
+        if events.is_empty() || journal_generation < generation {
+            return Ok(snapshot);
+        }
+
+        debug!("Replaying {} journal events", events.len());
+        let mut q = snapshot.unwrap_or_else(|| JobQueue::new(0));
+        for event in &events {
+            q.apply(event);
+        }
+        Ok(Some(q))
+    }
+
+    fn save(&mut self, q: &JobQueue, events: &[QueueEvent]) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+
+        let mut buf = Vec::new();
+        if !self.journal_file.exists() {
+            serde_json::to_writer(
+                &mut buf,
+                &JournalHeader {
+                    generation: self.generation,
+                },
+            )?;
+            buf.push(b'\n');
+        }
+        for event in events {
+            serde_json::to_writer(&mut buf, event)?;
+            buf.push(b'\n');
+        }
+
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.journal_file)?;
+        // Cut off a partially written batch, so that the events are not
+        // replayed twice once they are appended again
+        let len = f.metadata()?.len();
+        if let Err(e) = f.write_all(&buf).and_then(|_| f.sync_data()) {
+            if let Err(e) = f.set_len(len) {
+                error!("Could not truncate journal after a failed write: {}", e);
+            }
+            return Err(e);
+        }
+        self.journal_len += events.len() as u64;
+
+        // The events are safe in the journal at this point, so a failed
+        // snapshot must not make the caller append them again
+        if self.journal_len >= self.compact_after {
+            if let Err(e) = self.compact(q) {
+                error!("Could not write journal snapshot: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self, q: &JobQueue) -> Result<()> {
+        // Write the snapshot to a temporary file first and rename it, so that
+        // a crash leaves either the old or the new snapshot behind. The old
+        // journal is ignored once the new snapshot is in place.
+        let mut tmp_file = self.snapshot.state_file.clone().into_os_string();
+        tmp_file.push(".tmp");
+        let tmp_file = PathBuf::from(tmp_file);
+
+        let mut value = serde_json::to_value(q)?;
+        if let Some(o) = value.as_object_mut() {
+            o.insert(
+                SNAPSHOT_GENERATION.to_string(),
+                serde_json::Value::from(self.generation + 1),
+            );
+        }
+        let mut f = File::create(&tmp_file)?;
+        serde_json::to_writer_pretty(&mut f, &value)?;
+        f.sync_all()?;
+        fs::rename(&tmp_file, &self.snapshot.state_file)?;
+        self.generation += 1;
+
+        let events = self.journal_len;
+        self.new_journal()?;
+        debug!(
+            "Journal compacted into snapshot {} after {} events",
+            self.snapshot.state_file.to_str().unwrap(),
+            events
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use job_queue::{JobState, QueuePosition, SubmitOptions};
+    use limits::AppkeyLimits;
+
+    /// Returns a queue with the given jobs, submitted at the given seconds
+    /// after the epoch
+    fn queue_of(jobs: &[(&str, f64)]) -> JobQueue {
+        let mut q = JobQueue::new(0);
+        for (cmdline, _) in jobs {
+            q.submit(cmdline.to_string(), SubmitOptions::default());
+        }
+        let queued = q
+            .iter_queued()
+            .zip(jobs)
+            .map(|(job, (_, at))| {
+                let mut job = job.clone();
+                job.scheduled = UNIX_EPOCH + Duration::from_secs_f64(*at);
+                job
+            })
+            .collect();
+        JobQueue::from_parts(
+            q.last_id(),
+            QueueState::Running,
+            BTreeMap::new(),
+            queued,
+            Vec::new(),
+        )
+    }
+
+    fn ids(jobs: &[Job]) -> Vec<u64> {
+        jobs.iter().map(|j| j.id).collect()
+    }
+
+    #[test]
+    fn sqlite_until_includes_jobs_within_the_same_second() {
+        let mut storage = SqliteStorage::open(PathBuf::from(":memory:")).unwrap();
+        let q = queue_of(&[("a", 1000.25), ("b", 1000.75), ("c", 1001.0)]);
+        storage.save(&q, &[]).unwrap();
+
+        let filter = JobFilter {
+            until: Some(UNIX_EPOCH + Duration::from_secs_f64(1000.5)),
+            ..JobFilter::default()
+        };
+        assert_eq!(ids(&storage.find_jobs(&q, &filter).unwrap()), vec![1]);
+
+        let filter = JobFilter {
+            until: Some(UNIX_EPOCH + Duration::from_secs(1001)),
+            ..JobFilter::default()
+        };
+        assert_eq!(ids(&storage.find_jobs(&q, &filter).unwrap()), vec![1, 2]);
+    }
+
+    #[test]
+    fn sqlite_pages_like_in_memory_listings() {
+        let mut storage = SqliteStorage::open(PathBuf::from(":memory:")).unwrap();
+        let q = queue_of(&[
+            ("b x", 3.5),
+            ("a y", 1.25),
+            ("c x", 2.0),
+            ("a x", 2.0),
+            ("b y", 1.75),
+        ]);
+        storage.save(&q, &[]).unwrap();
+
+        let filters = vec![
+            JobFilter::default(),
+            JobFilter {
+                since: Some(UNIX_EPOCH + Duration::from_secs_f64(1.5)),
+                cmdline: Some("x".to_string()),
+                ..JobFilter::default()
+            },
+        ];
+        let sorts = [None, Some(SortKey::Submitted), Some(SortKey::Appkey)];
+        let pages = [(0, None), (1, Some(2)), (4, Some(3))];
+        for filter in &filters {
+            for &sort in &sorts {
+                for &descending in &[false, true] {
+                    for &(offset, limit) in &pages {
+                        let query = JobQuery {
+                            filter: filter.clone(),
+                            sort,
+                            descending,
+                            offset,
+                            limit,
+                        };
+                        let expected = query.apply(q.iter_queued());
+                        let page = storage.query_jobs(&q, &query).unwrap();
+                        assert_eq!(ids(&page.jobs), ids(&expected.jobs), "{:?}", query);
+                        assert_eq!(page.total, expected.total);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sqlite_saves_changes_incrementally() {
+        let mut storage = SqliteStorage::open(PathBuf::from(":memory:")).unwrap();
+        let mut q = queue_of(&[("a", 1.0), ("b", 2.0), ("c", 3.0)]);
+        let events = q.take_events();
+        storage.save(&q, &events).unwrap();
+
+        // started job 1, moved job 3 to the top and removed job 2
+        assert_eq!(q.schedule(&AppkeyLimits::default()).unwrap().id, 1);
+        assert!(q.move_job(3, QueuePosition::Top).is_ok());
+        assert!(q.remove(2).is_ok());
+        q.finish(1, JobState::Terminated(0), "out".into(), "".into());
+        let events = q.take_events();
+        storage.save(&q, &events).unwrap();
+
+        let loaded = storage.load_queue().unwrap().unwrap();
+        let queued: Vec<u64> = loaded.iter_queued().map(|j| j.id).collect();
+        let finished: Vec<&Job> = loaded.iter_finished().collect();
+        assert_eq!(queued, vec![3]);
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].stdout, "out");
+        assert_eq!(loaded.last_id(), 3);
+    }
+
+    /// Returns an empty directory for the files of a test
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("qmanager-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn journal_is_replayed_onto_snapshot() {
+        let state_file = test_dir("journal-replay").join("state.json");
+        let mut storage = JournalStorage::open(state_file.clone(), 100).unwrap();
+        let mut q = JobQueue::new(0);
+        q.submit("a".to_string(), SubmitOptions::default());
+        let events = q.take_events();
+        storage.save(&q, &events).unwrap();
+        storage.compact(&q).unwrap();
+
+        q.submit("b".to_string(), SubmitOptions::default());
+        assert!(q.hold(2).is_ok());
+        let events = q.take_events();
+        storage.save(&q, &events).unwrap();
+
+        let loaded = JournalStorage::open(state_file, 100)
+            .unwrap()
+            .load_queue()
+            .unwrap()
+            .unwrap();
+        let queued: Vec<(u64, JobState)> = loaded
+            .iter_queued()
+            .map(|j| (j.id, j.state.clone()))
+            .collect();
+        assert_eq!(queued, vec![(1, JobState::Queued), (2, JobState::Held)]);
+    }
+
+    #[test]
+    fn journal_of_compacted_snapshot_is_not_replayed() {
+        let state_file = test_dir("journal-compacted").join("state.json");
+        let mut storage = JournalStorage::open(state_file.clone(), 100).unwrap();
+        let mut q = JobQueue::new(0);
+        for cmdline in &["a", "b", "c"] {
+            q.submit(cmdline.to_string(), SubmitOptions::default());
+        }
+        assert!(q.move_job(3, QueuePosition::Top).is_ok());
+        let events = q.take_events();
+        storage.save(&q, &events).unwrap();
+
+        // the daemon dies after writing the snapshot, before the journal is
+        // replaced
+        let journal = fs::read(&storage.journal_file).unwrap();
+        storage.compact(&q).unwrap();
+        fs::write(&storage.journal_file, journal).unwrap();
+
+        let mut storage = JournalStorage::open(state_file.clone(), 100).unwrap();
+        assert_eq!(storage.journal_len, 0);
+        let loaded = storage.load_queue().unwrap().unwrap();
+        let queued: Vec<u64> = loaded.iter_queued().map(|j| j.id).collect();
+        assert_eq!(queued, vec![3, 1, 2]);
+
+        // changes from now on are replayed again
+        assert!(q.remove(1).is_ok());
+        let events = q.take_events();
+        storage.save(&q, &events).unwrap();
+        let loaded = JournalStorage::open(state_file, 100)
+            .unwrap()
+            .load_queue()
+            .unwrap()
+            .unwrap();
+        let queued: Vec<u64> = loaded.iter_queued().map(|j| j.id).collect();
+        assert_eq!(queued, vec![3, 2]);
+    }
+
+    #[test]
+    fn failed_saves_keep_their_events() {
+        let state_file = test_dir("journal-failed-save").join("state.json");
+        let mut state = ::state::State::open("journal", state_file.clone(), 100).unwrap();
+        let mut q = JobQueue::new(0);
+        q.submit("a".to_string(), SubmitOptions::default());
+        state.save(&mut q).unwrap();
+
+        // the journal cannot be opened for appending
+        let mut journal_file = state_file.clone().into_os_string();
+        journal_file.push(".journal");
+        let mut moved_journal = journal_file.clone();
+        moved_journal.push(".moved");
+        fs::rename(&journal_file, &moved_journal).unwrap();
+        fs::create_dir(&journal_file).unwrap();
+        q.submit("b".to_string(), SubmitOptions::default());
+        assert!(q.hold(2).is_ok());
+        assert!(state.save(&mut q).is_err());
+
+        fs::remove_dir(&journal_file).unwrap();
+        fs::rename(&moved_journal, &journal_file).unwrap();
+        q.submit("c".to_string(), SubmitOptions::default());
+        state.save(&mut q).unwrap();
+
+        let loaded = JournalStorage::open(state_file, 100)
+            .unwrap()
+            .load_queue()
+            .unwrap()
+            .unwrap();
+        let queued: Vec<(u64, JobState)> = loaded
+            .iter_queued()
+            .map(|j| (j.id, j.state.clone()))
+            .collect();
+        assert_eq!(
+            queued,
+            vec![
+                (1, JobState::Queued),
+                (2, JobState::Held),
+                (3, JobState::Queued)
+            ]
+        );
+    }
+}
@@ -0,0 +1,340 @@
+/// table.rs
+///
+/// Renders job listings for the terminal.
+///
+/// By default, each job is one row of an aligned table with its ID, appkey,
+/// owner, state, submission time (relative to now), run time and exit
+/// status. The command line fills the rest of the line and is cut off at the
+/// terminal width. If stdout is a terminal, states are coloured. `--wide`
+/// adds all time stamps and the PID and never cuts off the command line,
+/// `--format` prints each job through a template instead (see `render`).
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use job_queue::{Job, JobState};
+use notifier::format_time;
+use queues::QueueInfo;
+use template;
+
+/// Width assumed for the command line if the terminal is too narrow
+const MIN_CMDLINE_WIDTH: usize = 20;
+
+/// How job listings are printed
+#[derive(Debug, Default, Clone)]
+pub struct TableStyle {
+    /// Show all details and full command lines
+    pub wide: bool,
+
+    /// Print each job through this template instead of a table
+    pub format: Option<String>,
+}
+
+/// Returns whether stdout is a terminal
+pub fn stdout_is_tty() -> bool {
+    nix::unistd::isatty(nix::libc::STDOUT_FILENO).unwrap_or(false)
+}
+
+/// Returns the width of the terminal attached to stdout, if any
+fn terminal_width() -> Option<usize> {
+    if !stdout_is_tty() {
+        return None;
+    }
+    let mut size: nix::libc::winsize = unsafe { std::mem::zeroed() };
+    let ret = unsafe {
+        nix::libc::ioctl(
+            nix::libc::STDOUT_FILENO,
+            nix::libc::TIOCGWINSZ,
+            &mut size as *mut nix::libc::winsize,
+        )
+    };
+    if ret == 0 && size.ws_col > 0 {
+        Some(size.ws_col as usize)
+    } else {
+        None
+    }
+}
+
+/// Formats a duration with its two most significant units, i.e. `3h 12m`
+pub fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}d {}h", days, hours)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m {}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Formats a point in time relative to now, i.e. `5m ago`
+fn format_ago(t: SystemTime) -> String {
+    match SystemTime::now().duration_since(t) {
+        Ok(d) => {
+            let d = Duration::from_secs(d.as_secs());
+            match d.as_secs() {
+                0..=59 => format!("{}s ago", d.as_secs()),
+                60..=3599 => format!("{}m ago", d.as_secs() / 60),
+                3600..=86399 => format!("{}h ago", d.as_secs() / 3600),
+                _ => format!("{}d ago", d.as_secs() / 86400),
+            }
+        }
+        Err(_) => "just now".to_string(),
+    }
+}
+
+/// Returns how long the job has run, up to now if it is still running
+fn runtime(job: &Job) -> Option<Duration> {
+    let started = job.started?;
+    job.finished
+        .unwrap_or_else(SystemTime::now)
+        .duration_since(started)
+        .ok()
+}
+
+/// Returns the exit status in short form, i.e. `0`, `sig 15` or `error`
+fn exit_status(job: &Job) -> String {
+    match job.state {
+        JobState::Terminated(code) => code.to_string(),
+        JobState::Killed(signum) => format!("sig {}", signum),
+        JobState::Failed(_) => "error".to_string(),
+        JobState::Queued | JobState::Held | JobState::Running => "-".to_string(),
+    }
+}
+
+/// Estimates the run time per appkey as the average of the given jobs that
+/// have terminated successfully
+pub fn estimate_runtimes(jobs: &[Job]) -> HashMap<String, Duration> {
+    let mut sums: HashMap<String, (Duration, u32)> = HashMap::new();
+    for job in jobs.iter().filter(|j| j.state == JobState::Terminated(0)) {
+        if let Some(d) = runtime(job) {
+            let entry = sums
+                .entry(job.appkey().to_string())
+                .or_insert((Duration::from_secs(0), 0));
+            entry.0 += d;
+            entry.1 += 1;
+        }
+    }
+    sums.into_iter()
+        .map(|(appkey, (sum, n))| (appkey, sum / n))
+        .collect()
+}
+
+/// Returns the run time, or for queued jobs the estimated run time
+fn runtime_or_estimate(job: &Job, estimates: &HashMap<String, Duration>) -> String {
+    match (runtime(job), &job.state) {
+        (Some(d), _) => format_duration(d),
+        (None, JobState::Queued) | (None, JobState::Held) => estimates
+            .get(job.appkey())
+            .map(|d| format!("~{}", format_duration(*d)))
+            .unwrap_or_else(|| "-".to_string()),
+        (None, _) => "-".to_string(),
+    }
+}
+
+/// ANSI colour of a job state
+fn state_colour(state: &JobState) -> &'static str {
+    match state {
+        JobState::Queued => "\x1b[33m",
+        JobState::Held => "\x1b[34m",
+        JobState::Running => "\x1b[36m",
+        JobState::Terminated(0) => "\x1b[32m",
+        JobState::Terminated(_) | JobState::Failed(_) => "\x1b[31m",
+        JobState::Killed(_) => "\x1b[35m",
+    }
+}
+
+/// Cuts off text after the given number of characters
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        s.to_string()
+    } else {
+        let mut out: String = s.chars().take(width.saturating_sub(1)).collect();
+        out.push('…');
+        out
+    }
+}
+
+/// Replaces the placeholders in the given template: `{id}`, `{appkey}`,
+/// `{owner}`, `{queue}`, `{state}`, `{exit}`, `{submitted}`, `{started}`, `{finished}`,
+/// `{runtime}`, `{pid}`, `{cmdline}` and `{resubmitted_from}`. Escape
+/// sequences `\t` and `\n` are expanded.
+pub fn render(template: &str, job: &Job) -> String {
+    let time = |t: Option<SystemTime>| t.map(format_time).unwrap_or_default();
+
+    let mut values = HashMap::new();
+    values.insert("id", job.id.to_string());
+    values.insert("appkey", job.appkey().to_string());
+    values.insert("owner", job.owner.clone().unwrap_or_default());
+    values.insert("queue", job.queue.clone());
+    values.insert("state", job.state.name().to_string());
+    values.insert("exit", exit_status(job));
+    values.insert("submitted", format_time(job.scheduled));
+    values.insert("started", time(job.started));
+    values.insert("finished", time(job.finished));
+    values.insert(
+        "runtime",
+        runtime(job).map(format_duration).unwrap_or_default(),
+    );
+    values.insert("pid", job.pid.map(|p| p.to_string()).unwrap_or_default());
+    values.insert("cmdline", job.cmdline.clone());
+    values.insert(
+        "resubmitted_from",
+        job.resubmitted_from
+            .map(|id| id.to_string())
+            .unwrap_or_default(),
+    );
+
+    let template = template.replace("\\t", "\t").replace("\\n", "\n");
+    template::expand(&template, &values)
+}
+
+/// Prints the jobs as a table, or through the template of the style
+pub fn print_jobs(jobs: &[Job], style: &TableStyle, estimates: &HashMap<String, Duration>) {
+    if let Some(ref template) = style.format {
+        for job in jobs {
+            println!("{}", render(template, job));
+        }
+        return;
+    }
+    if jobs.is_empty() {
+        return;
+    }
+
+    let mut header = vec!["ID", "APPKEY", "OWNER", "STATE", "SUBMITTED"];
+    if style.wide {
+        header.extend(&["STARTED", "FINISHED"]);
+    }
+    header.extend(&["RUNTIME", "EXIT"]);
+    if style.wide {
+        header.push("PID");
+    }
+
+    let rows: Vec<Vec<String>> = jobs
+        .iter()
+        .map(|job| {
+            let time = |t: Option<SystemTime>| t.map(format_time).unwrap_or_else(|| "-".into());
+            let mut row = vec![
+                job.id.to_string(),
+                job.appkey().to_string(),
+                job.owner.clone().unwrap_or_else(|| "-".to_string()),
+                job.state.name().to_string(),
+            ];
+            if style.wide {
+                row.push(format_time(job.scheduled));
+                row.push(time(job.started));
+                row.push(time(job.finished));
+            } else {
+                row.push(format_ago(job.scheduled));
+            }
+            row.push(runtime_or_estimate(job, estimates));
+            row.push(exit_status(job));
+            if style.wide {
+                row.push(job.pid.map(|p| p.to_string()).unwrap_or_else(|| "-".into()));
+            }
+            row
+        })
+        .collect();
+
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].chars().count())
+                .chain(Some(header[i].len()))
+                .max()
+                .unwrap()
+        })
+        .collect();
+
+    // the command line gets whatever is left of the terminal
+    let used: usize = widths.iter().map(|w| w + 2).sum();
+    let cmdline_width = match terminal_width() {
+        Some(width) if !style.wide => Some(width.saturating_sub(used).max(MIN_CMDLINE_WIDTH)),
+        _ => None,
+    };
+    let colour = stdout_is_tty();
+    let state_column = 3;
+
+    let mut line = String::new();
+    for (h, w) in header.iter().zip(&widths) {
+        line.push_str(&format!("{:<w$}  ", h, w = w));
+    }
+    line.push_str("COMMAND");
+    if colour {
+        println!("\x1b[1m{}\x1b[0m", line);
+    } else {
+        println!("{}", line);
+    }
+
+    for (job, row) in jobs.iter().zip(&rows) {
+        let mut line = String::new();
+        for (i, (cell, w)) in row.iter().zip(&widths).enumerate() {
+            let cell = format!("{:<w$}  ", cell, w = w);
+            if colour && i == state_column {
+                line.push_str(state_colour(&job.state));
+                line.push_str(&cell);
+                line.push_str("\x1b[0m");
+            } else {
+                line.push_str(&cell);
+            }
+        }
+        match cmdline_width {
+            Some(width) => line.push_str(&truncate(&job.cmdline, width)),
+            None => line.push_str(&job.cmdline),
+        }
+        println!("{}", line);
+    }
+}
+
+/// Prints the queues as a table: name, state, slots, running and waiting jobs
+/// and accepted appkeys
+pub fn print_queues(queues: &[QueueInfo]) {
+    let header = ["QUEUE", "STATE", "SLOTS", "RUNNING", "WAITING", "APPKEYS"];
+    let rows: Vec<Vec<String>> = queues
+        .iter()
+        .map(|q| {
+            let appkeys = match q.appkeys {
+                None => "(all)".to_string(),
+                Some(ref appkeys) if appkeys.is_empty() => "(none)".to_string(),
+                Some(ref appkeys) => appkeys.join(", "),
+            };
+            vec![
+                q.name.clone(),
+                format!("{:?}", q.state).to_lowercase(),
+                q.slots.to_string(),
+                q.running.to_string(),
+                q.waiting.to_string(),
+                appkeys,
+            ]
+        })
+        .collect();
+
+    let widths: Vec<usize> = (0..header.len())
+        .map(|i| {
+            rows.iter()
+                .map(|r| r[i].chars().count())
+                .chain(Some(header[i].len()))
+                .max()
+                .unwrap()
+        })
+        .collect();
+
+    let line = |cells: Vec<&str>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, w)| format!("{:<w$}", cell, w = w))
+            .collect();
+        cells.join("  ").trim_end().to_string()
+    };
+    if stdout_is_tty() {
+        println!("\x1b[1m{}\x1b[0m", line(header.to_vec()));
+    } else {
+        println!("{}", line(header.to_vec()));
+    }
+    for row in &rows {
+        println!("{}", line(row.iter().map(|c| c.as_str()).collect()));
+    }
+}
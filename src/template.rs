@@ -0,0 +1,54 @@
+/// template.rs
+///
+/// Expands `{name}` placeholders in templates, used for mails and for
+/// `--format` of job listings.
+///
+/// The template is read once from left to right, so values are inserted
+/// as they are, even if they contain braces themselves. Unknown
+/// placeholders and unmatched braces are kept.
+use std::collections::HashMap;
+
+/// Replaces each `{name}` in the template with the value of `name`
+pub fn expand(template: &str, values: &HashMap<&str, String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        out.push_str(&rest[..open]);
+        let after = &rest[open + 1..];
+        let value = after
+            .find(['{', '}'])
+            .filter(|&close| after[close..].starts_with('}'))
+            .and_then(|close| values.get(&after[..close]).map(|v| (close, v)));
+
+        match value {
+            Some((close, value)) => {
+                out.push_str(value);
+                rest = &after[close + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_not_expanded_again() {
+        let mut values = HashMap::new();
+        values.insert("id", "7".to_string());
+        values.insert("cmdline", "echo {id} }{".to_string());
+
+        assert_eq!(
+            expand("{id}: {cmdline} {unknown} {{id}} {", &values),
+            "7: echo {id} }{ {unknown} {7} {"
+        );
+    }
+}
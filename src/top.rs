@@ -0,0 +1,762 @@
+/// top.rs
+///
+/// Full-screen view of the daemon, like top(1).
+///
+/// `qmanager top` shows the state of the queues, the running jobs with their
+/// elapsed time and allocated resources and the tail of the output of one of
+/// them, the queued jobs with the estimated time until they start, and the
+/// most recently finished jobs. The lists are fetched with the usual requests
+/// every second. The event stream (see `events.rs`) provides the output of
+/// running jobs, which is shown on the next refresh, and triggers a refresh
+/// as soon as a job or queue changes.
+///
+/// Keys: arrows select a job, `k` kills and `d` removes it (after asking),
+/// `h` holds or releases it, `l` or Enter opens its output in `$PAGER`, `s`
+/// starts or stops its queue, `r` refreshes and `q` quits.
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{self, BufReader, Result};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::style::{Attribute, Color, Print, SetAttribute, SetForegroundColor};
+use crossterm::{cursor, queue, terminal};
+use serde_json::{self, Value};
+
+use clicommands::send_request;
+use job_queue::{Job, JobList, JobQuery, JobState, QueueState, SortKey, DEFAULT_QUEUE};
+use protocol::{Request, Response};
+use queues::QueueInfo;
+use resources::{format_mem, ResourceUsage};
+use table::{estimate_runtimes, format_duration};
+
+/// Time between two refreshes if nothing happens
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Number of finished jobs fetched, for display and run time estimates
+const FINISHED_FETCHED: usize = 100;
+
+/// Bytes of output kept per running job
+const OUTPUT_TAIL_BYTES: usize = 16 * 1024;
+
+/// Time before reconnecting to the event stream
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Output of running jobs received from the event stream, by job ID
+type OutputTails = Arc<Mutex<HashMap<u64, String>>>;
+
+/// What the daemon reported on the last refresh
+#[derive(Default)]
+struct Snapshot {
+    queues: Vec<QueueInfo>,
+    resources: Option<ResourceUsage>,
+    queued: Vec<Job>,
+    finished: Vec<Job>,
+    estimates: HashMap<String, Duration>,
+}
+
+/// A line on the screen
+struct Line {
+    text: String,
+    colour: Option<Color>,
+    reverse: bool,
+    bold: bool,
+}
+
+impl Line {
+    fn new<S: Into<String>>(text: S) -> Line {
+        Line {
+            text: text.into(),
+            colour: None,
+            reverse: false,
+            bold: false,
+        }
+    }
+
+    fn bold(mut self) -> Line {
+        self.bold = true;
+        self
+    }
+
+    fn reverse(mut self, reverse: bool) -> Line {
+        self.reverse = reverse;
+        self
+    }
+
+    fn colour(mut self, colour: Color) -> Line {
+        self.colour = Some(colour);
+        self
+    }
+}
+
+/// Restores the terminal when dropped, also on errors and panics
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn enter() -> Result<TerminalGuard> {
+        terminal::enable_raw_mode()?;
+        queue!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+        io::stdout().flush()?;
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = queue!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = io::stdout().flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// An action that waits for confirmation
+#[derive(Clone, Copy)]
+enum Pending {
+    Kill(u64),
+    Remove(u64),
+}
+
+struct Top {
+    client: reqwest::Client,
+    url: reqwest::Url,
+    snapshot: Snapshot,
+    tails: OutputTails,
+    selected: Option<u64>,
+    pending: Option<Pending>,
+    message: String,
+}
+
+/// Colour of a job state, like in `table.rs`
+fn state_colour(state: &JobState) -> Color {
+    match state {
+        JobState::Queued => Color::Yellow,
+        JobState::Held => Color::Blue,
+        JobState::Running => Color::Cyan,
+        JobState::Terminated(0) => Color::Green,
+        JobState::Terminated(_) | JobState::Failed(_) => Color::Red,
+        JobState::Killed(_) => Color::Magenta,
+    }
+}
+
+fn elapsed(since: SystemTime) -> Duration {
+    SystemTime::now()
+        .duration_since(since)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+}
+
+/// Reads the event stream, collecting the output of jobs and flagging all
+/// other events in `changed`. Reconnects if the stream ends.
+fn follow_events(
+    client: reqwest::Client,
+    url: reqwest::Url,
+    tails: OutputTails,
+    changed: Arc<AtomicBool>,
+) {
+    let url = url.join("events").unwrap();
+    thread::Builder::new()
+        .name("Event Stream".to_owned())
+        .spawn(move || loop {
+            if let Ok(response) = client.get(url.clone()).send() {
+                let mut event = String::new();
+                for line in BufReader::new(response).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => break,
+                    };
+                    if let Some(name) = line.strip_prefix("event: ") {
+                        event = name.to_string();
+                    } else if let Some(data) = line.strip_prefix("data: ") {
+                        if event == "output" {
+                            append_output(&tails, data);
+                        } else {
+                            changed.store(true, Ordering::SeqCst);
+                        }
+                    }
+                }
+            }
+            thread::sleep(RECONNECT_DELAY);
+        })
+        .unwrap();
+}
+
+/// Appends the data of an output event to the tail of the job's output
+fn append_output(tails: &OutputTails, data: &str) {
+    let event: Value = match serde_json::from_str(data) {
+        Ok(event) => event,
+        Err(_) => return,
+    };
+    let (id, text) = match (event["id"].as_u64(), event["data"].as_str()) {
+        (Some(id), Some(text)) => (id, text),
+        _ => return,
+    };
+
+    let mut tails = tails.lock().unwrap();
+    let tail = tails.entry(id).or_default();
+    tail.push_str(text);
+    if tail.len() > OUTPUT_TAIL_BYTES {
+        let mut cut = tail.len() - OUTPUT_TAIL_BYTES;
+        while !tail.is_char_boundary(cut) {
+            cut += 1;
+        }
+        tail.drain(..cut);
+    }
+}
+
+impl Top {
+    /// Sends a request, turning error responses into an error message
+    fn request(&self, request: &Request) -> std::result::Result<Response, String> {
+        match send_request(&self.client, self.url.clone(), request, false) {
+            Ok(Response::Error(s)) => Err(s),
+            Ok(response) => Ok(response),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn list(&self, list: JobList, query: JobQuery) -> std::result::Result<Vec<Job>, String> {
+        match self.request(&Request::ListJobs { list, query })? {
+            Response::JobPage(page) => Ok(page.jobs),
+            response => Err(format!("Unexpected response: {:?}", response)),
+        }
+    }
+
+    fn refresh(&mut self) {
+        let result = (|| {
+            let queues = match self.request(&Request::GetQueues)? {
+                Response::Queues(queues) => queues,
+                response => return Err(format!("Unexpected response: {:?}", response)),
+            };
+            let resources = match self.request(&Request::GetResources)? {
+                Response::Resources(usage) => usage,
+                response => return Err(format!("Unexpected response: {:?}", response)),
+            };
+            let queued = self.list(JobList::Queued, JobQuery::default())?;
+            let finished = self.list(
+                JobList::Finished,
+                JobQuery {
+                    sort: Some(SortKey::Finished),
+                    descending: true,
+                    limit: Some(FINISHED_FETCHED),
+                    ..JobQuery::default()
+                },
+            )?;
+            Ok(Snapshot {
+                queues,
+                resources,
+                estimates: estimate_runtimes(&finished),
+                queued,
+                finished,
+            })
+        })();
+
+        match result {
+            Ok(snapshot) => {
+                self.snapshot = snapshot;
+                if self.selected.is_none_or(|id| self.job(id).is_none()) {
+                    self.selected = self.selectable().first().cloned();
+                }
+                // forget the output of jobs that are gone
+                let known: Vec<u64> = self.snapshot.queued.iter().map(|j| j.id).collect();
+                self.tails
+                    .lock()
+                    .unwrap()
+                    .retain(|id, _| known.contains(id));
+            }
+            Err(e) => self.message = format!("Could not refresh: {}", e),
+        }
+    }
+
+    fn job(&self, id: u64) -> Option<&Job> {
+        self.snapshot
+            .queued
+            .iter()
+            .chain(&self.snapshot.finished)
+            .find(|j| j.id == id)
+    }
+
+    /// IDs of all jobs in display order
+    fn selectable(&self) -> Vec<u64> {
+        self.snapshot
+            .queued
+            .iter()
+            .chain(&self.snapshot.finished)
+            .map(|j| j.id)
+            .collect()
+    }
+
+    fn move_selection(&mut self, delta: isize) {
+        let ids = self.selectable();
+        if ids.is_empty() {
+            return;
+        }
+        let pos = self
+            .selected
+            .and_then(|id| ids.iter().position(|&i| i == id))
+            .unwrap_or(0) as isize;
+        let pos = (pos + delta).max(0).min(ids.len() as isize - 1);
+        self.selected = Some(ids[pos as usize]);
+    }
+
+    fn confirm(&mut self, pending: Pending) {
+        let (verb, id) = match pending {
+            Pending::Kill(id) => ("Kill", id),
+            Pending::Remove(id) => ("Remove", id),
+        };
+        self.message = format!("{} job #{}? (y/n)", verb, id);
+        self.pending = Some(pending);
+    }
+
+    fn execute(&mut self, pending: Pending) {
+        let (request, done) = match pending {
+            Pending::Kill(id) => (Request::KillJob(id), format!("Sent SIGTERM to job #{}", id)),
+            Pending::Remove(id) => (Request::RemoveJob(id), format!("Removed job #{}", id)),
+        };
+        self.message = match self.request(&request) {
+            Ok(_) => done,
+            Err(e) => e,
+        };
+        self.refresh();
+    }
+
+    /// Holds the selected job, or releases it if it is held
+    fn toggle_hold(&mut self) {
+        let job = match self.selected.and_then(|id| self.job(id)) {
+            Some(job) => job,
+            None => return,
+        };
+        let (request, done) = if job.state == JobState::Held {
+            (
+                Request::ReleaseJob(job.id),
+                format!("Released job #{}", job.id),
+            )
+        } else {
+            (Request::HoldJob(job.id), format!("Held job #{}", job.id))
+        };
+        self.message = match self.request(&request) {
+            Ok(_) => done,
+            Err(e) => e,
+        };
+        self.refresh();
+    }
+
+    /// Stops the queue of the selected job, or the default queue if no job
+    /// is selected, or starts it if it is not running
+    fn toggle_queue(&mut self) {
+        let name = self
+            .selected
+            .and_then(|id| self.job(id))
+            .map_or(DEFAULT_QUEUE, |job| job.queue.as_str())
+            .to_string();
+        let state = self
+            .snapshot
+            .queues
+            .iter()
+            .find(|q| q.name == name)
+            .map(|q| q.state);
+        let new_state = match state {
+            Some(QueueState::Running) => QueueState::Stopping,
+            _ => QueueState::Running,
+        };
+        let request = Request::SetQueueStateIn {
+            queue: Some(name.clone()),
+            state: new_state,
+        };
+        self.message = match self.request(&request) {
+            Ok(_) => format!("Queue {} set to {:?}", name, new_state),
+            Err(e) => e,
+        };
+        self.refresh();
+    }
+
+    /// Shows the output of the selected job in the pager
+    fn show_logs(&mut self) -> Result<()> {
+        let id = match self.selected {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let job = match self.request(&Request::GetJob(id)) {
+            Ok(Response::GetJob(job)) => job,
+            Ok(response) => {
+                self.message = format!("Unexpected response: {:?}", response);
+                return Ok(());
+            }
+            Err(e) => {
+                self.message = e;
+                return Ok(());
+            }
+        };
+
+        let mut text = format!("Job #{}: {}\n\n=== stdout ===\n", job.id, job.cmdline);
+        if job.state == JobState::Running {
+            // output is only stored with the job once it has finished
+            if let Some(tail) = self.tails.lock().unwrap().get(&id) {
+                text.push_str(tail);
+            }
+        } else {
+            text.push_str(&job.stdout);
+            text.push_str("\n=== stderr ===\n");
+            text.push_str(&job.stderr);
+        }
+
+        let pager = std::env::var("PAGER").unwrap_or_else(|_| "less".to_string());
+        queue!(io::stdout(), cursor::Show, terminal::LeaveAlternateScreen)?;
+        io::stdout().flush()?;
+        terminal::disable_raw_mode()?;
+
+        let result = Command::new("sh")
+            .arg("-c")
+            .arg(&pager)
+            .stdin(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                if let Some(mut stdin) = child.stdin.take() {
+                    // the pager may quit before reading everything
+                    let _ = stdin.write_all(text.as_bytes());
+                }
+                child.wait()
+            });
+
+        terminal::enable_raw_mode()?;
+        queue!(io::stdout(), terminal::EnterAlternateScreen, cursor::Hide)?;
+        if let Err(e) = result {
+            self.message = format!("Could not run '{}': {}", pager, e);
+        }
+        Ok(())
+    }
+
+    /// A row of a job list
+    fn job_line(&self, job: &Job, time: String, duration: String) -> Line {
+        let text = format!(
+            " {:<6} {:<12} {:<10} {:<10} {:>10} {:>10}  {}",
+            job.id,
+            job.appkey(),
+            job.owner.as_deref().unwrap_or("-"),
+            job.state.name(),
+            time,
+            duration,
+            job.cmdline
+        );
+        Line::new(text)
+            .colour(state_colour(&job.state))
+            .reverse(self.selected == Some(job.id))
+    }
+
+    fn render(&self, width: usize, height: usize) -> Vec<Line> {
+        let snapshot = &self.snapshot;
+        let states = match snapshot.queues.as_slice() {
+            [] => "queue: ?".to_string(),
+            [queue] => format!("queue: {:?}", queue.state),
+            queues => {
+                let states: Vec<String> = queues
+                    .iter()
+                    .map(|q| format!("{} {:?}", q.name, q.state))
+                    .collect();
+                format!("queues: {}", states.join(", "))
+            }
+        };
+        let mut top = vec![
+            Line::new(format!(
+                " qmanager top - {}   {}   {} queued   {}",
+                self.url,
+                states,
+                snapshot
+                    .queued
+                    .iter()
+                    .filter(|j| j.state != JobState::Running)
+                    .count(),
+                humantime::format_rfc3339_seconds(SystemTime::now())
+            ))
+            .reverse(true),
+            Line::new(""),
+        ];
+
+        // the running jobs and the output of the selected or the first one
+        let running: Vec<&Job> = snapshot
+            .queued
+            .iter()
+            .filter(|j| j.state == JobState::Running)
+            .collect();
+        let allocated = snapshot.resources.map_or(String::new(), |usage| {
+            format!(
+                "   {} of {} CPUs, {} of {} memory",
+                usage.allocated.cpus,
+                usage.total.cpus,
+                format_mem(usage.allocated.mem),
+                format_mem(usage.total.mem)
+            )
+        });
+        top.push(Line::new(format!("RUNNING ({}){}", running.len(), allocated)).bold());
+        // per queue, the time until each of its slots is free
+        let mut slots: HashMap<&str, Vec<Option<Duration>>> = HashMap::new();
+        for job in &running {
+            let elapsed = job.started.map(elapsed).unwrap_or_default();
+            let estimate = snapshot.estimates.get(job.appkey());
+            let remaining = estimate.map(|e| e.checked_sub(elapsed).unwrap_or_default());
+            slots.entry(&job.queue).or_default().push(remaining);
+            top.push(self.job_line(job, String::new(), format_duration(elapsed)));
+        }
+        for queue in &snapshot.queues {
+            let free = slots.entry(&queue.name).or_default();
+            while free.len() < queue.slots {
+                free.push(Some(Duration::from_secs(0)));
+            }
+        }
+        let shown = running
+            .iter()
+            .find(|j| self.selected == Some(j.id))
+            .or_else(|| running.first());
+        match shown {
+            Some(job) => {
+                let tails = self.tails.lock().unwrap();
+                let output = tails.get(&job.id).map(|s| s.as_str()).unwrap_or("");
+                let lines: Vec<&str> = output.lines().collect();
+                let tail_rows = (height / 4).max(3);
+                if running.len() > 1 {
+                    top.push(Line::new(format!(" output of job #{}:", job.id)));
+                }
+                for line in &lines[lines.len().saturating_sub(tail_rows)..] {
+                    top.push(Line::new(format!("   {}", line)));
+                }
+                for _ in lines.len()..tail_rows {
+                    top.push(Line::new(""));
+                }
+            }
+            None => top.push(Line::new(" (none)")),
+        }
+        top.push(Line::new(""));
+
+        // queued jobs with the estimated time until they start, each taking
+        // the slot of its queue that is free first
+        let queued: Vec<&Job> = snapshot
+            .queued
+            .iter()
+            .filter(|j| j.state != JobState::Running)
+            .collect();
+        let mut queued_lines = Vec::new();
+        let mut free_resources = snapshot.resources.map(|u| u.total - u.allocated);
+        let mut waiting_for_resources: Vec<&str> = Vec::new();
+        for job in &queued {
+            if job.state == JobState::Held {
+                queued_lines.push(self.job_line(job, "held".to_string(), String::new()));
+                continue;
+            }
+            // a job that does not fit into the free resources starts at an
+            // unknown time, and so do the later jobs of its queue
+            if let Some(resources) = free_resources.as_mut() {
+                if waiting_for_resources.contains(&job.queue.as_str())
+                    || !resources.holds(&job.needs())
+                {
+                    waiting_for_resources.push(&job.queue);
+                    queued_lines.push(self.job_line(job, "?".to_string(), String::new()));
+                    continue;
+                }
+                *resources = *resources - job.needs();
+            }
+            let free = slots.entry(&job.queue).or_default();
+            // unknown times sort last
+            let next = (0..free.len()).min_by_key(|&i| (free[i].is_none(), free[i]));
+            let eta = match next.and_then(|i| free[i]) {
+                Some(d) => format!("in {}", format_duration(d)),
+                None => "?".to_string(),
+            };
+            if let Some(i) = next {
+                free[i] = match (free[i], snapshot.estimates.get(job.appkey())) {
+                    (Some(r), Some(e)) => Some(r + *e),
+                    _ => None,
+                };
+            }
+            queued_lines.push(self.job_line(job, eta, String::new()));
+        }
+
+        let finished_lines: Vec<Line> = snapshot
+            .finished
+            .iter()
+            .map(|job| {
+                let ago = job
+                    .finished
+                    .map(|t| format!("{} ago", format_duration(elapsed(t))))
+                    .unwrap_or_default();
+                let runtime = match (job.started, job.finished) {
+                    (Some(s), Some(f)) => format_duration(f.duration_since(s).unwrap_or_default()),
+                    _ => String::new(),
+                };
+                self.job_line(job, ago, runtime)
+            })
+            .collect();
+
+        // share the remaining rows, keeping the selected job visible
+        let footer = 2;
+        let free = height.saturating_sub(top.len() + footer + 4);
+        let queued_rows = queued_lines
+            .len()
+            .min(free.saturating_sub(finished_lines.len().min(free / 2)));
+        let finished_rows = free.saturating_sub(queued_rows);
+
+        let mut lines = top;
+        lines.push(Line::new(format!("QUEUED ({})", queued.len())).bold());
+        lines.push(Line::new(format!(
+            " {:<6} {:<12} {:<10} {:<10} {:>10} {:>10}  COMMAND",
+            "ID", "APPKEY", "OWNER", "STATE", "STARTS", ""
+        )));
+        lines.extend(self.visible(queued_lines, queued_rows));
+        lines.push(Line::new("FINISHED (recent)").bold());
+        lines.push(Line::new(format!(
+            " {:<6} {:<12} {:<10} {:<10} {:>10} {:>10}  COMMAND",
+            "ID", "APPKEY", "OWNER", "STATE", "FINISHED", "RUNTIME"
+        )));
+        lines.extend(self.visible(finished_lines, finished_rows));
+
+        while lines.len() < height.saturating_sub(footer) {
+            lines.push(Line::new(""));
+        }
+        lines.truncate(height.saturating_sub(footer));
+        lines.push(Line::new(format!(" {}", self.message)));
+        lines.push(
+            Line::new(
+                " q quit  \u{2191}\u{2193} select  k kill  d remove  h hold  l logs  s start/stop its queue  r refresh",
+            )
+            .reverse(true),
+        );
+
+        for line in &mut lines {
+            line.text = line.text.chars().take(width).collect();
+        }
+        lines
+    }
+
+    /// Returns at most `rows` lines, scrolled so that the selected one is shown
+    fn visible(&self, lines: Vec<Line>, rows: usize) -> Vec<Line> {
+        let selected = lines.iter().position(|l| l.reverse).unwrap_or(0);
+        let start = (selected + 1).saturating_sub(rows);
+        lines.into_iter().skip(start).take(rows).collect()
+    }
+
+    fn draw(&self) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let mut stdout = io::stdout();
+        for (row, line) in self
+            .render(width as usize, height as usize)
+            .into_iter()
+            .enumerate()
+        {
+            queue!(
+                stdout,
+                cursor::MoveTo(0, row as u16),
+                terminal::Clear(terminal::ClearType::CurrentLine)
+            )?;
+            if line.bold {
+                queue!(stdout, SetAttribute(Attribute::Bold))?;
+            }
+            if line.reverse {
+                queue!(stdout, SetAttribute(Attribute::Reverse))?;
+            }
+            if let Some(colour) = line.colour {
+                queue!(stdout, SetForegroundColor(colour))?;
+            }
+            let padding = if line.reverse {
+                (width as usize).saturating_sub(line.text.chars().count())
+            } else {
+                0
+            };
+            queue!(
+                stdout,
+                Print(line.text),
+                Print(" ".repeat(padding)),
+                SetAttribute(Attribute::Reset)
+            )?;
+        }
+        stdout.flush()
+    }
+}
+
+/// Runs the full-screen view until the user quits
+pub fn handle_top(
+    client: reqwest::Client,
+    stream_client: reqwest::Client,
+    url: reqwest::Url,
+) -> Result<()> {
+    let tails: OutputTails = Arc::default();
+    let changed = Arc::new(AtomicBool::new(false));
+    follow_events(
+        stream_client,
+        url.clone(),
+        Arc::clone(&tails),
+        Arc::clone(&changed),
+    );
+
+    let mut top = Top {
+        client,
+        url,
+        snapshot: Snapshot::default(),
+        tails,
+        selected: None,
+        pending: None,
+        message: String::new(),
+    };
+
+    let _guard = TerminalGuard::enter()?;
+    top.refresh();
+    let mut last_refresh = Instant::now();
+
+    loop {
+        top.draw()?;
+
+        // wait for a key, the next refresh or an event, whichever comes first
+        let deadline = last_refresh + REFRESH_INTERVAL;
+        let mut key = None;
+        while Instant::now() < deadline && !changed.load(Ordering::SeqCst) {
+            if event::poll(Duration::from_millis(100))? {
+                if let Event::Key(k) = event::read()? {
+                    if k.kind != KeyEventKind::Release {
+                        key = Some(k);
+                        break;
+                    }
+                }
+            }
+        }
+
+        if let Some(key) = key {
+            if let Some(pending) = top.pending.take() {
+                match key.code {
+                    KeyCode::Char('y') | KeyCode::Char('Y') => top.execute(pending),
+                    _ => top.message = "Cancelled".to_string(),
+                }
+                continue;
+            }
+            top.message.clear();
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(())
+                }
+                KeyCode::Up => top.move_selection(-1),
+                KeyCode::Down => top.move_selection(1),
+                KeyCode::PageUp => top.move_selection(-10),
+                KeyCode::PageDown => top.move_selection(10),
+                KeyCode::Char('k') => {
+                    if let Some(id) = top.selected {
+                        top.confirm(Pending::Kill(id));
+                    }
+                }
+                KeyCode::Char('d') | KeyCode::Delete => {
+                    if let Some(id) = top.selected {
+                        top.confirm(Pending::Remove(id));
+                    }
+                }
+                KeyCode::Char('l') | KeyCode::Enter => top.show_logs()?,
+                KeyCode::Char('h') => top.toggle_hold(),
+                KeyCode::Char('s') => top.toggle_queue(),
+                KeyCode::Char('r') => top.refresh(),
+                _ => {}
+            }
+            continue;
+        }
+
+        changed.store(false, Ordering::SeqCst);
+        top.refresh();
+        last_refresh = Instant::now();
+    }
+}